@@ -0,0 +1,206 @@
+//! Compare the arena block tree with the former tree of `Rc<RefCell>` blocks with `Weak` parents.
+//!
+//! Both trees run the binary search of Cascade in every top block: split down to a single bit,
+//! set the correct parity of the left sub block and infer the right one. Then they find the blocks
+//! containing every bit, as the cascade to earlier iterations does.
+//! The former tree is replicated here without the shuffled key, which would only make it slower.
+//!
+//! Run with `cargo bench --bench block_tree`.
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use prototype::block::{BlockId, BlockTree};
+
+const NR_BITS: [u32; 3] = [1 << 10, 1 << 16, 1 << 20];
+const BLOCK_SIZE: u32 = 64;
+
+struct RcBlock {
+    inner: RefCell<RcInner>,
+}
+
+struct RcInner {
+    start_bit_nr: u32,
+    end_bit_nr: u32,
+    correct_parity: Option<u8>,
+    parent: Option<Weak<RcBlock>>,
+    left_sub_block: Option<Rc<RcBlock>>,
+    right_sub_block: Option<Rc<RcBlock>>,
+}
+
+impl RcBlock {
+    fn new(start_bit_nr: u32, end_bit_nr: u32, parent: Option<Weak<RcBlock>>) -> Rc<Self> {
+        Rc::new(Self {
+            inner: RefCell::new(RcInner {
+                start_bit_nr,
+                end_bit_nr,
+                correct_parity: None,
+                parent,
+                left_sub_block: None,
+                right_sub_block: None,
+            }),
+        })
+    }
+
+    fn contains_bit(&self, bit_nr: u32) -> bool {
+        let inner = self.inner.borrow();
+        inner.start_bit_nr <= bit_nr && bit_nr <= inner.end_bit_nr
+    }
+
+    fn create_sub_blocks(self: &Rc<Self>) -> (Rc<Self>, Rc<Self>) {
+        let (start_bit_nr, end_bit_nr) = {
+            let inner = self.inner.borrow();
+            (inner.start_bit_nr, inner.end_bit_nr)
+        };
+        let mid_bit_nr = (start_bit_nr + end_bit_nr) / 2;
+        let left = RcBlock::new(start_bit_nr, mid_bit_nr, Some(Rc::downgrade(self)));
+        let right = RcBlock::new(mid_bit_nr + 1, end_bit_nr, Some(Rc::downgrade(self)));
+        let mut inner = self.inner.borrow_mut();
+        inner.left_sub_block = Some(left.clone());
+        inner.right_sub_block = Some(right.clone());
+        (left, right)
+    }
+
+    fn infer_correct_parity(&self) {
+        let parent = self
+            .inner
+            .borrow()
+            .parent
+            .as_ref()
+            .unwrap()
+            .upgrade()
+            .unwrap();
+        let parent = parent.inner.borrow();
+        let left = parent.left_sub_block.as_ref().unwrap().inner.borrow();
+        let parity = parent.correct_parity.unwrap() ^ left.correct_parity.unwrap();
+        self.inner.borrow_mut().correct_parity = Some(parity);
+    }
+}
+
+fn rc_binary_search(nr_bits: u32) -> Vec<Rc<RcBlock>> {
+    let top_blocks: Vec<_> = (0..nr_bits)
+        .step_by(BLOCK_SIZE as usize)
+        .map(|start_bit_nr| RcBlock::new(start_bit_nr, start_bit_nr + BLOCK_SIZE - 1, None))
+        .collect();
+    for (i, top_block) in top_blocks.iter().enumerate() {
+        top_block.inner.borrow_mut().correct_parity = Some(1);
+        let error_bit_nr = top_block.inner.borrow().start_bit_nr + i as u32 % BLOCK_SIZE;
+        let mut block = top_block.clone();
+        while block.inner.borrow().end_bit_nr > block.inner.borrow().start_bit_nr {
+            let (left, right) = block.create_sub_blocks();
+            left.inner.borrow_mut().correct_parity = Some(0);
+            right.infer_correct_parity();
+            block = if left.contains_bit(error_bit_nr) {
+                left
+            } else {
+                right
+            };
+        }
+    }
+    top_blocks
+}
+
+fn rc_find_blocks(top_blocks: &[Rc<RcBlock>], nr_bits: u32) -> u32 {
+    let mut nr_blocks = 0;
+    for bit_nr in 0..nr_bits {
+        let mut block = top_blocks.iter().find(|b| b.contains_bit(bit_nr)).cloned();
+        while let Some(current_block) = block {
+            nr_blocks += 1;
+            let inner = current_block.inner.borrow();
+            block = [inner.left_sub_block.clone(), inner.right_sub_block.clone()]
+                .into_iter()
+                .flatten()
+                .find(|b| b.contains_bit(bit_nr));
+        }
+    }
+    nr_blocks
+}
+
+fn arena_binary_search(nr_bits: u32) -> BlockTree {
+    let mut blocks = BlockTree::new();
+    for start_bit_nr in (0..nr_bits).step_by(BLOCK_SIZE as usize) {
+        blocks.add_top_block(start_bit_nr, start_bit_nr + BLOCK_SIZE - 1);
+    }
+    let top_blocks: Vec<BlockId> = blocks.get_top_blocks().collect();
+    for (i, top_block) in top_blocks.into_iter().enumerate() {
+        blocks.set_correct_parity(top_block, 1);
+        let error_bit_nr = blocks.get_start_bit_nr(top_block) + i as u32 % BLOCK_SIZE;
+        let mut block = top_block;
+        while blocks.get_nr_bits(block) > 1 {
+            let (left, right) = blocks.get_or_create_sub_blocks(block);
+            blocks.set_correct_parity(left, 0);
+            blocks.try_to_infer_correct_parity(right);
+            block = if blocks.contains_bit(left, error_bit_nr) {
+                left
+            } else {
+                right
+            };
+        }
+    }
+    blocks
+}
+
+fn arena_find_blocks(blocks: &BlockTree, nr_bits: u32) -> u32 {
+    let mut nr_blocks = 0;
+    for bit_nr in 0..nr_bits {
+        let mut block = blocks
+            .get_top_blocks()
+            .find(|&b| blocks.contains_bit(b, bit_nr));
+        while let Some(current_block) = block {
+            nr_blocks += 1;
+            block = [
+                blocks.get_left_sub_block(current_block),
+                blocks.get_right_sub_block(current_block),
+            ]
+            .into_iter()
+            .flatten()
+            .find(|&b| blocks.contains_bit(b, bit_nr));
+        }
+    }
+    nr_blocks
+}
+
+fn bench_binary_search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_tree_binary_search");
+    group.sample_size(10);
+    for nr_bits in NR_BITS {
+        group.bench_with_input(BenchmarkId::new("rc", nr_bits), &nr_bits, |b, &nr_bits| {
+            b.iter(|| rc_binary_search(black_box(nr_bits)))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("arena", nr_bits),
+            &nr_bits,
+            |b, &nr_bits| b.iter(|| arena_binary_search(black_box(nr_bits))),
+        );
+    }
+    group.finish();
+}
+
+/// Only the smaller keys, finding the top block is a linear scan in both trees.
+fn bench_find_blocks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_tree_find_blocks");
+    group.sample_size(10);
+    for nr_bits in &NR_BITS[..2] {
+        let nr_bits = *nr_bits;
+        let top_blocks = rc_binary_search(nr_bits);
+        let blocks = arena_binary_search(nr_bits);
+        assert_eq!(
+            rc_find_blocks(&top_blocks, nr_bits),
+            arena_find_blocks(&blocks, nr_bits)
+        );
+        group.bench_with_input(
+            BenchmarkId::new("rc", nr_bits),
+            &top_blocks,
+            |b, top_blocks| b.iter(|| rc_find_blocks(top_blocks, black_box(nr_bits))),
+        );
+        group.bench_with_input(BenchmarkId::new("arena", nr_bits), &blocks, |b, blocks| {
+            b.iter(|| arena_find_blocks(blocks, black_box(nr_bits)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_binary_search, bench_find_blocks);
+criterion_main!(benches);
@@ -0,0 +1,105 @@
+//! Compare the `count_ones` parity kernel with the original byte table approach.
+//!
+//! Run with `cargo bench --bench parity`.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use prototype::{
+    key::Key,
+    parity::{word_parity, word_parity_table, xor_fold},
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const NR_BITS: [usize; 3] = [1 << 10, 1 << 14, 1 << 20];
+
+fn random_words(nr_words: usize) -> Vec<u64> {
+    let mut rng = StdRng::seed_from_u64(0x1234567890ABCDEF);
+    (0..nr_words).map(|_| rng.gen()).collect()
+}
+
+fn random_key(nr_bits: usize) -> Key {
+    let mut rng = StdRng::seed_from_u64(0x1234567890ABCDEF);
+    let key_str: String = (0..nr_bits)
+        .map(|_| if rng.gen::<bool>() { '1' } else { '0' })
+        .collect();
+    Key::from(key_str.as_str())
+}
+
+/// The original range parity: XOR the words one at a time, then look up the parity per byte.
+fn table_words_parity(words: &[u64]) -> u8 {
+    let mut xor_words = 0u64;
+    for word in words {
+        xor_words ^= word;
+    }
+    word_parity_table(xor_words)
+}
+
+fn bench_word_parity(c: &mut Criterion) {
+    let words = random_words(1024);
+    let mut group = c.benchmark_group("word_parity");
+    group.bench_function("table", |b| {
+        b.iter(|| {
+            words
+                .iter()
+                .fold(0, |acc, &word| acc ^ word_parity_table(black_box(word)))
+        })
+    });
+    group.bench_function("count_ones", |b| {
+        b.iter(|| {
+            words
+                .iter()
+                .fold(0, |acc, &word| acc ^ word_parity(black_box(word)))
+        })
+    });
+    group.finish();
+}
+
+fn bench_range_parity(c: &mut Criterion) {
+    let mut group = c.benchmark_group("range_parity");
+    for nr_bits in NR_BITS {
+        let words = random_words(nr_bits / 64);
+        group.bench_with_input(BenchmarkId::new("table", nr_bits), &words, |b, words| {
+            b.iter(|| table_words_parity(black_box(words)))
+        });
+        group.bench_with_input(BenchmarkId::new("xor_fold", nr_bits), &words, |b, words| {
+            b.iter(|| word_parity(xor_fold(black_box(words))))
+        });
+    }
+    group.finish();
+}
+
+fn bench_range_parities(c: &mut Criterion) {
+    let mut group = c.benchmark_group("range_parities");
+    for nr_bits in NR_BITS {
+        let key = random_key(nr_bits);
+        // top blocks of 73 bits, as in the first iteration with 1% QBER
+        let ranges: Vec<(u32, u32)> = (0..nr_bits as u32)
+            .step_by(73)
+            .map(|start_bit_nr| (start_bit_nr, (start_bit_nr + 72).min(nr_bits as u32 - 1)))
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("one_by_one", nr_bits),
+            &ranges,
+            |b, ranges| {
+                b.iter(|| {
+                    ranges
+                        .iter()
+                        .map(|&(start_bit_nr, end_bit_nr)| {
+                            key.compute_range_parity(start_bit_nr, end_bit_nr)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("batch", nr_bits), &ranges, |b, ranges| {
+            b.iter(|| key.compute_range_parities(black_box(ranges)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_word_parity,
+    bench_range_parity,
+    bench_range_parities
+);
+criterion_main!(benches);
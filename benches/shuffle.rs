@@ -0,0 +1,80 @@
+//! Compare the table shuffle with the Feistel shuffle that computes the permutation on the fly.
+//!
+//! The table shuffle takes 8 bytes per key bit, the Feistel shuffle a few round keys.
+//! The memory of both is printed before the timings.
+//!
+//! Run with `cargo bench --bench shuffle`.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use prototype::shuffle::{Shuffle, ShuffleBackend};
+
+const NR_BITS: [u32; 3] = [1 << 10, 1 << 16, 1 << 22];
+const SEED: u64 = 0x1234567890ABCDEF;
+const BACKENDS: [(&str, ShuffleBackend); 2] = [
+    ("table", ShuffleBackend::Table),
+    ("feistel", ShuffleBackend::Feistel),
+];
+
+fn print_memory() {
+    for nr_bits in NR_BITS {
+        for (name, backend) in BACKENDS {
+            let shuffle =
+                Shuffle::new_shuffle_from_seed_with_backend(2, nr_bits, SEED, backend, false)
+                    .unwrap();
+            println!(
+                "shuffle memory {}/{}: {} bytes on the heap, {} bytes inline",
+                name,
+                nr_bits,
+                shuffle.heap_size(),
+                std::mem::size_of::<Shuffle>()
+            );
+        }
+    }
+}
+
+fn bench_create(c: &mut Criterion) {
+    print_memory();
+    let mut group = c.benchmark_group("shuffle_create");
+    group.sample_size(10);
+    for nr_bits in NR_BITS {
+        for (name, backend) in BACKENDS {
+            group.bench_with_input(BenchmarkId::new(name, nr_bits), &nr_bits, |b, &nr_bits| {
+                b.iter(|| {
+                    Shuffle::new_shuffle_from_seed_with_backend(
+                        2,
+                        black_box(nr_bits),
+                        SEED,
+                        backend,
+                        false,
+                    )
+                    .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Map every bit of the key in both directions, as creating the shuffled key does.
+fn bench_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("shuffle_lookup");
+    group.sample_size(10);
+    for nr_bits in NR_BITS {
+        for (name, backend) in BACKENDS {
+            let shuffle =
+                Shuffle::new_shuffle_from_seed_with_backend(2, nr_bits, SEED, backend, false)
+                    .unwrap();
+            group.bench_with_input(BenchmarkId::new(name, nr_bits), &shuffle, |b, shuffle| {
+                b.iter(|| {
+                    (0..nr_bits).fold(0, |acc, bit_nr| {
+                        acc ^ shuffle.shuffle_to_orig(black_box(bit_nr))
+                            ^ shuffle.orig_to_shuffle(black_box(bit_nr))
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_create, bench_lookup);
+criterion_main!(benches);
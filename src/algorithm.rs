@@ -1,10 +1,24 @@
-use std::ops::Deref;
+use std::{ops::Deref, sync::Arc};
+
+use crate::{
+    partition::Partitioner,
+    shuffle::{ShuffleBackend, ShuffleCache},
+    split::SplitStrategy,
+};
 
 pub trait Algorithm {
     const MIN_ESTIMATED_BIT_ERR_RATE: f32 = 1e-5;
     fn block_size(iteration_nr: u32, estimated_bit_error_rate: f32, key_size: u32) -> u32;
+    fn config(&self) -> &InnerConfig;
+
+    /// How the binary search splits a block with an odd number of errors.
+    fn split_strategy(&self) -> SplitStrategy {
+        self.config().split_strategy()
+    }
 }
 
+// TODO: most of the options are not implemented yet
+#[derive(Clone)]
 pub struct InnerConfig {
     name: String,
     nr_cascade_iterations: u32,
@@ -14,11 +28,81 @@ pub struct InnerConfig {
     biconf_cascade: bool,
     ask_correct_parity_using_shuffle_seed: bool,
     cache_shuffles: bool,
+    // None is the process-wide cache
+    shuffle_cache: Option<Arc<ShuffleCache>>,
+    shuffle_backend: ShuffleBackend,
+    split_strategy: SplitStrategy,
+    partitioner: Partitioner,
 }
 
-pub struct OriginalAlgorithm(InnerConfig);
+impl InnerConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn nr_cascade_iterations(&self) -> u32 {
+        self.nr_cascade_iterations
+    }
+
+    pub fn nr_biconf_iterations(&self) -> u32 {
+        self.nr_biconf_iterations
+    }
+
+    pub fn biconf_error_free_streak(&self) -> bool {
+        self.biconf_error_free_streak
+    }
+
+    pub fn biconf_correct_complement(&self) -> bool {
+        self.biconf_correct_complement
+    }
+
+    pub fn biconf_cascade(&self) -> bool {
+        self.biconf_cascade
+    }
+
+    /// Whether shuffles are kept in the shuffle cache, to be reused by later reconciliations.
+    pub fn cache_shuffles(&self) -> bool {
+        self.cache_shuffles
+    }
+
+    /// The cache the shuffles are kept in, None if the shuffles are not cached.
+    ///
+    /// This is the cache given to [`OriginalAlgorithm::with_shuffle_cache`], else the process-wide
+    /// [`ShuffleCache::global`].
+    pub fn shuffle_cache(&self) -> Option<&ShuffleCache> {
+        if !self.cache_shuffles {
+            return None;
+        }
+        match &self.shuffle_cache {
+            Some(shuffle_cache) => Some(shuffle_cache),
+            None => Some(ShuffleCache::global()),
+        }
+    }
+
+    /// Whether parity requests identify a block by its shuffle seed and range,
+    /// instead of the list of its bits, see [`protocol`](crate::protocol).
+    pub fn ask_correct_parity_using_shuffle_seed(&self) -> bool {
+        self.ask_correct_parity_using_shuffle_seed
+    }
+
+    /// How the shuffles of the iterations store their permutation.
+    pub fn shuffle_backend(&self) -> ShuffleBackend {
+        self.shuffle_backend
+    }
+
+    /// How the binary search splits a block, see [`split`](crate::split).
+    pub fn split_strategy(&self) -> SplitStrategy {
+        self.split_strategy
+    }
 
-struct BiconfAlgorithm(InnerConfig);
+    /// How an iteration partitions the shuffled key into top blocks, see [`partition`](crate::partition).
+    pub fn partitioner(&self) -> Partitioner {
+        self.partitioner
+    }
+}
+
+#[derive(Clone)]
+pub struct OriginalAlgorithm(InnerConfig);
 
 impl Deref for OriginalAlgorithm {
     type Target = InnerConfig;
@@ -28,52 +112,110 @@ impl Deref for OriginalAlgorithm {
 }
 
 impl OriginalAlgorithm {
-    pub fn new(
-        name: &str,
-        nr_cascade_iterations: u32,
-        nr_biconf_iterations: u32,
-        biconf_error_free_streak: bool,
-        biconf_correct_complement: bool,
-        biconf_cascade: bool,
-        ask_correct_parity_using_shuffle_seed: bool,
-        cache_shuffles: bool,
-    ) -> Self {
+    /// Configuration without the BICONF options, asking parities by shuffle seed and caching
+    /// the shuffles; the `with_*` methods change the other options.
+    pub fn new(name: &str, nr_cascade_iterations: u32, nr_biconf_iterations: u32) -> Self {
         Self(InnerConfig {
             name: name.to_string(),
             nr_cascade_iterations,
             nr_biconf_iterations,
-            biconf_error_free_streak,
-            biconf_correct_complement,
-            biconf_cascade,
-            ask_correct_parity_using_shuffle_seed,
-            cache_shuffles,
+            biconf_error_free_streak: false,
+            biconf_correct_complement: false,
+            biconf_cascade: false,
+            ask_correct_parity_using_shuffle_seed: true,
+            cache_shuffles: true,
+            shuffle_cache: None,
+            shuffle_backend: ShuffleBackend::default(),
+            split_strategy: SplitStrategy::default(),
+            partitioner: Partitioner::default(),
         })
     }
+
+    pub fn with_biconf_options(
+        mut self,
+        biconf_error_free_streak: bool,
+        biconf_correct_complement: bool,
+        biconf_cascade: bool,
+    ) -> Self {
+        self.0.biconf_error_free_streak = biconf_error_free_streak;
+        self.0.biconf_correct_complement = biconf_correct_complement;
+        self.0.biconf_cascade = biconf_cascade;
+        self
+    }
+
+    pub fn with_ask_correct_parity_using_shuffle_seed(
+        mut self,
+        ask_correct_parity_using_shuffle_seed: bool,
+    ) -> Self {
+        self.0.ask_correct_parity_using_shuffle_seed = ask_correct_parity_using_shuffle_seed;
+        self
+    }
+
+    pub fn with_cache_shuffles(mut self, cache_shuffles: bool) -> Self {
+        self.0.cache_shuffles = cache_shuffles;
+        self
+    }
+
+    /// Keep the shuffles in the given cache instead of the process-wide one,
+    /// e.g. one cache per session.
+    pub fn with_shuffle_cache(mut self, shuffle_cache: Arc<ShuffleCache>) -> Self {
+        self.0.shuffle_cache = Some(shuffle_cache);
+        self
+    }
+
+    pub fn with_shuffle_backend(mut self, shuffle_backend: ShuffleBackend) -> Self {
+        self.0.shuffle_backend = shuffle_backend;
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics for a k-ary split with `k < 2`
+    pub fn with_split_strategy(mut self, split_strategy: SplitStrategy) -> Self {
+        if let SplitStrategy::KAry(k) = split_strategy {
+            assert!(k >= 2, "a k-ary split needs k >= 2");
+        }
+        self.0.split_strategy = split_strategy;
+        self
+    }
+
+    pub fn with_partitioner(mut self, partitioner: Partitioner) -> Self {
+        self.0.partitioner = partitioner;
+        self
+    }
 }
 
 impl Default for OriginalAlgorithm {
     fn default() -> Self {
-        Self::new("original", 4, 0, false, false, false, true, true)
+        Self::new("original", 4, 0)
     }
 }
 impl Algorithm for OriginalAlgorithm {
-    fn block_size(iteration_nr: u32, estimated_bit_error_rate: f32, key_size: u32) -> u32 {
+    fn block_size(iteration_nr: u32, estimated_bit_error_rate: f32, _key_size: u32) -> u32 {
         let estimated_bit_error_rate =
             estimated_bit_error_rate.max(Self::MIN_ESTIMATED_BIT_ERR_RATE);
-        if iteration_nr == 1 {
-            // Casting from a float to an integer will round the float towards zero
-            // NaN will return 0
-            // Values larger than the maximum integer value, including INFINITY, will saturate to the maximum value of the integer type.
-            // Values smaller than the minimum integer value, including NEG_INFINITY, will saturate to the minimum value of the integer type.
-            return (0.73 / estimated_bit_error_rate).ceil() as u32;
-        }
-        2 * Self::block_size(iteration_nr - 1, estimated_bit_error_rate, key_size)
+        // Casting from a float to an integer will round the float towards zero
+        // NaN will return 0
+        // Values larger than the maximum integer value, including INFINITY, will saturate to the maximum value of the integer type.
+        // Values smaller than the minimum integer value, including NEG_INFINITY, will saturate to the minimum value of the integer type.
+        let first_block_size = (0.73 / estimated_bit_error_rate).ceil() as u32;
+        // the block size doubles in every iteration
+        first_block_size * 2u32.pow(iteration_nr - 1)
+    }
+
+    fn config(&self) -> &InnerConfig {
+        &self.0
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::algorithm::{Algorithm, OriginalAlgorithm};
+    use crate::{
+        algorithm::{Algorithm, OriginalAlgorithm},
+        partition::Partitioner,
+        shuffle::ShuffleBackend,
+        split::SplitStrategy,
+    };
 
     #[test]
     fn test_original_algorithm() {
@@ -110,5 +252,29 @@ mod tests {
         assert!(!alg.biconf_cascade);
         assert!(alg.ask_correct_parity_using_shuffle_seed);
         assert!(alg.cache_shuffles);
+        assert_eq!(ShuffleBackend::Table, alg.shuffle_backend());
+
+        let alg = OriginalAlgorithm::default().with_shuffle_backend(ShuffleBackend::Feistel);
+        assert_eq!(ShuffleBackend::Feistel, alg.shuffle_backend());
+
+        assert_eq!(
+            SplitStrategy::Bisect,
+            OriginalAlgorithm::default().split_strategy()
+        );
+        let alg = OriginalAlgorithm::default().with_split_strategy(SplitStrategy::KAry(4));
+        assert_eq!(SplitStrategy::KAry(4), alg.split_strategy());
+
+        assert_eq!(
+            Partitioner::FixedSize,
+            OriginalAlgorithm::default().partitioner()
+        );
+        let alg = OriginalAlgorithm::default().with_partitioner(Partitioner::EqualSize);
+        assert_eq!(Partitioner::EqualSize, alg.partitioner());
+    }
+
+    #[test]
+    #[should_panic(expected = "a k-ary split needs k >= 2")]
+    fn test_k_ary_split_needs_two_parts() {
+        let _ = OriginalAlgorithm::default().with_split_strategy(SplitStrategy::KAry(1));
     }
 }
@@ -0,0 +1,54 @@
+//! Subsets of the key bits for BICONF.
+//!
+//! After the Cascade iterations, every BICONF iteration compares the parity of a random subset of
+//! the key bits. Every bit is in the subset with probability 1/2, so that a remaining error is
+//! detected with probability 1/2 in every iteration. The parity of the subset is asked with a
+//! [`ParityRequest::BitList`](crate::protocol::ParityRequest::BitList).
+
+use rand::{CryptoRng, RngCore};
+
+/// Random subset of the bits `0..nr_bits`, sorted.
+///
+/// An eavesdropper must not predict the subset, so the generator must be cryptographically secure.
+pub fn random_subset<R: RngCore + CryptoRng + ?Sized>(nr_bits: u32, rng: &mut R) -> Vec<u32> {
+    let mut subset = Vec::with_capacity(nr_bits as usize / 2);
+    for word_start_bit_nr in (0..nr_bits).step_by(u64::BITS as usize) {
+        // one random bit per key bit
+        let mut word = rng.next_u64();
+        for bit_nr in word_start_bit_nr..nr_bits.min(word_start_bit_nr + u64::BITS) {
+            if word & 1 == 1 {
+                subset.push(bit_nr);
+            }
+            word >>= 1;
+        }
+    }
+    subset
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::random_subset;
+
+    #[test]
+    fn test_random_subset() {
+        const NR_BITS: u32 = 10000;
+        let subset = random_subset(NR_BITS, &mut StdRng::seed_from_u64(1234));
+        assert!(subset.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(subset.iter().all(|&bit_nr| bit_nr < NR_BITS));
+        // about half of the bits, 5 standard deviations
+        assert!((subset.len() as i64 - 5000).abs() < 250);
+
+        // reproducible from the seed
+        assert_eq!(
+            subset,
+            random_subset(NR_BITS, &mut StdRng::seed_from_u64(1234))
+        );
+        assert_ne!(
+            subset,
+            random_subset(NR_BITS, &mut StdRng::seed_from_u64(4321))
+        );
+        assert!(random_subset(0, &mut StdRng::seed_from_u64(1234)).is_empty());
+    }
+}
@@ -1,41 +1,62 @@
-use crate::{key::Key, shuffled_key::ShuffledKey};
-use std::{
-    cell::{Ref, RefCell, RefMut},
-    rc::{Rc, Weak},
+use tracing::trace;
+
+use crate::{
+    error::{Error, Result},
+    protocol::{ParityChannel, ParityRequest},
+    shuffled_key::ShuffledKey,
+    split::SplitStrategy,
 };
 
-type WeakBlockRef = Weak<Block>;
-pub type BlockRef = Rc<Block>;
-/// A block is a contiguous range of bits in a shuffled key.
-/// not thread safe, it use RefCell to allow interior mutability
-#[derive(Debug)]
-pub struct Block {
-    inner: RefCell<Inner>,
+/// Index of a block in its [`BlockTree`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(u32);
+
+impl BlockId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
 }
 
-#[derive(Debug)]
-struct Inner {
-    // the type of the block
-    block_type: BlockType,
+/// The blocks of one iteration, stored in a `Vec` and addressed by [`BlockId`].
+///
+/// The top blocks come first, in order of their bits. The sub blocks of a block are created
+/// together and follow each other, in order of their bits, see [`SplitStrategy`]. A block is a contiguous range of bits
+/// in the shuffled key of the iteration; the tree does not hold the key, the operations that
+/// need the bits take the [`ShuffledKey`].
+/// Not thread safe.
+#[derive(Debug, Default, Clone)]
+pub struct BlockTree {
+    nodes: Vec<Node>,
+    nr_top_blocks: u32,
+}
 
+// 20 bytes, instead of an Rc<RefCell> allocation per block holding a ShuffledKey clone.
+#[derive(Debug, Clone)]
+struct Node {
     // start index of in the shuffled key
     start_bit_nr: u32,
-    // end index of in the shuffled key
+    // end index of in the shuffled key, inclusive
     end_bit_nr: u32,
-    // reference to the shuffled key
-    shuffled_key: ShuffledKey,
-    // the parity of the bits in the range
-    current_parity: Option<u8>,
-    // the parity answerd by the remote
-    correct_parity: Option<u8>,
-    // the parent block
-    parent: Option<WeakBlockRef>,
-    // the left sub block
-    left_sub_block: Option<BlockRef>,
-    // the right sub block
-    right_sub_block: Option<BlockRef>,
+    // NO_BLOCK for top blocks
+    parent: u32,
+    // the left sub block, the other sub blocks are the next ones, NO_BLOCK without sub blocks
+    first_child: u32,
+    // 0, or at least 2
+    nr_sub_blocks: u8,
+    // see the PARITY_ and CORRECTED_BIT constants
+    flags: u8,
 }
 
+const NO_BLOCK: u32 = u32::MAX;
+// the correct parity answered by the remote, or inferred
+const PARITY_KNOWN: u8 = 0b0001;
+const PARITY_VALUE: u8 = 0b0010;
+// how the correct parity became known, neither if it was set directly
+const PARITY_ASKED: u8 = 0b0100;
+const PARITY_INFERRED: u8 = 0b1000;
+// the single bit of the block was corrected
+const CORRECTED_BIT: u8 = 0b1_0000;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum BlockType {
     TopLevel,
@@ -45,183 +66,231 @@ pub enum BlockType {
 #[derive(Debug, Clone, PartialEq)]
 pub enum SubBlockType {
     Left,
+    /// Neither the left nor the right sub block of a k-ary split.
+    Middle,
     Right,
 }
-impl Inner {
-    fn new(
-        block_type: BlockType,
-        start_bit_nr: u32,
-        end_bit_nr: u32,
-        shuffled_key: ShuffledKey,
-    ) -> Self {
-        Self {
-            block_type,
+
+impl BlockTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a top block, after all existing top blocks and before any sub block.
+    ///
+    /// The range is inclusive, a.k.a `start_bit_nr..=end_bit_nr`
+    pub fn add_top_block(&mut self, start_bit_nr: u32, end_bit_nr: u32) -> BlockId {
+        assert_eq!(
+            self.nodes.len(),
+            self.nr_top_blocks as usize,
+            "top blocks come before sub blocks"
+        );
+        self.nr_top_blocks += 1;
+        self.push(start_bit_nr, end_bit_nr, NO_BLOCK)
+    }
+
+    fn push(&mut self, start_bit_nr: u32, end_bit_nr: u32, parent: u32) -> BlockId {
+        assert!(start_bit_nr <= end_bit_nr);
+        let id = BlockId(self.nodes.len() as u32);
+        self.nodes.push(Node {
             start_bit_nr,
             end_bit_nr,
-            shuffled_key,
-            current_parity: None,
-            correct_parity: None,
-            parent: None,
-            left_sub_block: None,
-            right_sub_block: None,
+            parent,
+            first_child: NO_BLOCK,
+            nr_sub_blocks: 0,
+            flags: 0,
+        });
+        id
+    }
+
+    fn node(&self, block: BlockId) -> &Node {
+        &self.nodes[block.index()]
+    }
+
+    pub fn get_nr_blocks(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn get_top_blocks(&self) -> impl ExactSizeIterator<Item = BlockId> {
+        (0..self.nr_top_blocks).map(BlockId)
+    }
+
+    /// The top block at `index`, in order of their bits.
+    pub fn get_top_block(&self, index: u32) -> BlockId {
+        assert!(index < self.nr_top_blocks, "no top block {}", index);
+        BlockId(index)
+    }
+
+    /// The top block containing a bit, by binary search: the top blocks are contiguous and in
+    /// order of their bits.
+    pub fn get_top_block_containing_bit(&self, bit_nr: u32) -> Option<BlockId> {
+        let top_nodes = &self.nodes[..self.nr_top_blocks as usize];
+        let index = top_nodes.partition_point(|node| node.end_bit_nr < bit_nr);
+        top_nodes
+            .get(index)
+            .filter(|node| node.start_bit_nr <= bit_nr)
+            .map(|_| BlockId(index as u32))
+    }
+
+    /// All blocks, the top blocks first, sub blocks in order of creation.
+    pub fn get_all_blocks(&self) -> impl ExactSizeIterator<Item = BlockId> {
+        (0..self.nodes.len() as u32).map(BlockId)
+    }
+
+    pub fn get_block_type(&self, block: BlockId) -> BlockType {
+        let Some(parent) = self.get_parent_block(block) else {
+            return BlockType::TopLevel;
+        };
+        let parent = self.node(parent);
+        if block.0 == parent.first_child {
+            BlockType::SubBlock(SubBlockType::Left)
+        } else if block.0 == parent.first_child + u32::from(parent.nr_sub_blocks) - 1 {
+            BlockType::SubBlock(SubBlockType::Right)
+        } else {
+            BlockType::SubBlock(SubBlockType::Middle)
         }
     }
-}
 
-/// Block only provide API for working with Rc<Block>
-impl Block {
-    /// The range is inclusive, a.k.a `start_bit_nr..=end_bit_nr the`
-    pub fn new(
-        block_type: BlockType,
-        start_bit_nr: u32,
-        end_bit_nr: u32,
-        shuffled_key: ShuffledKey,
-    ) -> BlockRef {
-        let inner = Inner::new(block_type, start_bit_nr, end_bit_nr, shuffled_key);
-        let block = Rc::new(Block {
-            inner: RefCell::new(inner),
-        });
-        block
+    pub fn get_start_bit_nr(&self, block: BlockId) -> u32 {
+        self.node(block).start_bit_nr
     }
 
-    pub fn get_block_type(&self) -> BlockType {
-        self.inner.borrow().block_type.clone()
+    pub fn get_end_bit_nr(&self, block: BlockId) -> u32 {
+        self.node(block).end_bit_nr
     }
-    pub fn get_start_bit_nr(&self) -> u32 {
-        self.inner.borrow().start_bit_nr
+
+    pub fn contains_bit(&self, block: BlockId, bit_nr: u32) -> bool {
+        let node = self.node(block);
+        node.start_bit_nr <= bit_nr && bit_nr <= node.end_bit_nr
     }
 
-    pub fn get_end_bit_nr(&self) -> u32 {
-        self.inner.borrow().end_bit_nr
+    pub fn get_nr_bits(&self, block: BlockId) -> u32 {
+        let node = self.node(block);
+        node.end_bit_nr - node.start_bit_nr + 1
     }
 
-    pub fn contains_bit(&self, bit_nr: u32) -> bool {
-        self.get_start_bit_nr() <= bit_nr && bit_nr <= self.get_end_bit_nr()
+    pub fn get_correct_parity(&self, block: BlockId) -> Option<u8> {
+        let flags = self.node(block).flags;
+        (flags & PARITY_KNOWN != 0).then_some((flags & PARITY_VALUE != 0) as u8)
     }
 
-    pub fn get_nr_bits(&self) -> u32 {
-        self.inner.borrow().end_bit_nr - self.inner.borrow().start_bit_nr + 1
+    pub fn set_correct_parity(&mut self, block: BlockId, correct_parity: u8) {
+        let flags = &mut self.nodes[block.index()].flags;
+        *flags &= !PARITY_VALUE;
+        *flags |= PARITY_KNOWN | if correct_parity == 1 { PARITY_VALUE } else { 0 };
     }
 
-    pub fn get_correct_parity(&self) -> Option<u8> {
-        self.inner.borrow().correct_parity
+    /// The correct parity was asked from the remote.
+    pub fn was_asked(&self, block: BlockId) -> bool {
+        self.node(block).flags & PARITY_ASKED != 0
     }
 
-    pub fn get_or_compute_current_parity(&self) -> u8 {
-        let current_parity = self.inner.borrow().current_parity;
-        match current_parity {
-            Some(parity) => parity,
-            None => {
-                println!("compute_current_parity {}", self);
-                let start_bit_nr = self.inner.borrow().start_bit_nr;
-                let end_bit_nr = self.inner.borrow().end_bit_nr;
-                let parity = self
-                    .inner
-                    .borrow()
-                    .shuffled_key
-                    .compute_range_parity(start_bit_nr, end_bit_nr);
-                self.inner.borrow_mut().current_parity = Some(parity);
-                parity
-            }
-        }
+    /// The correct parity was inferred from the parent and the siblings.
+    pub fn was_inferred(&self, block: BlockId) -> bool {
+        self.node(block).flags & PARITY_INFERRED != 0
     }
-    pub fn correct_bit(&self, bit_nr: u32) {
-        self.inner.borrow_mut().shuffled_key.flip_bit(bit_nr);
+
+    /// The single bit of the block was corrected.
+    pub fn is_corrected(&self, block: BlockId) -> bool {
+        self.node(block).flags & CORRECTED_BIT != 0
     }
-    pub fn flip_current_parity(&self) {
-        if self.inner.borrow().current_parity.is_none() {
-            println!("current_parity is unknown, skip flip block {} ", self);
-            return;
-        }
-        println!("flip_current_parity { }", self);
-        let current_parity = self.inner.borrow().current_parity.unwrap();
 
-        self.inner.borrow_mut().current_parity = Some(1 - current_parity);
+    /// Record that the single bit of the block was corrected, the caller flips the bit.
+    pub fn mark_corrected(&mut self, block: BlockId) {
+        assert_eq!(self.get_nr_bits(block), 1, "only a single bit is corrected");
+        self.nodes[block.index()].flags |= CORRECTED_BIT;
     }
 
-    pub fn set_correct_parity(&self, correct_parity: u8) {
-        self.inner.borrow_mut().correct_parity = Some(correct_parity);
+    /// Compute the current parity of the bits in the block.
+    ///
+    /// The shuffled key keeps a parity index, so this costs O(log n) and reflects the bits
+    /// corrected so far, in this or any other iteration, once the shuffled key is synced.
+    /// There is no cached parity to keep in sync.
+    pub fn compute_current_parity(&self, block: BlockId, shuffled_key: &ShuffledKey) -> u8 {
+        let node = self.node(block);
+        shuffled_key.compute_range_parity(node.start_bit_nr, node.end_bit_nr)
     }
 
     /// Error Parity.
-    /// # Panics
     ///
-    /// Panics if `correct_parity` is not known
+    /// # Errors
+    ///
+    /// [`Error::UnknownCorrectParity`] if `correct_parity` is not known
     ///
     /// # Returns
     ///
     /// `true`: Odd number of errors in block,
     /// `false`: Even number of errors in block
-    pub fn get_error_parity(&self) -> bool {
-        let correct_parity = self
-            .inner
-            .borrow()
-            .correct_parity
-            .expect("correct_parity must be known");
-        let current_parity = self.get_or_compute_current_parity();
-        let error_parity = current_parity != correct_parity;
-        // println!("get_error_parity: {}, block: {} ", error_parity, self);
-        error_parity
-    }
-
-    pub fn get_parent_block(&self) -> Option<BlockRef> {
-        match self.inner.borrow().parent {
-            Some(ref weak_parent) => {
-                let parent = weak_parent.upgrade().expect("Parent block must exist");
-                Some(parent)
-            }
-            None => None,
-        }
+    pub fn get_error_parity(&self, block: BlockId, shuffled_key: &ShuffledKey) -> Result<bool> {
+        let correct_parity =
+            self.get_correct_parity(block)
+                .ok_or_else(|| Error::UnknownCorrectParity {
+                    start_bit_nr: self.get_start_bit_nr(block),
+                    end_bit_nr: self.get_end_bit_nr(block),
+                })?;
+        Ok(self.compute_current_parity(block, shuffled_key) != correct_parity)
+    }
+
+    pub fn get_parent_block(&self, block: BlockId) -> Option<BlockId> {
+        let parent = self.node(block).parent;
+        (parent != NO_BLOCK).then_some(BlockId(parent))
     }
 
-    pub fn get_left_sub_block(&self) -> Option<BlockRef> {
-        self.inner.borrow().left_sub_block.clone()
+    pub fn get_left_sub_block(&self, block: BlockId) -> Option<BlockId> {
+        let first_child = self.node(block).first_child;
+        (first_child != NO_BLOCK).then_some(BlockId(first_child))
     }
 
-    pub fn get_right_sub_block(&self) -> Option<BlockRef> {
-        self.inner.borrow().right_sub_block.clone()
+    /// The last sub block.
+    pub fn get_right_sub_block(&self, block: BlockId) -> Option<BlockId> {
+        let node = self.node(block);
+        (node.first_child != NO_BLOCK)
+            .then(|| BlockId(node.first_child + u32::from(node.nr_sub_blocks) - 1))
+    }
+
+    /// The sub blocks in order of their bits, none if the block is not split.
+    pub fn get_sub_blocks(&self, block: BlockId) -> impl ExactSizeIterator<Item = BlockId> {
+        let node = self.node(block);
+        let first_child = if node.first_child == NO_BLOCK {
+            0
+        } else {
+            node.first_child
+        };
+        (first_child..first_child + u32::from(node.nr_sub_blocks)).map(BlockId)
     }
 
-    pub fn has_sub_blocks(&self) -> bool {
-        // either both are None or both are Some
-        assert!(self.get_left_sub_block().is_some() == self.get_right_sub_block().is_some());
-        self.get_left_sub_block().is_some() && self.get_right_sub_block().is_some()
+    pub fn has_sub_blocks(&self, block: BlockId) -> bool {
+        self.node(block).first_child != NO_BLOCK
     }
 
-    /// Create a new sub block and set the parent block to self
+    /// Get the left and right sub blocks of a block split in halves, split it if the block
+    /// has no sub blocks yet.
     ///
-    /// # Arguments
-    /// left: if true, create a left sub block, otherwise create a right sub block
-    pub fn create_sub_block(self: &BlockRef, left: SubBlockType) -> BlockRef {
-        let start_bit_nr = self.inner.borrow().start_bit_nr;
-        let end_bit_nr = self.inner.borrow().end_bit_nr;
-        let mid_bit_nr = (start_bit_nr + end_bit_nr) / 2;
-        let sub_block = match left {
-            SubBlockType::Left => {
-                let block = Block::new(
-                    BlockType::SubBlock(SubBlockType::Left),
-                    start_bit_nr,
-                    mid_bit_nr,
-                    self.inner.borrow().shuffled_key.clone(),
-                );
-                self.inner.borrow_mut().left_sub_block = Some(block.clone());
-                block
-            }
-            SubBlockType::Right => {
-                let block = Block::new(
-                    BlockType::SubBlock(SubBlockType::Right),
-                    mid_bit_nr + 1,
-                    end_bit_nr,
-                    self.inner.borrow().shuffled_key.clone(),
-                );
-                self.inner.borrow_mut().right_sub_block = Some(block.clone());
-                block
+    /// The left sub block takes the larger half of an odd sized block.
+    pub fn get_or_create_sub_blocks(&mut self, block: BlockId) -> (BlockId, BlockId) {
+        let mut sub_blocks = self.get_or_split(block, SplitStrategy::Bisect);
+        assert_eq!(2, sub_blocks.len(), "the block is not split in halves");
+        (sub_blocks.next().unwrap(), sub_blocks.next().unwrap())
+    }
+
+    /// Get the sub blocks, split the block with `split_strategy` if it has no sub blocks yet.
+    pub fn get_or_split(
+        &mut self,
+        block: BlockId,
+        split_strategy: SplitStrategy,
+    ) -> impl ExactSizeIterator<Item = BlockId> {
+        if !self.has_sub_blocks(block) {
+            let node = self.node(block);
+            let sub_blocks = split_strategy.split(node.start_bit_nr, node.end_bit_nr);
+            let first_child = BlockId(self.nodes.len() as u32);
+            for (start_bit_nr, end_bit_nr) in &sub_blocks {
+                self.push(*start_bit_nr, *end_bit_nr, block.0);
             }
-        };
-        // set parent for sub block
-        let parent_block = Rc::downgrade(self);
-        sub_block.inner.borrow_mut().parent = Some(parent_block);
-        sub_block
+            let node = &mut self.nodes[block.index()];
+            node.first_child = first_child.0;
+            node.nr_sub_blocks = u8::try_from(sub_blocks.len()).expect("at most 255 sub blocks");
+        }
+        self.get_sub_blocks(block)
     }
 
     /// Try to infer the correct parity of the block.
@@ -229,83 +298,142 @@ impl Block {
     /// # Returns
     ///
     /// `true`: correct parity was inferred, otherwise `false`
-    pub fn try_to_infer_correct_parity(self: &BlockRef) -> bool {
+    pub fn try_to_infer_correct_parity(&mut self, block: BlockId) -> bool {
         // only try to infer if correct_parity is not known yet
-        if self.get_correct_parity().is_some() {
+        if self.get_correct_parity(block).is_some() {
             return true;
         }
 
         // Cannot infer if there is no parent block.
-        if self.inner.borrow().parent.is_none() {
+        let Some(parent_block) = self.get_parent_block(block) else {
             return false;
-        }
-
-        let parent_block = self
-            .inner
-            .borrow()
-            .parent
-            .as_ref()
-            .unwrap()
-            .upgrade()
-            .expect("parent block must exist");
-
-        // Cannot infer if there is no sibling block (yet).
-        if parent_block.get_left_sub_block().is_none()
-            || parent_block.get_right_sub_block().is_none()
-        {
-            return false;
-        }
+        };
 
         // Cannot infer if the correct parity of the parent is unknown.
-        if parent_block.get_correct_parity().is_none() {
+        let Some(parent_parity) = self.get_correct_parity(parent_block) else {
             return false;
+        };
+
+        // the sub blocks are created together, the siblings always exist
+        let mut parity = parent_parity;
+        for sibling_block in self.get_sub_blocks(parent_block) {
+            if sibling_block == block {
+                continue;
+            }
+            // Cannot infer if the correct parity of a sibling is unknown.
+            let Some(sibling_parity) = self.get_correct_parity(sibling_block) else {
+                return false;
+            };
+            parity ^= sibling_parity;
         }
+        // XOR the correct parities of the parent and sibling blocks to get the correct parity of this block
+        self.set_correct_parity(block, parity);
+        self.nodes[block.index()].flags |= PARITY_INFERRED;
+        true
+    }
 
-        // get sibling block
-        let mut sibling_block = parent_block.get_left_sub_block().unwrap();
+    /// Ask the correct parity of the block through the channel, unless it is known.
+    pub fn ask_correct_parity(
+        &mut self,
+        block: BlockId,
+        shuffled_key: &mut ShuffledKey,
+        channel: &mut dyn ParityChannel,
+    ) -> Result<bool> {
+        Ok(!self
+            .ask_correct_parities(&[block], shuffled_key, channel)?
+            .is_empty())
+    }
 
-        // check equality of pointers to determine if self is the left or right sub block
-        if Rc::ptr_eq(self, &sibling_block) {
-            // if self is the left sub block, get the right sub block
-            sibling_block = parent_block.get_right_sub_block().unwrap();
-        }
-        // Cannot infer if the correct parity of the sibling is unknown.
-        if sibling_block.get_correct_parity().is_none() {
-            return false;
+    /// Ask the correct parities of the blocks whose parity is unknown through the channel,
+    /// in one round trip.
+    ///
+    /// # Returns
+    ///
+    /// the blocks whose parity was asked
+    ///
+    /// # Errors
+    ///
+    /// the error of the channel, or of [`BlockTree::set_asked_parities`]
+    pub fn ask_correct_parities(
+        &mut self,
+        blocks: &[BlockId],
+        shuffled_key: &mut ShuffledKey,
+        channel: &mut dyn ParityChannel,
+    ) -> Result<Vec<BlockId>> {
+        let asked_blocks = self.get_blocks_to_ask(blocks);
+        if !asked_blocks.is_empty() {
+            let requests = self.parity_requests(&asked_blocks, shuffled_key);
+            let correct_parities = channel.ask_correct_parities(&requests)?;
+            self.set_asked_parities(&asked_blocks, &correct_parities)?;
         }
-        // XOR the correct parities of the parent and sibling block to get the correct parity of this block
-        let inferred_correct_parity = parent_block.get_correct_parity().unwrap()
-            ^ sibling_block.get_correct_parity().unwrap();
-        self.inner.borrow_mut().correct_parity = Some(inferred_correct_parity);
-        true
+        Ok(asked_blocks)
     }
 
-    // simulate asking the correct parity of the block
-    // calculate correct parity using original correct key
-    pub fn ask_correct_parity(self: &BlockRef) {
-        if self.get_correct_parity().is_some() {
-            println!("Correct parity already known: {}", self);
-            return;
+    /// The blocks whose correct parity is unknown, to ask in one round trip.
+    pub fn get_blocks_to_ask(&self, blocks: &[BlockId]) -> Vec<BlockId> {
+        let mut asked_blocks = Vec::with_capacity(blocks.len());
+        for &block in blocks {
+            let start_bit_nr = self.get_start_bit_nr(block);
+            let end_bit_nr = self.get_end_bit_nr(block);
+            if self.get_correct_parity(block).is_some() {
+                trace!(start_bit_nr, end_bit_nr, "correct parity already known");
+            } else {
+                trace!(
+                    block_type = ?self.get_block_type(block),
+                    start_bit_nr,
+                    end_bit_nr,
+                    "ask correct parity"
+                );
+                asked_blocks.push(block);
+            }
         }
-        println!("Ask correct parity: {}", self);
+        asked_blocks
+    }
 
-        let correct_parity = self
-            .inner
-            .borrow()
-            .shuffled_key
-            .ask_correct_range_parity(self.get_start_bit_nr(), self.get_end_bit_nr());
-        self.set_correct_parity(correct_parity);
+    /// The requests for the correct parities of the blocks, see [`ShuffledKey::parity_requests`].
+    pub fn parity_requests(
+        &self,
+        blocks: &[BlockId],
+        shuffled_key: &mut ShuffledKey,
+    ) -> Vec<ParityRequest> {
+        let ranges: Vec<(u32, u32)> = blocks
+            .iter()
+            .map(|&block| (self.get_start_bit_nr(block), self.get_end_bit_nr(block)))
+            .collect();
+        shuffled_key.parity_requests(&ranges)
+    }
+
+    /// Set the correct parities asked for the blocks, in order.
+    ///
+    /// # Errors
+    ///
+    /// [`Error::MalformedMessage`] if the replies are not one parity of 0 or 1 per block,
+    /// then no parity is set
+    pub fn set_asked_parities(
+        &mut self,
+        blocks: &[BlockId],
+        correct_parities: &[u8],
+    ) -> Result<()> {
+        if correct_parities.len() != blocks.len() {
+            return Err(Error::MalformedMessage("not one parity per request"));
+        }
+        if correct_parities.iter().any(|&parity| parity > 1) {
+            return Err(Error::MalformedMessage("parity is not 0 or 1"));
+        }
+        for (&block, &correct_parity) in blocks.iter().zip(correct_parities) {
+            self.set_correct_parity(block, correct_parity);
+            self.nodes[block.index()].flags |= PARITY_ASKED;
+        }
+        Ok(())
     }
-}
 
-impl std::fmt::Display for Block {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
+    /// Printable block type and range.
+    pub fn display(&self, block: BlockId) -> impl std::fmt::Display {
+        format!(
             "{:?} ({}-{})",
-            self.get_block_type(),
-            self.get_start_bit_nr(),
-            self.get_end_bit_nr()
+            self.get_block_type(block),
+            self.get_start_bit_nr(block),
+            self.get_end_bit_nr(block)
         )
     }
 }
@@ -313,118 +441,199 @@ impl std::fmt::Display for Block {
 #[cfg(test)]
 mod tests {
     use crate::{
-        block::{Block, BlockRef, BlockType, SubBlockType},
+        block::{BlockId, BlockTree, BlockType, SubBlockType},
+        error::Error,
         key::Key,
+        protocol::SimulatedParityChannel,
         shuffle,
-        shuffled_key::{SharedKey, ShuffledKey},
+        shuffled_key::ShuffledKey,
+        split::SplitStrategy,
     };
-    use std::{cell::RefCell, mem, rc::Rc};
+    use std::{mem, sync::Arc};
 
-    fn create_test_shuffled_key() -> (BlockRef, SharedKey) {
+    fn create_test_shuffled_key() -> (BlockTree, BlockId, ShuffledKey, Key) {
         const SEED: u64 = 0x1234567890ABCDEF;
         const KEY_STR: &str = "10010001";
-        let correct_key = Key::from(KEY_STR);
-        let key = Rc::new(RefCell::new(correct_key.clone()));
+        let key = Key::from(KEY_STR);
         let shuffle =
-            shuffle::Shuffle::new_shuffle_from_seed(1, key.borrow().get_nr_bits(), SEED, true);
+            shuffle::Shuffle::new_shuffle_from_seed(1, key.get_nr_bits(), SEED, true).unwrap();
+        let mut blocks = BlockTree::new();
         let top_block_start_bit_nr = 0;
         let top_block_end_bit_nr = 3;
-        let block = Block::new(
-            BlockType::TopLevel,
-            top_block_start_bit_nr,
-            top_block_end_bit_nr,
-            ShuffledKey::new(Rc::new(correct_key), key.clone(), shuffle),
-        );
+        let block = blocks.add_top_block(top_block_start_bit_nr, top_block_end_bit_nr);
+        let shuffled_key = ShuffledKey::new(&key, shuffle);
 
-        (block, key)
+        (blocks, block, shuffled_key, key)
     }
 
     #[test]
-    fn test_apis_with_block_receiver() {
-        let (block, key) = create_test_shuffled_key();
+    fn test_block_apis() {
+        let (mut blocks, block, mut shuffled_key, mut key) = create_test_shuffled_key();
 
-        assert_eq!(block.get_start_bit_nr(), 0);
-        assert_eq!(block.get_end_bit_nr(), 3);
-        assert_eq!(block.get_nr_bits(), 4);
-        assert_eq!(block.get_block_type(), BlockType::TopLevel);
+        assert_eq!(blocks.get_start_bit_nr(block), 0);
+        assert_eq!(blocks.get_end_bit_nr(block), 3);
+        assert_eq!(blocks.get_nr_bits(block), 4);
+        assert_eq!(blocks.get_block_type(block), BlockType::TopLevel);
         assert_eq!(mem::size_of::<BlockType>(), 1);
-        assert!(block.get_correct_parity().is_none());
-        assert!(block.get_parent_block().is_none());
-        assert!(block.get_left_sub_block().is_none());
-        assert!(block.get_right_sub_block().is_none());
-
-        // parity is not known yet, so it should be computed
-        assert_eq!(block.get_or_compute_current_parity(), 0);
-        // flip the parity after a single bit in Key is corrected, so that we do not need to recompute the parity
+        assert!(blocks.get_correct_parity(block).is_none());
+        assert_eq!(
+            Err(Error::UnknownCorrectParity {
+                start_bit_nr: 0,
+                end_bit_nr: 3
+            }),
+            blocks.get_error_parity(block, &shuffled_key)
+        );
+        assert!(blocks.get_parent_block(block).is_none());
+        assert!(blocks.get_left_sub_block(block).is_none());
+        assert!(blocks.get_right_sub_block(block).is_none());
+
+        assert_eq!(blocks.compute_current_parity(block, &shuffled_key), 0);
+        // the current parity follows a bit corrected in Key
         // Note: this is not the correct way to correct a bit in Key, but it is sufficient for testing
-        key.borrow_mut().flip_bit(0);
+        key.flip_bit(0);
         assert_eq!(
-            key.borrow()
-                .compute_range_parity(block.get_start_bit_nr(), block.get_end_bit_nr()),
+            key.compute_range_parity(blocks.get_start_bit_nr(block), blocks.get_end_bit_nr(block)),
             1
         );
-        block.flip_current_parity();
-        assert_eq!(block.get_or_compute_current_parity(), 1);
+        shuffled_key.sync(&key);
+        assert_eq!(blocks.compute_current_parity(block, &shuffled_key), 1);
 
         // set correct_parity, assume we got it from the remote
-        block.set_correct_parity(0);
-        assert_eq!(block.get_correct_parity(), Some(0));
+        blocks.set_correct_parity(block, 0);
+        assert_eq!(blocks.get_correct_parity(block), Some(0));
         // check error parity
-        assert_eq!(block.get_error_parity(), true);
+        assert_eq!(Ok(true), blocks.get_error_parity(block, &shuffled_key));
+        blocks.set_correct_parity(block, 1);
+        assert_eq!(blocks.get_correct_parity(block), Some(1));
+        assert_eq!(Ok(false), blocks.get_error_parity(block, &shuffled_key));
     }
 
     #[test]
-    pub fn test_apis_with_rc_receiver() {
-        let (top_block, _) = create_test_shuffled_key();
-        let block2 = top_block.clone();
-        let left_sub_block = top_block.create_sub_block(SubBlockType::Left);
-        let right_sub_block = top_block.create_sub_block(SubBlockType::Right);
-        // two Rc: top_block + block2
-        assert_eq!(Rc::strong_count(&top_block), 2);
-        // two Rc: `left_sub_block` + the one insdie `top_block`
-        assert_eq!(Rc::strong_count(&left_sub_block), 2);
-        // two Rc: `right_sub_block` + the one insdie `top_block`
-        assert_eq!(Rc::strong_count(&right_sub_block), 2);
-
-        // move the blocks into a closure and drop them
-        #[allow(path_statements)]
-        let f = move || {
-            top_block;
-            left_sub_block;
-            right_sub_block;
-        };
-        f();
+    pub fn test_sub_blocks() {
+        let (mut blocks, top_block, _, _) = create_test_shuffled_key();
+        let other_top_block = blocks.add_top_block(4, 6);
+        let (left_sub_block, right_sub_block) = blocks.get_or_create_sub_blocks(top_block);
+        assert_eq!(4, blocks.get_nr_blocks());
+        assert_eq!(
+            vec![top_block, other_top_block],
+            blocks.get_top_blocks().collect::<Vec<_>>()
+        );
+        // sub blocks are created once
+        assert_eq!(
+            (left_sub_block, right_sub_block),
+            blocks.get_or_create_sub_blocks(top_block)
+        );
+        assert_eq!(4, blocks.get_nr_blocks());
+        assert!(blocks.has_sub_blocks(top_block));
+        assert_eq!(Some(left_sub_block), blocks.get_left_sub_block(top_block));
+        assert_eq!(Some(right_sub_block), blocks.get_right_sub_block(top_block));
+        assert_eq!(Some(top_block), blocks.get_parent_block(left_sub_block));
+        assert_eq!(Some(top_block), blocks.get_parent_block(right_sub_block));
+        assert_eq!(
+            BlockType::SubBlock(SubBlockType::Left),
+            blocks.get_block_type(left_sub_block)
+        );
+        assert_eq!(
+            BlockType::SubBlock(SubBlockType::Right),
+            blocks.get_block_type(right_sub_block)
+        );
+        assert_eq!((0, 1), range(&blocks, left_sub_block));
+        assert_eq!((2, 3), range(&blocks, right_sub_block));
 
-        assert_eq!(Rc::strong_count(&block2), 1);
+        // the left sub block takes the larger half
+        let (left_sub_block, right_sub_block) = blocks.get_or_create_sub_blocks(other_top_block);
+        assert_eq!((4, 5), range(&blocks, left_sub_block));
+        assert_eq!((6, 6), range(&blocks, right_sub_block));
+    }
+
+    fn range(blocks: &BlockTree, block: BlockId) -> (u32, u32) {
+        (blocks.get_start_bit_nr(block), blocks.get_end_bit_nr(block))
+    }
+
+    #[test]
+    #[should_panic(expected = "top blocks come before sub blocks")]
+    pub fn test_top_blocks_first() {
+        let (mut blocks, top_block, _, _) = create_test_shuffled_key();
+        blocks.get_or_create_sub_blocks(top_block);
+        blocks.add_top_block(4, 7);
     }
 
     #[test]
     pub fn test_infer_correct_parity() {
-        let (top_block, _) = create_test_shuffled_key();
-        let left_sub_block = top_block.create_sub_block(SubBlockType::Left);
-        let right_sub_block = top_block.create_sub_block(SubBlockType::Right);
+        let (mut blocks, top_block, _, _) = create_test_shuffled_key();
+        let (left_sub_block, right_sub_block) = blocks.get_or_create_sub_blocks(top_block);
 
         // cannot infer if there is no parent block
-        assert_eq!(top_block.try_to_infer_correct_parity(), false);
+        assert!(!blocks.try_to_infer_correct_parity(top_block));
 
         // cannot infer if the correct parity of the parent is unknown
-        assert_eq!(left_sub_block.try_to_infer_correct_parity(), false);
-        assert_eq!(right_sub_block.try_to_infer_correct_parity(), false);
+        assert!(!blocks.try_to_infer_correct_parity(left_sub_block));
+        assert!(!blocks.try_to_infer_correct_parity(right_sub_block));
 
         // set correct_parity, assume we got it from the remote
-        top_block.set_correct_parity(0);
-        assert_eq!(top_block.get_correct_parity(), Some(0));
+        blocks.set_correct_parity(top_block, 0);
+        assert_eq!(blocks.get_correct_parity(top_block), Some(0));
 
         // cannot infer if the correct parity of the sibling is unknown
-        assert_eq!(left_sub_block.try_to_infer_correct_parity(), false);
-        assert_eq!(right_sub_block.try_to_infer_correct_parity(), false);
+        assert!(!blocks.try_to_infer_correct_parity(left_sub_block));
+        assert!(!blocks.try_to_infer_correct_parity(right_sub_block));
 
         // set correct_parity, assume we got it from the remote
-        left_sub_block.set_correct_parity(0);
-        assert_eq!(left_sub_block.get_correct_parity(), Some(0));
+        blocks.set_correct_parity(left_sub_block, 0);
+        assert_eq!(blocks.get_correct_parity(left_sub_block), Some(0));
 
         // XOR the correct parities of the parent and sibling block to get the correct parity of this block
-        assert_eq!(right_sub_block.try_to_infer_correct_parity(), true);
-        assert_eq!(right_sub_block.get_correct_parity(), Some(0));
+        assert!(blocks.try_to_infer_correct_parity(right_sub_block));
+        assert_eq!(blocks.get_correct_parity(right_sub_block), Some(0));
+        assert!(blocks.was_inferred(right_sub_block));
+        assert!(!blocks.was_inferred(left_sub_block));
+        assert!(!blocks.was_asked(right_sub_block));
+    }
+
+    #[test]
+    pub fn test_k_ary_sub_blocks() {
+        let (mut blocks, top_block, mut shuffled_key, key) = create_test_shuffled_key();
+        // no errors, the correct key is the noise key
+        let mut channel = SimulatedParityChannel::new(Arc::new(key));
+        let sub_blocks: Vec<BlockId> = blocks
+            .get_or_split(top_block, SplitStrategy::KAry(3))
+            .collect();
+        assert_eq!(
+            vec![(0, 1), (2, 2), (3, 3)],
+            sub_blocks
+                .iter()
+                .map(|&block| range(&blocks, block))
+                .collect::<Vec<_>>()
+        );
+        // the sub blocks of the first split are reused, whatever the strategy
+        assert_eq!(
+            sub_blocks,
+            blocks
+                .get_or_split(top_block, SplitStrategy::Bisect)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(sub_blocks[2]), blocks.get_right_sub_block(top_block));
+        assert_eq!(
+            BlockType::SubBlock(SubBlockType::Middle),
+            blocks.get_block_type(sub_blocks[1])
+        );
+
+        // infer the last sub block from the parent and all its siblings
+        blocks
+            .ask_correct_parity(top_block, &mut shuffled_key, &mut channel)
+            .unwrap();
+        blocks
+            .ask_correct_parities(&sub_blocks[..1], &mut shuffled_key, &mut channel)
+            .unwrap();
+        assert!(!blocks.try_to_infer_correct_parity(sub_blocks[2]));
+        blocks
+            .ask_correct_parities(&sub_blocks[1..2], &mut shuffled_key, &mut channel)
+            .unwrap();
+        assert!(blocks.try_to_infer_correct_parity(sub_blocks[2]));
+        assert_eq!(
+            Some(shuffled_key.compute_range_parity(3, 3)),
+            blocks.get_correct_parity(sub_blocks[2])
+        );
+        assert_eq!(3, shuffled_key.get_wire_stats().nr_round_trips);
     }
 }
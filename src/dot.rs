@@ -0,0 +1,147 @@
+//! Graphviz DOT export of the block trees, to see how the binary search of every iteration
+//! proceeded.
+//!
+//! Every iteration is a cluster, every block a node with its range of shuffled bits,
+//! its correct parity and how it became known (asked or inferred), and its current parity.
+//! The single bit blocks that were corrected are filled and show the bit number in the noise key.
+//!
+//! Render with `dot -Tsvg cascade.dot -o cascade.svg`.
+
+use std::fmt::{self, Write};
+
+use crate::{
+    block::{BlockId, BlockTree},
+    shuffled_key::ShuffledKey,
+};
+
+/// DOT graph of the given iterations.
+///
+/// # Arguments
+/// write_iterations: writes the iterations into the graph, see [`write_iteration`]
+pub fn graph(write_iterations: impl FnOnce(&mut String) -> fmt::Result) -> String {
+    let mut dot = String::new();
+    dot.push_str("digraph cascade {\n  node [shape=box, fontname=monospace];\n");
+    write_iterations(&mut dot).expect("writing to a String cannot fail");
+    dot.push_str("}\n");
+    dot
+}
+
+/// Write the blocks of one iteration as a cluster.
+///
+/// The current parities are the ones of the shuffled key as of its last sync.
+pub fn write_iteration(
+    out: &mut impl Write,
+    iteration_nr: u32,
+    blocks: &BlockTree,
+    shuffled_key: &ShuffledKey,
+) -> fmt::Result {
+    writeln!(out, "  subgraph cluster_iteration_{} {{", iteration_nr)?;
+    writeln!(out, "    label=\"Iteration {}\";", iteration_nr)?;
+    for block in blocks.get_all_blocks() {
+        let node = node_id(iteration_nr, block);
+        let correct_parity = match blocks.get_correct_parity(block) {
+            Some(parity) => parity.to_string(),
+            None => "?".to_string(),
+        };
+        let source = if blocks.was_asked(block) {
+            " (asked)"
+        } else if blocks.was_inferred(block) {
+            " (inferred)"
+        } else {
+            ""
+        };
+        write!(
+            out,
+            "    {} [label=\"{}-{}\\ncorrect: {}{}\\ncurrent: {}",
+            node,
+            blocks.get_start_bit_nr(block),
+            blocks.get_end_bit_nr(block),
+            correct_parity,
+            source,
+            blocks.compute_current_parity(block, shuffled_key)
+        )?;
+        if blocks.is_corrected(block) {
+            let orig_bit_nr = shuffled_key.shuffle_to_orig_bit_nr(blocks.get_start_bit_nr(block));
+            write!(
+                out,
+                "\\ncorrected bit: {}\", style=filled, fillcolor=lightgreen",
+                orig_bit_nr
+            )?;
+        } else {
+            write!(out, "\"")?;
+        }
+        writeln!(out, "];")?;
+        if let Some(parent_block) = blocks.get_parent_block(block) {
+            writeln!(
+                out,
+                "    {} -> {};",
+                node_id(iteration_nr, parent_block),
+                node
+            )?;
+        }
+    }
+    writeln!(out, "  }}")
+}
+
+fn node_id(iteration_nr: u32, block: BlockId) -> String {
+    format!("i{}_b{}", iteration_nr, block.index())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        block::BlockTree, key::Key, protocol::SimulatedParityChannel, shuffle::Shuffle,
+        shuffled_key::ShuffledKey,
+    };
+
+    use super::{graph, write_iteration};
+
+    #[test]
+    fn test_write_iteration() {
+        const KEY_STR: &str = "10010001";
+        let mut channel = SimulatedParityChannel::new(Arc::new(Key::from(KEY_STR)));
+        let mut noise_key = Key::from(KEY_STR);
+        // iteration 1 is not shuffled
+        let shuffle = Shuffle::new_shuffle_from_seed(1, 8, 0, false).unwrap();
+        let mut shuffled_key = ShuffledKey::new(&noise_key, shuffle);
+        let mut blocks = BlockTree::new();
+        let top_block = blocks.add_top_block(0, 3);
+        blocks.add_top_block(4, 7);
+
+        // an error in bit 1
+        shuffled_key.flip_bit(&mut noise_key, 1);
+        blocks
+            .ask_correct_parity(top_block, &mut shuffled_key, &mut channel)
+            .unwrap();
+        let (left_sub_block, right_sub_block) = blocks.get_or_create_sub_blocks(top_block);
+        blocks
+            .ask_correct_parity(left_sub_block, &mut shuffled_key, &mut channel)
+            .unwrap();
+        blocks.try_to_infer_correct_parity(right_sub_block);
+        let (_, bit_1_block) = blocks.get_or_create_sub_blocks(left_sub_block);
+        shuffled_key.flip_bit(&mut noise_key, 1);
+        blocks.mark_corrected(bit_1_block);
+
+        let dot = graph(|out| write_iteration(out, 1, &blocks, &shuffled_key));
+        let expected = r#"digraph cascade {
+  node [shape=box, fontname=monospace];
+  subgraph cluster_iteration_1 {
+    label="Iteration 1";
+    i1_b0 [label="0-3\ncorrect: 0 (asked)\ncurrent: 0"];
+    i1_b1 [label="4-7\ncorrect: ?\ncurrent: 1"];
+    i1_b2 [label="0-1\ncorrect: 1 (asked)\ncurrent: 1"];
+    i1_b0 -> i1_b2;
+    i1_b3 [label="2-3\ncorrect: 1 (inferred)\ncurrent: 1"];
+    i1_b0 -> i1_b3;
+    i1_b4 [label="0-0\ncorrect: ?\ncurrent: 1"];
+    i1_b2 -> i1_b4;
+    i1_b5 [label="1-1\ncorrect: ?\ncurrent: 0\ncorrected bit: 1", style=filled, fillcolor=lightgreen];
+    i1_b2 -> i1_b5;
+  }
+}
+"#;
+        assert_eq!(expected, dot);
+    }
+}
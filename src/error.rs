@@ -0,0 +1,150 @@
+//! Errors of the inputs that come from outside the engine: key strings, peer requests,
+//! recorded shuffles and the parameters of the public constructors.
+//!
+//! Misuse of the engine internals, e.g. a bit number of another key, still panics.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A key needs at least one bit.
+    EmptyKey,
+    /// A key has at most `u32::MAX` bits.
+    KeyTooLong {
+        nr_bits: usize,
+    },
+    /// A key string holds something else than `0` and `1`.
+    InvalidKeyCharacter {
+        position: usize,
+        character: char,
+    },
+    BitOutOfRange {
+        bit_nr: u32,
+        nr_bits: u32,
+    },
+    /// Not an inclusive range `start_bit_nr..=end_bit_nr` of the bits of the key.
+    InvalidRange {
+        start_bit_nr: u32,
+        end_bit_nr: u32,
+        nr_bits: u32,
+    },
+    /// The iterations are numbered from 1.
+    InvalidIterationNr(u32),
+    /// A parity request names an iteration after the agreed number of iterations.
+    IterationNrOutOfRange {
+        iteration_nr: u32,
+        nr_iterations: u32,
+    },
+    /// A recorded shuffle maps two shuffled bits to the bit, or a bit out of range.
+    InvalidPermutation {
+        orig_bit_nr: u32,
+    },
+    /// The correct parity of the block `start_bit_nr..=end_bit_nr` is neither asked nor inferred.
+    UnknownCorrectParity {
+        start_bit_nr: u32,
+        end_bit_nr: u32,
+    },
+    /// A parity request names a shuffle seed that is not the agreed seed of its iteration,
+    /// see [`seed`](crate::seed).
+    UnexpectedShuffleSeed {
+        iteration_nr: u32,
+    },
+    /// The QBER estimation discloses more bits of the key than allowed.
+    TooManyDisclosedBits {
+        nr_disclosed_bits: u32,
+        max_disclosed_bits: u32,
+    },
+    /// The bytes are not a valid parity request, see [`protocol`](crate::protocol).
+    MalformedRequest(&'static str),
+    /// A session message from the peer is inconsistent, see [`session`](crate::session).
+    MalformedMessage(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyKey => write!(f, "empty key"),
+            Self::KeyTooLong { nr_bits } => {
+                write!(
+                    f,
+                    "key of {} bits, at most {} are supported",
+                    nr_bits,
+                    u32::MAX
+                )
+            }
+            Self::InvalidKeyCharacter {
+                position,
+                character,
+            } => write!(
+                f,
+                "invalid key character {:?} at position {}, expected 0 or 1",
+                character, position
+            ),
+            Self::BitOutOfRange { bit_nr, nr_bits } => {
+                write!(
+                    f,
+                    "bit {} out of range, the key has {} bits",
+                    bit_nr, nr_bits
+                )
+            }
+            Self::InvalidRange {
+                start_bit_nr,
+                end_bit_nr,
+                nr_bits,
+            } => write!(
+                f,
+                "invalid range {}..={}, the key has {} bits",
+                start_bit_nr, end_bit_nr, nr_bits
+            ),
+            Self::InvalidIterationNr(iteration_nr) => {
+                write!(
+                    f,
+                    "invalid iteration number {}, the first is 1",
+                    iteration_nr
+                )
+            }
+            Self::IterationNrOutOfRange {
+                iteration_nr,
+                nr_iterations,
+            } => write!(
+                f,
+                "iteration {} out of range, the session has {} iterations",
+                iteration_nr, nr_iterations
+            ),
+            Self::InvalidPermutation { orig_bit_nr } => {
+                write!(
+                    f,
+                    "not a permutation, bit {} out of range or shuffled twice",
+                    orig_bit_nr
+                )
+            }
+            Self::UnknownCorrectParity {
+                start_bit_nr,
+                end_bit_nr,
+            } => write!(
+                f,
+                "correct parity of the block {}..={} is unknown",
+                start_bit_nr, end_bit_nr
+            ),
+            Self::UnexpectedShuffleSeed { iteration_nr } => write!(
+                f,
+                "the shuffle seed of iteration {} is not the agreed one",
+                iteration_nr
+            ),
+            Self::TooManyDisclosedBits {
+                nr_disclosed_bits,
+                max_disclosed_bits,
+            } => write!(
+                f,
+                "the estimation discloses {} bits, at most {} are allowed",
+                nr_disclosed_bits, max_disclosed_bits
+            ),
+            Self::MalformedRequest(reason) => write!(f, "malformed parity request: {}", reason),
+            Self::MalformedMessage(reason) => write!(f, "malformed session message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,285 @@
+//! Estimate the quantum bit error rate (QBER) before reconciliation.
+//!
+//! Both sides agree on a random sample of bit positions, derived from a shared seed.
+//! Alice discloses her bits at these positions, and Bob compares them with his own bits.
+//! The fraction of different bits is the estimated QBER, and Hoeffding's inequality gives a
+//! confidence interval around it:
+//!
+//! `P(|qber - estimated_qber| >= epsilon) <= 2 * exp(-2 * nr_sampled_bits * epsilon^2)`
+//!
+//! The disclosed bits are known to an eavesdropper, so both sides discard them from their keys.
+//!
+//! Positions agreed from a seed follow Floyd's sampling over the portable stream of
+//! [`shuffle_rng`](crate::shuffle_rng), so that they do not depend on the rand version:
+//!
+//! ```text
+//! sample of k of nr_bits:       sorted bit numbers
+//!     set = {}
+//!     for j from nr_bits - k to nr_bits - 1:
+//!         t = below(j + 1)
+//!         insert j if t is in set, else t
+//! ```
+
+use std::collections::HashSet;
+
+use rand::{seq::index, CryptoRng, RngCore};
+
+use crate::{
+    error::{Error, Result},
+    key::Key,
+    shuffle_rng::ShuffleRng,
+};
+
+/// Result of a QBER estimation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QberEstimate {
+    pub nr_sampled_bits: u32,
+    pub nr_bit_errors: u32,
+    pub qber: f32,
+    /// The QBER is in `[lower_bound, upper_bound]` with probability `confidence`.
+    pub lower_bound: f32,
+    pub upper_bound: f32,
+    pub confidence: f32,
+}
+
+pub struct QberEstimator {
+    nr_sampled_bits: u32,
+    confidence: f32,
+}
+
+impl QberEstimator {
+    /// # Arguments
+    /// nr_sampled_bits: number of bits to disclose
+    /// confidence: confidence level of the interval, e.g. 0.99
+    pub fn new(nr_sampled_bits: u32, confidence: f32) -> Self {
+        assert!(nr_sampled_bits > 0);
+        assert!(confidence > 0.0 && confidence < 1.0);
+        Self {
+            nr_sampled_bits,
+            confidence,
+        }
+    }
+
+    pub fn get_nr_sampled_bits(&self) -> u32 {
+        self.nr_sampled_bits
+    }
+
+    /// Pick the positions of the bits to disclose.
+    ///
+    /// Both sides call this with the same seed and key size, and get the same sorted positions,
+    /// see the module documentation for the specification.
+    pub fn sample_bit_nrs(&self, nr_bits: u32, seed: u64) -> Vec<u32> {
+        assert!(self.nr_sampled_bits < nr_bits);
+        let mut rng = ShuffleRng::from_seed(seed);
+        let mut sampled = HashSet::with_capacity(self.nr_sampled_bits as usize);
+        for j in nr_bits - self.nr_sampled_bits..nr_bits {
+            let t = rng.below(j + 1);
+            if !sampled.insert(t) {
+                sampled.insert(j);
+            }
+        }
+        let mut bit_nrs: Vec<u32> = sampled.into_iter().collect();
+        bit_nrs.sort_unstable();
+        bit_nrs
+    }
+
+    /// Pick the positions of the bits to disclose with the given generator.
+    ///
+    /// An eavesdropper must not predict the positions, so the generator must be
+    /// cryptographically secure.
+    pub fn sample_bit_nrs_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        &self,
+        nr_bits: u32,
+        rng: &mut R,
+    ) -> Vec<u32> {
+        assert!(self.nr_sampled_bits < nr_bits);
+        let mut bit_nrs: Vec<u32> =
+            index::sample(rng, nr_bits as usize, self.nr_sampled_bits as usize)
+                .into_iter()
+                .map(|bit_nr| bit_nr as u32)
+                .collect();
+        bit_nrs.sort_unstable();
+        bit_nrs
+    }
+
+    /// Compare the local bits at `bit_nrs` with the bits disclosed by the peer.
+    ///
+    /// # Errors
+    ///
+    /// [`Error::MalformedMessage`] if the peer bits are not one bit of 0 or 1 per position, or
+    /// there are none, [`Error::BitOutOfRange`] if a position is not in the key
+    pub fn estimate(&self, key: &Key, bit_nrs: &[u32], peer_bits: &[u8]) -> Result<QberEstimate> {
+        if peer_bits.len() != bit_nrs.len() {
+            return Err(Error::MalformedMessage(
+                "number of estimation bits differs from the request",
+            ));
+        }
+        if bit_nrs.is_empty() {
+            return Err(Error::MalformedMessage("no estimation bits"));
+        }
+        if peer_bits.iter().any(|&bit| bit > 1) {
+            return Err(Error::MalformedMessage("estimation bit neither 0 nor 1"));
+        }
+        let mut nr_bit_errors = 0;
+        for (&bit_nr, &peer_bit) in bit_nrs.iter().zip(peer_bits) {
+            if key.try_get_bit(bit_nr)? != peer_bit {
+                nr_bit_errors += 1;
+            }
+        }
+        let nr_sampled_bits = bit_nrs.len() as u32;
+
+        let qber = nr_bit_errors as f32 / nr_sampled_bits as f32;
+        let epsilon = hoeffding_epsilon(nr_sampled_bits, self.confidence);
+        Ok(QberEstimate {
+            nr_sampled_bits,
+            nr_bit_errors,
+            qber,
+            lower_bound: (qber - epsilon).max(0.0),
+            upper_bound: (qber + epsilon).min(1.0),
+            confidence: self.confidence,
+        })
+    }
+
+    /// Run the whole estimation between the two keys of a simulation.
+    ///
+    /// The sampled bits are discarded from both keys, and the estimated QBER is set on both keys,
+    /// so that the reconciliation can start with the remaining bits.
+    ///
+    /// # Errors
+    ///
+    /// [`Error::BitOutOfRange`] if the correct key is shorter than the noise key
+    pub fn estimate_and_discard(
+        &self,
+        noise_key: &mut Key,
+        correct_key: &mut Key,
+        seed: u64,
+    ) -> Result<QberEstimate> {
+        let bit_nrs = self.sample_bit_nrs(noise_key.get_nr_bits(), seed);
+        // Alice side: disclose the sampled bits
+        let peer_bits = bit_nrs
+            .iter()
+            .map(|&bit_nr| correct_key.try_get_bit(bit_nr))
+            .collect::<Result<Vec<u8>>>()?;
+        // Bob side: compare
+        let estimate = self.estimate(noise_key, &bit_nrs, &peer_bits)?;
+
+        for key in [noise_key, correct_key] {
+            key.discard_bits(&bit_nrs);
+            key.set_estimated_ber(estimate.qber);
+        }
+        Ok(estimate)
+    }
+}
+
+/// The bits a side sends to its peer for the QBER estimation.
+///
+/// # Panics
+///
+/// Panics if a bit is out of range, check the positions asked by a peer first
+pub fn disclose_bits(key: &Key, bit_nrs: &[u32]) -> Vec<u8> {
+    bit_nrs.iter().map(|&bit_nr| key.get_bit(bit_nr)).collect()
+}
+
+/// Half width of the two-sided Hoeffding confidence interval.
+fn hoeffding_epsilon(nr_sampled_bits: u32, confidence: f32) -> f32 {
+    let delta = 1.0 - f64::from(confidence);
+    ((2.0 / delta).ln() / (2.0 * f64::from(nr_sampled_bits))).sqrt() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::Error, key::Key, random::set_random_seed};
+
+    use super::{hoeffding_epsilon, QberEstimator};
+
+    #[test]
+    fn test_sample_bit_nrs() {
+        let estimator = QberEstimator::new(100, 0.99);
+        let bit_nrs = estimator.sample_bit_nrs(1000, 1234);
+        assert_eq!(100, bit_nrs.len());
+        assert!(bit_nrs.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(bit_nrs.iter().all(|&bit_nr| bit_nr < 1000));
+        // both sides agree on the positions
+        assert_eq!(bit_nrs, estimator.sample_bit_nrs(1000, 1234));
+        assert_ne!(bit_nrs, estimator.sample_bit_nrs(1000, 4321));
+        // pinned by the specification, independent of the rand version
+        assert_eq!(
+            vec![27, 49, 54, 79, 87],
+            QberEstimator::new(5, 0.99).sample_bit_nrs(100, 1234)
+        );
+    }
+
+    #[test]
+    fn test_hoeffding_epsilon() {
+        // ln(2 / 0.05) / (2 * 1000) = 0.0018444, sqrt = 0.042947
+        assert!((hoeffding_epsilon(1000, 0.95) - 0.042947).abs() < 1e-5);
+        // more samples, tighter interval
+        assert!(hoeffding_epsilon(10000, 0.95) < hoeffding_epsilon(1000, 0.95));
+    }
+
+    #[test]
+    fn test_estimate_and_discard() {
+        const NR_SAMPLED_BITS: u32 = 2000;
+        set_random_seed(3333);
+        let key_str =
+            "100100011001000110010100011001000101000110010001010001100100011100010001".repeat(200);
+        let mut correct_key = Key::from(key_str.as_str());
+        let mut noise_key = correct_key.clone();
+        noise_key.set_estimated_ber(0.1);
+        noise_key.apply_noise();
+        let initial_bit_err = correct_key.nr_bits_different(&noise_key);
+
+        let estimator = QberEstimator::new(NR_SAMPLED_BITS, 0.99);
+        let estimate = estimator
+            .estimate_and_discard(&mut noise_key, &mut correct_key, 5555)
+            .unwrap();
+
+        assert_eq!(NR_SAMPLED_BITS, estimate.nr_sampled_bits);
+        assert!(estimate.lower_bound <= 0.1 && 0.1 <= estimate.upper_bound);
+        assert!(estimate.lower_bound <= estimate.qber && estimate.qber <= estimate.upper_bound);
+        // the disclosed bits are gone from both keys
+        assert_eq!(14400 - NR_SAMPLED_BITS, correct_key.get_nr_bits());
+        assert_eq!(14400 - NR_SAMPLED_BITS, noise_key.get_nr_bits());
+        assert_eq!(
+            initial_bit_err - estimate.nr_bit_errors,
+            correct_key.nr_bits_different(&noise_key)
+        );
+        assert_eq!(estimate.qber, noise_key.get_estimated_ber());
+        assert_eq!(estimate.qber, correct_key.get_estimated_ber());
+    }
+
+    #[test]
+    fn test_invalid_peer_bits() {
+        let estimator = QberEstimator::new(2, 0.99);
+        let key = Key::from("0110");
+        assert_eq!(
+            Ok(1),
+            estimator
+                .estimate(&key, &[1, 2], &[1, 0])
+                .map(|estimate| estimate.nr_bit_errors)
+        );
+        for (bit_nrs, peer_bits, error) in [
+            (
+                &[1, 2][..],
+                &[1][..],
+                Error::MalformedMessage("number of estimation bits differs from the request"),
+            ),
+            (&[], &[], Error::MalformedMessage("no estimation bits")),
+            (
+                &[1, 2],
+                &[1, 2],
+                Error::MalformedMessage("estimation bit neither 0 nor 1"),
+            ),
+            (
+                &[1, 4],
+                &[1, 1],
+                Error::BitOutOfRange {
+                    bit_nr: 4,
+                    nr_bits: 4,
+                },
+            ),
+        ] {
+            assert_eq!(Err(error), estimator.estimate(&key, bit_nrs, peer_bits));
+        }
+    }
+}
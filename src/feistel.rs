@@ -0,0 +1,128 @@
+use crate::shuffle_rng::ShuffleRng;
+
+const NR_ROUNDS: usize = 8;
+
+/// Keyed permutation of `0..nr_bits`, computed on the fly instead of stored in a table.
+///
+/// A balanced Feistel network permutes the smallest domain of an even number of bits
+/// that holds `nr_bits` values. Values outside of `0..nr_bits` are encrypted again
+/// (cycle walking) until they fall inside, which keeps the permutation a bijection
+/// on `0..nr_bits`. The domain is at most 4 times larger than `nr_bits`,
+/// so a lookup takes less than 4 walks on average.
+#[derive(Debug, Clone)]
+pub struct FeistelPermutation {
+    nr_bits: u32,
+    half_width: u32,
+    half_mask: u64,
+    round_keys: [u64; NR_ROUNDS],
+}
+
+impl FeistelPermutation {
+    pub fn new(nr_bits: u32, seed: u64) -> Self {
+        // number of bits of the largest value, 0..=32
+        let width = u32::BITS - nr_bits.saturating_sub(1).leading_zeros();
+        let half_width = width.div_ceil(2).max(1);
+        let mut rng = ShuffleRng::from_seed(seed);
+        Self {
+            nr_bits,
+            half_width,
+            half_mask: (1 << half_width) - 1,
+            round_keys: std::array::from_fn(|_| rng.next_u64()),
+        }
+    }
+
+    pub fn get_nr_bits(&self) -> u32 {
+        self.nr_bits
+    }
+
+    pub fn permute(&self, value: u32) -> u32 {
+        assert!(value < self.nr_bits);
+        let mut value = value as u64;
+        loop {
+            value = self.encrypt(value);
+            if value < self.nr_bits as u64 {
+                return value as u32;
+            }
+        }
+    }
+
+    pub fn invert(&self, value: u32) -> u32 {
+        assert!(value < self.nr_bits);
+        let mut value = value as u64;
+        loop {
+            value = self.decrypt(value);
+            if value < self.nr_bits as u64 {
+                return value as u32;
+            }
+        }
+    }
+
+    fn encrypt(&self, value: u64) -> u64 {
+        let mut left = value >> self.half_width;
+        let mut right = value & self.half_mask;
+        for round_key in self.round_keys {
+            (left, right) = (right, left ^ self.round(round_key, right));
+        }
+        (left << self.half_width) | right
+    }
+
+    fn decrypt(&self, value: u64) -> u64 {
+        let mut left = value >> self.half_width;
+        let mut right = value & self.half_mask;
+        for round_key in self.round_keys.into_iter().rev() {
+            (left, right) = (right ^ self.round(round_key, left), left);
+        }
+        (left << self.half_width) | right
+    }
+
+    // splitmix64 finalizer of the keyed half block
+    fn round(&self, round_key: u64, half: u64) -> u64 {
+        let mut z = half ^ round_key;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        (z ^ (z >> 31)) & self.half_mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FeistelPermutation;
+
+    #[test]
+    fn test_bijection() {
+        for nr_bits in [1, 2, 3, 5, 64, 100, 1000, 4097] {
+            let permutation = FeistelPermutation::new(nr_bits, 123456789);
+            let mut seen = vec![false; nr_bits as usize];
+            for value in 0..nr_bits {
+                let permuted = permutation.permute(value);
+                assert!(!seen[permuted as usize]);
+                seen[permuted as usize] = true;
+                assert_eq!(value, permutation.invert(permuted));
+            }
+        }
+    }
+
+    #[test]
+    fn test_seed() {
+        let permutation = FeistelPermutation::new(1000, 1);
+        let same_permutation = FeistelPermutation::new(1000, 1);
+        let other_permutation = FeistelPermutation::new(1000, 2);
+        let permuted: Vec<u32> = (0..1000).map(|v| permutation.permute(v)).collect();
+        let same_permuted: Vec<u32> = (0..1000).map(|v| same_permutation.permute(v)).collect();
+        let other_permuted: Vec<u32> = (0..1000).map(|v| other_permutation.permute(v)).collect();
+        assert_eq!(permuted, same_permuted);
+        assert_ne!(permuted, other_permuted);
+        // not the identity
+        assert_ne!(permuted, (0..1000).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_large_domain() {
+        let permutation = FeistelPermutation::new(u32::MAX, 42);
+        for value in [0, 1, 1 << 20, u32::MAX - 1] {
+            let permuted = permutation.permute(value);
+            assert!(permuted < u32::MAX);
+            assert_eq!(value, permutation.invert(permuted));
+        }
+    }
+}
@@ -1,202 +1,473 @@
-use std::rc::Rc;
+use tracing::{debug, debug_span};
 
 use crate::{
-    algorithm::{Algorithm, OriginalAlgorithm},
-    block::{Block, BlockRef, BlockType, SubBlockType},
+    algorithm::Algorithm,
+    block::{BlockId, BlockTree, BlockType},
+    dot,
+    error::Result,
     key::Key,
-    shuffle::{self, SharedShuffle, Shuffle},
-    shuffled_key::{SharedKey, ShuffledKey},
+    observer::ReconciliationObserver,
+    partition::Partitioner,
+    protocol::{ParityChannel, ParityRequest, ParityRequestEncoding, WireStats},
+    shuffle::{SharedShuffle, Shuffle},
+    shuffled_key::ShuffledKey,
 };
 
+/// One iteration of Cascade: the shuffled copy of the noise key and its block tree.
+///
+/// The noise key is owned by the caller and passed to the methods that read or correct bits.
+/// The correct parities are asked through a [`ParityChannel`], or, to ask them over messages,
+/// with the requests of [`Iteration::parity_requests`] and the replies given to
+/// [`Iteration::set_asked_parities`].
 pub struct Iteration<T: Algorithm> {
     iteration_nr: u32,
-    top_blocks: Vec<Rc<Block>>,
-    nr_key_bits: u32,
+    blocks: BlockTree,
+    // size of most top blocks, see Partitioner
+    block_size: u32,
     algo: T,
     shuffled_key: ShuffledKey,
 }
 
+/// One step of the binary search for an error: a block split into sub blocks, whose correct
+/// parities are asked, except the one inferred from its siblings.
+pub struct SearchStep {
+    sub_blocks: Vec<BlockId>,
+    inferred_index: usize,
+    blocks_to_ask: Vec<BlockId>,
+}
+
+impl SearchStep {
+    /// The sub blocks whose correct parity must be asked, before [`Iteration::finish_search_step`].
+    pub fn get_blocks_to_ask(&self) -> &[BlockId] {
+        &self.blocks_to_ask
+    }
+}
+
 impl<T: Algorithm> Iteration<T> {
-    pub fn new(iteration_nr: u32, correct_key: Rc<Key>, noise_key: SharedKey, algo: T) -> Self {
+    /// # Arguments
+    /// shuffle_seed: seed of the shuffle of this iteration, see [`SeedSchedule`](crate::seed::SeedSchedule)
+    ///
+    /// # Errors
+    ///
+    /// [`Error::InvalidIterationNr`](crate::error::Error::InvalidIterationNr) if `iteration_nr` is 0
+    pub fn new(iteration_nr: u32, shuffle_seed: u64, noise_key: &Key, algo: T) -> Result<Self> {
         // create shuffled key for this iteration
-        // for testing purposes, we use a fixed seed
-        const SEED: u64 = 0x1234567890ABCDEF;
-
-        let shuffle = Shuffle::new_shuffle_from_seed(
-            iteration_nr,
-            noise_key.borrow().get_nr_bits(),
-            SEED,
-            true,
-        );
-        let shuffled_key = ShuffledKey::new(correct_key, noise_key, shuffle);
+        let nr_bits = noise_key.get_nr_bits();
+        let backend = algo.config().shuffle_backend();
+        let shuffle = match algo.config().shuffle_cache() {
+            Some(cache) => {
+                cache.get_or_create_from_seed(iteration_nr, nr_bits, shuffle_seed, backend)?
+            }
+            None => Shuffle::new_shuffle_from_seed_with_backend(
+                iteration_nr,
+                nr_bits,
+                shuffle_seed,
+                backend,
+                false,
+            )?,
+        };
+        Ok(Self::with_shuffle(iteration_nr, shuffle, noise_key, algo))
+    }
 
+    /// Iteration using the given shuffle, e.g. a shuffle recorded from a peer.
+    pub fn with_shuffle(
+        iteration_nr: u32,
+        shuffle: SharedShuffle,
+        noise_key: &Key,
+        algo: T,
+    ) -> Self {
+        let encoding = if algo.config().ask_correct_parity_using_shuffle_seed() {
+            ParityRequestEncoding::ShuffleSeed
+        } else {
+            ParityRequestEncoding::BitList
+        };
+        let shuffled_key = ShuffledKey::with_encoding(noise_key, shuffle, encoding);
         let estimated_ber = shuffled_key.get_estimated_ber();
         let nr_key_bits = shuffled_key.get_nr_bits();
         // create top blocks for this iteration
         let block_size = <T as Algorithm>::block_size(iteration_nr, estimated_ber, nr_key_bits);
-        println!("block size: {}", block_size);
-        let mut start_bit_nr = 0;
-        let mut top_blocks = Vec::new();
-
-        while start_bit_nr < nr_key_bits {
-            let end_bit_nr = std::cmp::min(start_bit_nr + block_size, nr_key_bits) - 1;
-            let block = Block::new(
-                BlockType::TopLevel,
-                start_bit_nr,
-                end_bit_nr,
-                shuffled_key.clone(),
-            );
-            top_blocks.push(block);
-            start_bit_nr += block_size;
+        let mut blocks = BlockTree::new();
+        let top_blocks = algo.config().partitioner().partition(
+            nr_key_bits,
+            block_size,
+            shuffled_key.get_shuffle().get_seed(),
+        );
+        for (start_bit_nr, end_bit_nr) in top_blocks {
+            blocks.add_top_block(start_bit_nr, end_bit_nr);
         }
+        debug!(
+            iteration_nr,
+            block_size,
+            nr_top_blocks = blocks.get_top_blocks().len(),
+            "create top blocks"
+        );
 
         Self {
             iteration_nr,
-            top_blocks,
-            nr_key_bits,
+            blocks,
+            block_size,
             algo,
             shuffled_key,
         }
     }
 
-    pub fn schedule_top_block_ask_correct_parity_task(&self) {
-        println!(
-            "Iteration: {}, schedule top block ask correct parity task",
-            self.get_iteration_nr()
+    /// Start the iteration.
+    ///
+    /// # Returns
+    ///
+    /// the top blocks whose correct parity must be asked, see [`Iteration::parity_requests`]
+    pub fn start(&mut self, observer: &mut dyn ReconciliationObserver) -> Vec<BlockId> {
+        debug!(
+            iteration_nr = self.iteration_nr,
+            "ask correct parities of the top blocks"
         );
-        for block in self.top_blocks.iter() {
-            // spawn async tasks for concurrent asking
-            block.ask_correct_parity();
-        }
+        observer.iteration_started(self.iteration_nr);
+        let top_blocks: Vec<BlockId> = self.blocks.get_top_blocks().collect();
+        self.blocks.get_blocks_to_ask(&top_blocks)
     }
 
-    pub fn schedule_top_block_correct_task(&self) -> Vec<u32> {
-        println!(
-            "Iteration: {}, schedule top block correct task",
-            self.get_iteration_nr()
-        );
-        let mut corrected_bits = Vec::new();
-        for block in self.top_blocks.iter() {
-            if block.get_error_parity() {
-                println!(
-                    "schedule correct block: {:?}, range: {}..{}",
-                    block.get_block_type(),
-                    block.get_start_bit_nr(),
-                    block.get_end_bit_nr()
-                );
-                let orig_bit_nr = self.try_correct_block(block);
-                println!("corrected bit: {}", orig_bit_nr);
+    pub fn schedule_top_block_ask_correct_parity_task(
+        &mut self,
+        channel: &mut dyn ParityChannel,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> Result<()> {
+        let blocks = self.start(observer);
+        // all top blocks in one round trip
+        self.ask_correct_parities(&blocks, channel, observer)
+    }
 
-                corrected_bits.push(orig_bit_nr);
-            }
+    pub fn schedule_top_block_correct_task(
+        &mut self,
+        noise_key: &mut Key,
+        channel: &mut dyn ParityChannel,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> Result<Vec<u32>> {
+        debug!(iteration_nr = self.iteration_nr, "correct the top blocks");
+        let mut corrected_bit_nrs = Vec::new();
+        for block in self.blocks.get_top_blocks() {
+            corrected_bit_nrs
+                .extend(self.try_correct_top_block(noise_key, block, channel, observer)?);
         }
-        corrected_bits
+        Ok(corrected_bit_nrs)
+    }
+
+    /// Correct a bit in the top block if it has an odd number of errors.
+    ///
+    /// # Returns
+    ///
+    /// the corrected bit number in the noise key, if any
+    pub fn try_correct_top_block(
+        &mut self,
+        noise_key: &mut Key,
+        block: BlockId,
+        channel: &mut dyn ParityChannel,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> Result<Option<u32>> {
+        self.sync(noise_key);
+        if !self.has_odd_error_parity(block) {
+            return Ok(None);
+        }
+        self.try_correct_block(noise_key, block, channel, observer)
+            .map(Some)
     }
 
     // start with top block
-    pub fn try_correct_block(&self, block: &BlockRef) -> u32 {
-        let mut current_block = block.clone();
+    pub fn try_correct_block(
+        &mut self,
+        noise_key: &mut Key,
+        block: BlockId,
+        channel: &mut dyn ParityChannel,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> Result<u32> {
+        let _span = debug_span!(
+            "block",
+            iteration_nr = self.iteration_nr,
+            start_bit_nr = self.blocks.get_start_bit_nr(block),
+            end_bit_nr = self.blocks.get_end_bit_nr(block)
+        )
+        .entered();
+        let mut current_block = block;
+        while self.blocks.get_nr_bits(current_block) > 1 {
+            let step = self.start_search_step(noise_key, current_block);
+            let blocks = step.get_blocks_to_ask().to_vec();
+            self.ask_correct_parities(&blocks, channel, observer)?;
+            current_block = self.finish_search_step(step, observer);
+        }
+        // correct the bit
+        let orig_bit_nr = self.correct_bit(noise_key, current_block, observer);
+        debug!(orig_bit_nr, "corrected bit");
+        Ok(orig_bit_nr)
+    }
 
-        while current_block.get_nr_bits() > 1 {
-            let left_sub_block = current_block.create_sub_block(SubBlockType::Left);
-            let right_sub_block = current_block.create_sub_block(SubBlockType::Right);
-            left_sub_block.ask_correct_parity();
-            right_sub_block.try_to_infer_correct_parity();
+    /// Split a block with an odd number of errors, of more than one bit, into sub blocks.
+    ///
+    /// The sub blocks of an earlier pass are reused, with the parities known so far.
+    pub fn start_search_step(&mut self, noise_key: &Key, block: BlockId) -> SearchStep {
+        self.sync(noise_key);
+        let split_strategy = self.algo.split_strategy();
+        let sub_blocks: Vec<BlockId> = self.blocks.get_or_split(block, split_strategy).collect();
+        let ranges: Vec<(u32, u32)> = sub_blocks
+            .iter()
+            .map(|&block| {
+                (
+                    self.blocks.get_start_bit_nr(block),
+                    self.blocks.get_end_bit_nr(block),
+                )
+            })
+            .collect();
+        let inferred_index = split_strategy.inferred_sub_block(&ranges);
+        let asked_blocks: Vec<BlockId> = sub_blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &block)| (index != inferred_index).then_some(block))
+            .collect();
+        let blocks_to_ask = self.blocks.get_blocks_to_ask(&asked_blocks);
+        SearchStep {
+            sub_blocks,
+            inferred_index,
+            blocks_to_ask,
+        }
+    }
 
-            let error_parity = left_sub_block.get_error_parity();
+    /// Infer the last correct parity of the sub blocks, once the asked ones are set.
+    ///
+    /// # Returns
+    ///
+    /// the first sub block with an odd number of errors, to search next
+    pub fn finish_search_step(
+        &mut self,
+        step: SearchStep,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> BlockId {
+        let inferred_block = step.sub_blocks[step.inferred_index];
+        if self.blocks.try_to_infer_correct_parity(inferred_block) {
+            self.notify_parity_known(inferred_block, observer);
+        }
+        // there is one since the block has an odd number of errors
+        step.sub_blocks
+            .into_iter()
+            .find(|&block| self.has_odd_error_parity(block))
+            .expect("a sub block has an odd number of errors")
+    }
 
-            // if odd number of errors, recurse on left sub block
-            if error_parity {
-                current_block = left_sub_block;
-            }
-            // if even number of errors, we can infer right sub block
-            // and recurse on it
-            else {
-                right_sub_block.get_error_parity();
-                current_block = right_sub_block
-            }
+    /// Ask the correct parity of a block of this iteration, unless it is known.
+    pub fn ask_correct_parity(
+        &mut self,
+        block: BlockId,
+        channel: &mut dyn ParityChannel,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> Result<()> {
+        self.ask_correct_parities(&[block], channel, observer)
+    }
+
+    /// Ask the correct parities of the blocks whose parity is unknown, in one round trip.
+    ///
+    /// # Errors
+    ///
+    /// the error of the channel, or of [`BlockTree::set_asked_parities`]
+    pub fn ask_correct_parities(
+        &mut self,
+        blocks: &[BlockId],
+        channel: &mut dyn ParityChannel,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> Result<()> {
+        let blocks = self.blocks.get_blocks_to_ask(blocks);
+        if blocks.is_empty() {
+            return Ok(());
         }
-        // correct the bit
-        let shuffle_bit_nr = current_block.get_start_bit_nr();
-        current_block.correct_bit(shuffle_bit_nr);
+        let requests = self.parity_requests(&blocks);
+        let correct_parities = channel.ask_correct_parities(&requests)?;
+        self.set_asked_parities(&blocks, &correct_parities, observer)
+    }
 
-        self.flip_parity_upstream(&current_block);
-        return self.shuffled_key.shuffle_to_orig_bit_nr(shuffle_bit_nr);
+    /// The requests for the correct parities of the blocks, sent in one round trip.
+    pub fn parity_requests(&mut self, blocks: &[BlockId]) -> Vec<ParityRequest> {
+        self.blocks.parity_requests(blocks, &mut self.shuffled_key)
+    }
+
+    /// Set the correct parities replied to [`Iteration::parity_requests`], in order.
+    ///
+    /// # Errors
+    ///
+    /// see [`BlockTree::set_asked_parities`]
+    pub fn set_asked_parities(
+        &mut self,
+        blocks: &[BlockId],
+        correct_parities: &[u8],
+        observer: &mut dyn ReconciliationObserver,
+    ) -> Result<()> {
+        self.blocks.set_asked_parities(blocks, correct_parities)?;
+        for &block in blocks {
+            self.notify_parity_known(block, observer);
+        }
+        Ok(())
+    }
+
+    // the correct parity of the block was just asked or inferred
+    fn notify_parity_known(&self, block: BlockId, observer: &mut dyn ReconciliationObserver) {
+        let start_bit_nr = self.blocks.get_start_bit_nr(block);
+        let end_bit_nr = self.blocks.get_end_bit_nr(block);
+        let correct_parity = self
+            .blocks
+            .get_correct_parity(block)
+            .expect("the correct parity is known");
+        if self.blocks.was_inferred(block) {
+            observer.sub_block_parity_inferred(
+                self.iteration_nr,
+                start_bit_nr,
+                end_bit_nr,
+                correct_parity,
+            );
+        } else if self.blocks.get_block_type(block) == BlockType::TopLevel {
+            observer.top_block_parity_asked(
+                self.iteration_nr,
+                start_bit_nr,
+                end_bit_nr,
+                correct_parity,
+            );
+        } else {
+            observer.sub_block_parity_asked(
+                self.iteration_nr,
+                start_bit_nr,
+                end_bit_nr,
+                correct_parity,
+            );
+        }
+    }
+
+    /// See [`BlockTree::get_error_parity`], as of the last sync.
+    pub fn get_error_parity(&self, block: BlockId) -> Result<bool> {
+        self.blocks.get_error_parity(block, &self.shuffled_key)
+    }
+
+    /// Whether a block, whose correct parity is known, has an odd number of errors as of the last
+    /// sync.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the correct parity of the block is not known
+    pub(crate) fn has_odd_error_parity(&self, block: BlockId) -> bool {
+        self.get_error_parity(block)
+            .expect("the correct parity of the block is known")
+    }
+
+    /// Flip the bit of a single bit block in the noise key, the other iterations see it after
+    /// their next sync.
+    ///
+    /// # Returns
+    ///
+    /// the bit number in the noise key
+    pub fn correct_bit(
+        &mut self,
+        noise_key: &mut Key,
+        single_bit_block: BlockId,
+        observer: &mut dyn ReconciliationObserver,
+    ) -> u32 {
+        self.blocks.mark_corrected(single_bit_block);
+        let shuffle_bit_nr = self.blocks.get_start_bit_nr(single_bit_block);
+        self.shuffled_key.flip_bit(noise_key, shuffle_bit_nr);
+        let orig_bit_nr = self.shuffled_key.shuffle_to_orig_bit_nr(shuffle_bit_nr);
+        observer.bit_corrected(self.iteration_nr, orig_bit_nr);
+        orig_bit_nr
+    }
+
+    /// Apply the bits corrected in the noise key since the last sync, by any iteration.
+    pub fn sync(&mut self, noise_key: &Key) {
+        self.shuffled_key.sync(noise_key);
     }
 
     pub fn get_iteration_nr(&self) -> u32 {
         self.iteration_nr
     }
 
-    pub fn get_top_blocks(&self) -> &Vec<Rc<Block>> {
-        &self.top_blocks
+    pub fn get_top_blocks(&self) -> impl ExactSizeIterator<Item = BlockId> {
+        self.blocks.get_top_blocks()
     }
 
-    pub fn is_started(&self) -> bool {
-        self.top_blocks
-            .iter()
-            .all(|block| block.get_correct_parity().is_some())
+    /// The top block containing a shuffled bit, in O(1) for [`Partitioner::FixedSize`] and
+    /// by binary search for the other partitioners.
+    pub fn get_top_block_containing_bit(&self, shuffle_bit_nr: u32) -> BlockId {
+        if self.algo.config().partitioner() == Partitioner::FixedSize {
+            return self.blocks.get_top_block(shuffle_bit_nr / self.block_size);
+        }
+        self.blocks
+            .get_top_block_containing_bit(shuffle_bit_nr)
+            .expect("the top blocks cover the key")
     }
 
-    pub fn flip_parity_upstream(&self, leaf_block: &BlockRef) {
-        // current block
-        leaf_block.flip_current_parity();
+    /// The top block containing a bit of the noise key.
+    pub fn get_top_block_containing_orig_bit(&self, orig_bit_nr: u32) -> BlockId {
+        self.get_top_block_containing_bit(self.shuffled_key.orig_to_shuffle_bit_nr(orig_bit_nr))
+    }
 
-        // traverse up to top block
-        let mut parent_block = leaf_block.get_parent_block();
-        while let Some(block) = parent_block {
-            block.flip_current_parity();
-            parent_block = block.get_parent_block();
+    /// The blocks containing a bit of the noise key, from the top block down to the smallest
+    /// sub block created so far.
+    pub fn get_blocks_containing_orig_bit(&self, orig_bit_nr: u32) -> Vec<BlockId> {
+        let bit_nr = self.shuffled_key.orig_to_shuffle_bit_nr(orig_bit_nr);
+        let mut block = self.get_top_block_containing_bit(bit_nr);
+        let mut blocks = vec![block];
+        while let Some(sub_block) = self
+            .blocks
+            .get_sub_blocks(block)
+            .find(|&sub_block| self.blocks.contains_bit(sub_block, bit_nr))
+        {
+            block = sub_block;
+            blocks.push(block);
         }
+        blocks
     }
 
-    /// Start with the top block that contains this bit
-    pub fn flip_parity_downstream(&self, top_block: &BlockRef, bit_nr: u32) {
-        println!("flip parity downstream, bit nr: {}", bit_nr);
-        // current top block
-        top_block.flip_current_parity();
-        // traverse down all blocks containing this bit
-        // because it is binary tree, either left or right sub block will contain this bit
-        let mut block = top_block.clone();
-        while block.has_sub_blocks() {
-            // start with left
-            // note that we always create both left and right sub blocks, so
-            // if any of them is None, we can break
-            let left_block = block.get_left_sub_block().unwrap();
-            if left_block.contains_bit(bit_nr) {
-                left_block.flip_current_parity();
-                block = left_block;
-                continue;
-            }
-            // then right
-            let right_block = block.get_right_sub_block().unwrap();
-            assert!(right_block.contains_bit(bit_nr));
-            right_block.flip_current_parity();
-            block = right_block;
-        }
+    pub fn get_blocks(&self) -> &BlockTree {
+        &self.blocks
+    }
+
+    pub fn get_blocks_mut(&mut self) -> &mut BlockTree {
+        &mut self.blocks
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.blocks
+            .get_top_blocks()
+            .all(|block| self.blocks.get_correct_parity(block).is_some())
     }
 
     pub fn get_shuffled_key(&self) -> &ShuffledKey {
         &self.shuffled_key
     }
+
+    pub fn get_wire_stats(&self) -> WireStats {
+        self.shuffled_key.get_wire_stats()
+    }
+
+    /// Write the block tree of this iteration into a DOT graph, see [`dot`].
+    pub fn write_dot(&self, out: &mut impl std::fmt::Write) -> std::fmt::Result {
+        dot::write_iteration(out, self.iteration_nr, &self.blocks, &self.shuffled_key)
+    }
+
+    /// DOT graph of the block tree of this iteration, as of the last sync.
+    pub fn to_dot(&self) -> String {
+        dot::graph(|out| self.write_dot(out))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{cell::RefCell, rc::Rc};
+    use std::sync::Arc;
 
     use crate::{
         algorithm::OriginalAlgorithm,
-        block::{Block, BlockType},
         key::Key,
-        shuffle::Shuffle,
-        shuffled_key::{SharedKey, ShuffledKey},
+        observer::NoObserver,
+        partition::Partitioner,
+        protocol::SimulatedParityChannel,
+        seed::SeedSchedule,
+        shuffle::{ShuffleBackend, ShuffleCache, ShuffleIndex},
+        split::SplitStrategy,
     };
 
     use super::Iteration;
 
-    fn create_test_shuffled_key() -> (Rc<Key>, SharedKey) {
-        const SEED: u64 = 0x1234567890ABCDEF;
+    fn create_test_shuffled_key() -> (Arc<Key>, Key) {
         const KEY_STR: &str = "10010001100100011001000110010001";
         assert_eq!(KEY_STR.len(), 32);
         // correct key
@@ -206,35 +477,197 @@ mod tests {
         noise_key.set_estimated_ber(0.1); // 10% BER, about 3 errors
         noise_key.apply_noise();
 
-        assert_ne!(correct_key.to_string(), noise_key.to_string());
-        (Rc::new(correct_key), Rc::new(RefCell::new(noise_key)))
-    }
-
-    fn print_keys(shuffled_key: &ShuffledKey) {
-        println!(
-            "correct key: {}",
-            shuffled_key.get_correct_key().to_string()
+        assert_ne!(
+            correct_key.reveal().to_string(),
+            noise_key.reveal().to_string()
         );
-        println!("noise key:   {}", shuffled_key.get_noise_key().to_string());
+        (Arc::new(correct_key), noise_key)
     }
 
     #[test]
     fn test_correct_block() {
         const ITERATION_NR: u32 = 2;
-        let (correct_key, noise_key) = create_test_shuffled_key();
-        let iteration = Iteration::new(
+        let (correct_key, mut noise_key) = create_test_shuffled_key();
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let mut iteration = Iteration::new(
             ITERATION_NR,
-            correct_key,
-            noise_key,
+            seed_schedule.shuffle_seed(ITERATION_NR),
+            &noise_key,
             OriginalAlgorithm::default(),
-        );
-        print_keys(&iteration.get_shuffled_key());
+        )
+        .unwrap();
+        assert_ne!(0, iteration.get_top_blocks().len());
+        let nr_bit_errors = correct_key.nr_bits_different(&noise_key);
 
-        println!("top blocks count: {}", iteration.get_top_blocks().len());
+        let mut channel = SimulatedParityChannel::new(correct_key.clone());
 
-        iteration.schedule_top_block_ask_correct_parity_task();
-        iteration.schedule_top_block_correct_task();
+        iteration
+            .schedule_top_block_ask_correct_parity_task(&mut channel, &mut NoObserver)
+            .unwrap();
+        let corrected_bit_nrs = iteration
+            .schedule_top_block_correct_task(&mut noise_key, &mut channel, &mut NoObserver)
+            .unwrap();
         // should correct one bit
-        print_keys(&iteration.get_shuffled_key());
+        assert!(!corrected_bit_nrs.is_empty());
+        assert_eq!(
+            nr_bit_errors - corrected_bit_nrs.len() as u32,
+            correct_key.nr_bits_different(&noise_key)
+        );
+    }
+
+    #[test]
+    fn test_top_block_containing_bit() {
+        let (_, noise_key) = create_test_shuffled_key();
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        // the block size doubles in every iteration
+        for (iteration_nr, partitioner) in [1, 2, 3].into_iter().flat_map(|iteration_nr| {
+            [
+                Partitioner::FixedSize,
+                Partitioner::EqualSize,
+                Partitioner::RandomOffset,
+            ]
+            .map(|partitioner| (iteration_nr, partitioner))
+        }) {
+            let iteration = Iteration::new(
+                iteration_nr,
+                seed_schedule.shuffle_seed(iteration_nr),
+                &noise_key,
+                OriginalAlgorithm::default().with_partitioner(partitioner),
+            )
+            .unwrap();
+            let blocks = iteration.get_blocks();
+            for bit_nr in 0..32 {
+                let top_block = iteration.get_top_block_containing_bit(bit_nr);
+                assert!(blocks.contains_bit(top_block, bit_nr));
+                let orig_bit_nr = iteration.get_shuffled_key().shuffle_to_orig_bit_nr(bit_nr);
+                assert_eq!(
+                    top_block,
+                    iteration.get_top_block_containing_orig_bit(orig_bit_nr)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_cache_shuffles_config() {
+        let (_, noise_key) = create_test_shuffled_key();
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let index = ShuffleIndex::new(2, 32, seed_schedule.shuffle_seed(2));
+        let cache = Arc::new(ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY));
+        let no_cache = OriginalAlgorithm::new("no cache", 4, 0)
+            .with_shuffle_cache(cache.clone())
+            .with_cache_shuffles(false);
+        let _ = Iteration::new(2, seed_schedule.shuffle_seed(2), &noise_key, no_cache).unwrap();
+        assert!(!cache.contains(&index));
+
+        let _ = Iteration::new(
+            2,
+            seed_schedule.shuffle_seed(2),
+            &noise_key,
+            OriginalAlgorithm::default().with_shuffle_cache(cache.clone()),
+        )
+        .unwrap();
+        assert!(cache.contains(&index));
+    }
+
+    #[test]
+    fn test_shuffle_backend_config() {
+        const ITERATION_NR: u32 = 2;
+        let (correct_key, mut noise_key) = create_test_shuffled_key();
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let mut iteration = Iteration::new(
+            ITERATION_NR,
+            seed_schedule.shuffle_seed(ITERATION_NR),
+            &noise_key,
+            OriginalAlgorithm::default().with_shuffle_backend(ShuffleBackend::Feistel),
+        )
+        .unwrap();
+        let shuffle = iteration.get_shuffled_key().get_shuffle();
+        assert_eq!(ShuffleBackend::Feistel, shuffle.get_backend());
+        assert_eq!(0, shuffle.heap_size());
+
+        let mut channel = SimulatedParityChannel::new(correct_key);
+
+        iteration
+            .schedule_top_block_ask_correct_parity_task(&mut channel, &mut NoObserver)
+            .unwrap();
+        iteration
+            .schedule_top_block_correct_task(&mut noise_key, &mut channel, &mut NoObserver)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parity_request_encoding_config() {
+        const ITERATION_NR: u32 = 2;
+        let (correct_key, noise_key) = create_test_shuffled_key();
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let bit_list = OriginalAlgorithm::new("bit list", 4, 0)
+            .with_ask_correct_parity_using_shuffle_seed(false);
+        let mut wire_stats = Vec::new();
+        for algo in [bit_list, OriginalAlgorithm::default()] {
+            let mut iteration = Iteration::new(
+                ITERATION_NR,
+                seed_schedule.shuffle_seed(ITERATION_NR),
+                &noise_key,
+                algo,
+            )
+            .unwrap();
+            let mut channel = SimulatedParityChannel::new(correct_key.clone());
+            iteration
+                .schedule_top_block_ask_correct_parity_task(&mut channel, &mut NoObserver)
+                .unwrap();
+            wire_stats.push(iteration.get_wire_stats());
+        }
+        // 32 bits in top blocks of 16 bits
+        assert_eq!(2, wire_stats[0].nr_requests);
+        assert_eq!(2 * (5 + 4 * 16), wire_stats[0].request_bytes);
+        assert_eq!(2, wire_stats[1].nr_requests);
+        assert_eq!(2 * 21, wire_stats[1].request_bytes);
+    }
+
+    #[test]
+    fn test_split_strategies() {
+        const ITERATION_NR: u32 = 2;
+        const ERROR_ORIG_BIT_NR: u32 = 5;
+        let (correct_key, _) = create_test_shuffled_key();
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        for split_strategy in [
+            SplitStrategy::Bisect,
+            SplitStrategy::AskSmaller,
+            SplitStrategy::PowerOfTwo,
+            SplitStrategy::KAry(4),
+        ] {
+            let mut noise_key = (*correct_key).clone();
+            noise_key.flip_bit(ERROR_ORIG_BIT_NR);
+            let mut iteration = Iteration::new(
+                ITERATION_NR,
+                seed_schedule.shuffle_seed(ITERATION_NR),
+                &noise_key,
+                OriginalAlgorithm::default().with_split_strategy(split_strategy),
+            )
+            .unwrap();
+            let mut channel = SimulatedParityChannel::new(correct_key.clone());
+            iteration
+                .schedule_top_block_ask_correct_parity_task(&mut channel, &mut NoObserver)
+                .unwrap();
+            assert_eq!(
+                vec![ERROR_ORIG_BIT_NR],
+                iteration
+                    .schedule_top_block_correct_task(&mut noise_key, &mut channel, &mut NoObserver)
+                    .unwrap()
+            );
+            assert_eq!(0, correct_key.nr_bits_different(&noise_key));
+
+            let wire_stats = iteration.get_wire_stats();
+            if split_strategy == SplitStrategy::KAry(4) {
+                // 2 top blocks of 16 bits, 4 and 1 bits: 3 sub blocks asked per round trip
+                assert_eq!(2 + 3 + 3, wire_stats.nr_requests);
+                assert_eq!(2 + 2, wire_stats.nr_round_trips);
+            } else {
+                // 16 bits, 4 levels: 1 sub block asked per round trip
+                assert_eq!(2 + 4, wire_stats.nr_requests);
+                assert_eq!(wire_stats.nr_requests, wire_stats.nr_round_trips);
+            }
+        }
     }
 }
@@ -1,42 +1,13 @@
-use crate::random::{random_bit_nr, random_uint32};
-use std::{collections::HashSet, fmt};
-
-/// Calculate the parity of a sigle word.
-///
-/// This only need 8 XOR and 8 lookups operations.
-///
-/// a naive approach of counting 1s in the word would require iterating
-/// through all 64 bits, checking if each bit is set to 1,
-/// and incrementing a counter if it is.
-/// This would involve more operations and conditional statements,
-/// making it less efficient than the precomputed table approach.
-///
-///
-fn word_parity(word: u64) -> u8 {
-    // precomputed parity values for all possible 256 byte values (from 0 to 255).
-    static BYTE_PARITY: [u8; 256] = [
-        0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0,
-        0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1,
-        0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0,
-        0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0,
-        0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0,
-        0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1,
-        0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0,
-        0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1,
-        0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0,
-    ];
-
-    let mut parity = 0;
-    parity ^= BYTE_PARITY[(word & 0xff) as usize];
-    parity ^= BYTE_PARITY[((word >> 8) & 0xff) as usize];
-    parity ^= BYTE_PARITY[((word >> 16) & 0xff) as usize];
-    parity ^= BYTE_PARITY[((word >> 24) & 0xff) as usize];
-    parity ^= BYTE_PARITY[((word >> 32) & 0xff) as usize];
-    parity ^= BYTE_PARITY[((word >> 40) & 0xff) as usize];
-    parity ^= BYTE_PARITY[((word >> 48) & 0xff) as usize];
-    parity ^= BYTE_PARITY[((word >> 56) & 0xff) as usize];
-    parity
-}
+use crate::{
+    error::{Error, Result},
+    parity::{word_parity, xor_fold, ParityIndex},
+    random::{self, random_bit_nr},
+    shuffle::Shuffle,
+};
+use rand::Rng;
+use std::{collections::HashSet, fmt, str::FromStr};
+use tracing::debug;
+use zeroize::Zeroize;
 
 /// Key is per thread data structure
 /// Key bits are stored into 64-bit int "words" as follows:
@@ -50,18 +21,46 @@ fn word_parity(word: u64) -> u8 {
 ///
 /// Note: we use the term "word" instead of te more natural term "block" to avoid confusion with
 ///       cascade blocks.
-#[derive(Clone, Debug)]
+///
+/// Key bits are secret: the words are zeroized when the key is dropped, `Debug` only shows
+/// the key size, and printing the bits requires an explicit call to [`Key::reveal`].
 pub struct Key {
     nr_bits: u32,
     nr_words: u32,
     words: Vec<u64>,
     estimated_ber: f32,
+    // bits changed since the last compaction, in order of change,
+    // so that shuffled copies of the key can catch up with the changes
+    changes: Vec<u32>,
+    // changes dropped by compact_changes, the changes are numbered from the creation of the key
+    nr_compacted_changes: usize,
+    // false for shuffled copies, whose changes nobody reads
+    record_changes: bool,
+    // optional index for O(log n) range parities
+    parity_index: Option<ParityIndex>,
 }
 
-impl From<&str> for Key {
-    fn from(value: &str) -> Self {
-        assert!(!value.chars().any(|c| c != '0' && c != '1'));
-        let nr_bits_param = u32::try_from(value.len()).expect("should be able to convert to u32");
+/// Key of a string of `0` and `1`, the first character is bit 0.
+impl FromStr for Key {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if let Some((position, character)) = value
+            .chars()
+            .enumerate()
+            .find(|&(_, c)| c != '0' && c != '1')
+        {
+            return Err(Error::InvalidKeyCharacter {
+                position,
+                character,
+            });
+        }
+        if value.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        let nr_bits_param = u32::try_from(value.len()).map_err(|_| Error::KeyTooLong {
+            nr_bits: value.len(),
+        })?;
         let nr_words = (nr_bits_param - 1) / 64 + 1;
         let mut words = Vec::with_capacity(nr_words as usize);
         for word in value.as_bytes().chunks(64) {
@@ -73,17 +72,33 @@ impl From<&str> for Key {
         }
         words[nr_words as usize - 1] &= Self::end_word_mask(nr_bits_param - 1);
 
-        Key {
+        Ok(Key {
             nr_bits: nr_bits_param,
             nr_words,
             words,
             estimated_ber: Self::ESTIMATED_QBER,
-        }
+            changes: Vec::new(),
+            nr_compacted_changes: 0,
+            record_changes: true,
+            parity_index: None,
+        })
+    }
+}
+
+/// # Panics
+///
+/// Panics if the string is not a valid key, parse it to get an [`Error`] instead.
+impl From<&str> for Key {
+    fn from(value: &str) -> Self {
+        value.parse().unwrap_or_else(|error| panic!("{}", error))
     }
 }
 
 impl Key {
-    const ESTIMATED_QBER: f32 = 0.02; // TODO: read from config file
+    // default until the QBER is estimated, see `estimation::QberEstimator`
+    const ESTIMATED_QBER: f32 = 0.02;
+    /// Ranges spanning fewer words are folded directly, even if the key has a parity index.
+    const PARITY_INDEX_MIN_WORDS: u32 = 16;
 
     pub fn set_estimated_ber(&mut self, estimated_ber: f32) {
         self.estimated_ber = estimated_ber;
@@ -92,12 +107,19 @@ impl Key {
         self.estimated_ber
     }
     /// Apply bit errors to the key, for prototype purposes.
+    ///
+    /// Uses the random generator of this thread, see [`random`](crate::random).
     pub fn apply_noise(&mut self) {
+        random::with_rng(|rng| self.apply_noise_with_rng(rng));
+    }
+
+    /// Apply bit errors to the key, at positions picked by `rng`.
+    pub fn apply_noise_with_rng<R: Rng + ?Sized>(&mut self, rng: &mut R) {
         let nr_bit_errors = (self.estimated_ber * self.nr_bits as f32).round() as u32;
         let mut error_bits = HashSet::new();
-        println!("nr_bit_errors: {}", nr_bit_errors);
+        debug!(nr_bit_errors, "apply noise");
         for d in (self.nr_bits - nr_bit_errors)..self.nr_bits {
-            let t = random_bit_nr(0, d);
+            let t = random_bit_nr(rng, 0, d);
             if !error_bits.contains(&t) {
                 error_bits.insert(t);
             } else {
@@ -145,6 +167,39 @@ impl Key {
         mask
     }
 
+    /// Build a [`ParityIndex`] for the key.
+    ///
+    /// With the index, range parities cost O(log n) instead of O(n), and flipping a bit
+    /// updates the index in O(log n).
+    pub fn build_parity_index(&mut self) {
+        self.parity_index = Some(ParityIndex::new(&self.words));
+    }
+
+    pub fn has_parity_index(&self) -> bool {
+        self.parity_index.is_some()
+    }
+
+    /// Check that `start_bit_nr..=end_bit_nr` is a range of bits of the key.
+    pub fn check_range(&self, start_bit_nr: u32, end_bit_nr: u32) -> Result<()> {
+        if start_bit_nr > end_bit_nr || end_bit_nr >= self.nr_bits {
+            return Err(Error::InvalidRange {
+                start_bit_nr,
+                end_bit_nr,
+                nr_bits: self.nr_bits,
+            });
+        }
+        Ok(())
+    }
+
+    /// [`Key::compute_range_parity`] of a range from outside, e.g. a peer request.
+    pub fn try_compute_range_parity(&self, start_bit_nr: u32, end_bit_nr: u32) -> Result<u8> {
+        self.check_range(start_bit_nr, end_bit_nr)?;
+        Ok(self.compute_range_parity(start_bit_nr, end_bit_nr))
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the range is out of the key, see [`Key::try_compute_range_parity`].
     pub fn compute_range_parity(&self, start_bit_nr: u32, end_bit_nr: u32) -> u8 {
         assert!(start_bit_nr < self.nr_bits);
         assert!(end_bit_nr < self.nr_bits);
@@ -152,21 +207,71 @@ impl Key {
         let start_word_nr = start_bit_nr / 64;
         let end_word_nr = end_bit_nr / 64;
 
-        let mut xor_words = 0u64;
-        for word_nr in start_word_nr..=end_word_nr {
-            xor_words ^= self.words[word_nr as usize];
+        if let Some(parity_index) = &self.parity_index {
+            if end_word_nr - start_word_nr >= Self::PARITY_INDEX_MIN_WORDS {
+                return parity_index.range_parity(start_word_nr as usize, end_word_nr as usize)
+                    ^ word_parity(self.unwanted_bits(start_bit_nr, end_bit_nr));
+            }
         }
+
+        let mut xor_words = xor_fold(&self.words[start_word_nr as usize..=end_word_nr as usize]);
+        xor_words ^= self.unwanted_bits(start_bit_nr, end_bit_nr);
+
+        word_parity(xor_words)
+    }
+
+    /// Compute the parities of a batch of ranges in one pass over the key.
+    ///
+    /// The ranges are inclusive, a.k.a `start_bit_nr..=end_bit_nr`, and may overlap.
+    /// A prefix XOR of the words is computed once, after that every range only costs
+    /// a few word operations, regardless of its size.
+    pub fn compute_range_parities(&self, ranges: &[(u32, u32)]) -> Vec<u8> {
+        let Some(max_end_bit_nr) = ranges.iter().map(|&(_, end_bit_nr)| end_bit_nr).max() else {
+            return Vec::new();
+        };
+        assert!(max_end_bit_nr < self.nr_bits);
+        let nr_prefix_words = (max_end_bit_nr / 64) as usize + 1;
+
+        // prefix_xor[i] is the XOR of words[0..i]
+        let mut prefix_xor = Vec::with_capacity(nr_prefix_words + 1);
+        let mut acc = 0u64;
+        prefix_xor.push(acc);
+        for &word in &self.words[..nr_prefix_words] {
+            acc ^= word;
+            prefix_xor.push(acc);
+        }
+
+        let parities = ranges
+            .iter()
+            .map(|&(start_bit_nr, end_bit_nr)| {
+                assert!(start_bit_nr <= end_bit_nr);
+                let start_word_nr = (start_bit_nr / 64) as usize;
+                let end_word_nr = (end_bit_nr / 64) as usize;
+                let mut xor_words = prefix_xor[end_word_nr + 1] ^ prefix_xor[start_word_nr];
+                xor_words ^= self.unwanted_bits(start_bit_nr, end_bit_nr);
+                word_parity(xor_words)
+            })
+            .collect();
+
+        // the prefix words carry key bits
+        prefix_xor.zeroize();
+        parities
+    }
+
+    // Bits in the start and end word that are outside of the range.
+    // XOR them into a fold of the words from start to end word to undo them.
+    fn unwanted_bits(&self, start_bit_nr: u32, end_bit_nr: u32) -> u64 {
+        let start_word_nr = (start_bit_nr / 64) as usize;
+        let end_word_nr = (end_bit_nr / 64) as usize;
+
         // Undo bits that we did not want to include in first word.
         let unwanted_mask = !Self::start_word_mask(start_bit_nr);
-        let unwanted_bits = self.words[start_word_nr as usize] & unwanted_mask;
-        xor_words ^= unwanted_bits;
+        let mut unwanted_bits = self.words[start_word_nr] & unwanted_mask;
 
-        // Undo bits that we did not want to include in first word.
+        // Undo bits that we did not want to include in last word.
         let unwanted_mask = !Self::end_word_mask(end_bit_nr);
-        let unwanted_bits = self.words[end_word_nr as usize] & unwanted_mask;
-        xor_words ^= unwanted_bits;
-
-        word_parity(xor_words)
+        unwanted_bits ^= self.words[end_word_nr] & unwanted_mask;
+        unwanted_bits
     }
 
     pub(crate) fn get_nr_bits(&self) -> u32 {
@@ -180,12 +285,23 @@ impl Key {
         for word_nr in 0..self.nr_words {
             let word_nr = word_nr as usize;
             let xor_word = self.words[word_nr] ^ other_key.words[word_nr];
-            difference += xor_word.count_ones() as u32;
+            difference += xor_word.count_ones();
         }
 
         difference
     }
 
+    /// [`Key::get_bit`] of a bit number from outside, e.g. a peer request.
+    pub(crate) fn try_get_bit(&self, bit_nr: u32) -> Result<u8> {
+        if bit_nr >= self.nr_bits {
+            return Err(Error::BitOutOfRange {
+                bit_nr,
+                nr_bits: self.nr_bits,
+            });
+        }
+        Ok(self.get_bit(bit_nr))
+    }
+
     pub(crate) fn get_bit(&self, bit_nr: u32) -> u8 {
         assert!(bit_nr < self.nr_bits);
         let word_nr = (bit_nr / 64) as usize;
@@ -201,11 +317,20 @@ impl Key {
         let bit_nr_in_word = bit_nr % 64;
         let mask = 1u64 << bit_nr_in_word;
 
+        let old_word = self.words[word_nr];
         match value {
             0 => self.words[word_nr] &= !mask,
             1 => self.words[word_nr] |= mask,
             _ => panic!("Invalid value for setting a bit"),
         }
+        if self.words[word_nr] != old_word {
+            if self.record_changes {
+                self.changes.push(bit_nr);
+            }
+            if let Some(parity_index) = &mut self.parity_index {
+                parity_index.flip_word_parity(word_nr);
+            }
+        }
     }
 
     pub(crate) fn flip_bit(&mut self, bit_nr: u32) {
@@ -214,43 +339,213 @@ impl Key {
         let bit_nr_in_word = bit_nr % 64;
         let mask = 1u64 << bit_nr_in_word;
         self.words[word_nr] ^= mask;
+        if self.record_changes {
+            self.changes.push(bit_nr);
+        }
+        if let Some(parity_index) = &mut self.parity_index {
+            parity_index.flip_word_parity(word_nr);
+        }
+    }
+
+    /// Number of bit changes made to the key so far, including the compacted ones.
+    pub(crate) fn get_nr_changes(&self) -> usize {
+        self.nr_compacted_changes + self.changes.len()
+    }
+
+    /// Bits changed after the first `nr_changes` changes, in order of change.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the changes were compacted since, a copy must sync before the compaction.
+    pub(crate) fn changes_since(&self, nr_changes: usize) -> &[u32] {
+        assert!(
+            nr_changes >= self.nr_compacted_changes,
+            "changes compacted before the copy synced"
+        );
+        &self.changes[nr_changes - self.nr_compacted_changes..]
+    }
+
+    /// Forget the recorded changes, once every shuffled copy of the key has synced them.
+    pub(crate) fn compact_changes(&mut self) {
+        self.nr_compacted_changes += self.changes.len();
+        self.changes.zeroize();
+    }
+
+    /// Create a copy of the key with the bits in shuffled order.
+    ///
+    /// Bit `i` of the returned key is bit `shuffle_to_orig(i)` of this key.
+    pub(crate) fn shuffled(&self, shuffle: &Shuffle) -> Key {
+        assert_eq!(self.nr_bits, shuffle.get_nr_bits());
+        let mut words = vec![0u64; self.nr_words as usize];
+        for shuffle_bit_nr in 0..self.nr_bits {
+            let bit = u64::from(self.get_bit(shuffle.shuffle_to_orig(shuffle_bit_nr)));
+            words[(shuffle_bit_nr / 64) as usize] |= bit << (shuffle_bit_nr % 64);
+        }
+        Key {
+            nr_bits: self.nr_bits,
+            nr_words: self.nr_words,
+            words,
+            estimated_ber: self.estimated_ber,
+            changes: Vec::new(),
+            nr_compacted_changes: 0,
+            record_changes: false,
+            parity_index: None,
+        }
+    }
+
+    /// Remove bits from the key, e.g. bits that were disclosed to estimate the QBER.
+    ///
+    /// `bit_nrs` must be sorted and distinct. The remaining bits keep their order.
+    /// The bit numbers change, so this must be done before any shuffled key is created from the key.
+    pub fn discard_bits(&mut self, bit_nrs: &[u32]) {
+        assert!(bit_nrs.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(bit_nrs.iter().all(|&bit_nr| bit_nr < self.nr_bits));
+        assert!((bit_nrs.len() as u32) < self.nr_bits);
+
+        let nr_bits = self.nr_bits - bit_nrs.len() as u32;
+        let nr_words = (nr_bits - 1) / 64 + 1;
+        let mut words = vec![0u64; nr_words as usize];
+        let mut discarded = bit_nrs.iter().peekable();
+        let mut new_bit_nr = 0;
+        for bit_nr in 0..self.nr_bits {
+            if discarded.next_if_eq(&&bit_nr).is_some() {
+                continue;
+            }
+            let bit = u64::from(self.get_bit(bit_nr));
+            words[(new_bit_nr / 64) as usize] |= bit << (new_bit_nr % 64);
+            new_bit_nr += 1;
+        }
+
+        self.words.zeroize();
+        self.compact_changes();
+        self.nr_bits = nr_bits;
+        self.nr_words = nr_words;
+        self.words = words;
+        if self.parity_index.is_some() {
+            self.build_parity_index();
+        }
+    }
+
+    /// Opt in to printing the key bits.
+    ///
+    /// The returned value implements `Display` and prints the bits in natural order from LSB to MSB.
+    /// Only use it for testing and debugging, the output is the secret key.
+    pub fn reveal(&self) -> RevealedKey<'_> {
+        RevealedKey(self)
+    }
+}
+
+/// The copy starts without recorded changes, numbered on from the changes of the key, so that
+/// shuffled copies synced with the key can sync with the copy too.
+impl Clone for Key {
+    fn clone(&self) -> Self {
+        Self {
+            nr_bits: self.nr_bits,
+            nr_words: self.nr_words,
+            words: self.words.clone(),
+            estimated_ber: self.estimated_ber,
+            changes: Vec::new(),
+            nr_compacted_changes: self.get_nr_changes(),
+            record_changes: self.record_changes,
+            parity_index: self.parity_index.clone(),
+        }
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.words.zeroize();
+        self.changes.zeroize();
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("nr_bits", &self.nr_bits)
+            .field("words", &"<redacted>")
+            .field("estimated_ber", &self.estimated_ber)
+            .finish()
     }
 }
 
-impl fmt::Display for Key {
+/// Printable view of the key bits, created by [`Key::reveal`].
+pub struct RevealedKey<'a>(&'a Key);
+
+impl fmt::Display for RevealedKey<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut s = String::new();
+        let key = self.0;
+        let mut s = String::with_capacity(key.nr_bits as usize);
         // Print key bits in natural order from LSB to MSB
-        for bit_nr in 0..self.nr_bits {
-            // '0' ascii code is 48
-            let zero = '0' as u8;
-            s.push(char::from(zero + self.get_bit(bit_nr)));
+        for bit_nr in 0..key.nr_bits {
+            s.push(char::from(b'0' + key.get_bit(bit_nr)));
         }
-        write!(f, "{}", s)
+        let result = write!(f, "{}", s);
+        s.zeroize();
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
+        error::Error,
         key::Key,
-        random::{self, set_random_uint32_seed},
+        random::{random_bit_nr, set_random_seed},
+        shuffle::Shuffle,
     };
+    use rand::{rngs::StdRng, SeedableRng};
 
     #[test]
     fn test_str_to_key() {
         let key = Key::from("1011000010101111010010001001000011001100110001011010100001010111");
         assert_eq!(
             "1011000010101111010010001001000011001100110001011010100001010111",
-            key.to_string()
+            key.reveal().to_string()
         );
 
         let mut noise_key = key.clone();
         noise_key.set_estimated_ber(0.1);
         noise_key.apply_noise();
-        println!("key:       {:>12}", key.to_string());
-        println!("noise_key: {:>12}", noise_key.to_string());
-        assert_ne!(key.to_string(), noise_key.to_string());
+        assert_ne!(key.reveal().to_string(), noise_key.reveal().to_string());
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert_eq!(Err(Error::EmptyKey), "".parse::<Key>().map(|_| ()));
+        assert_eq!(
+            Err(Error::InvalidKeyCharacter {
+                position: 2,
+                character: '2'
+            }),
+            "012".parse::<Key>().map(|_| ())
+        );
+        let key: Key = "0110".parse().unwrap();
+        assert_eq!(Ok(0), key.try_compute_range_parity(1, 2));
+        assert_eq!(Ok(1), key.try_get_bit(2));
+        assert_eq!(
+            Err(Error::BitOutOfRange {
+                bit_nr: 4,
+                nr_bits: 4
+            }),
+            key.try_get_bit(4)
+        );
+        for (start_bit_nr, end_bit_nr) in [(2, 1), (1, 4)] {
+            assert_eq!(
+                Err(Error::InvalidRange {
+                    start_bit_nr,
+                    end_bit_nr,
+                    nr_bits: 4
+                }),
+                key.try_compute_range_parity(start_bit_nr, end_bit_nr)
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid key character 'x' at position 1")]
+    fn test_key_from_invalid_str() {
+        let _ = Key::from("0x");
     }
 
     #[test]
@@ -258,7 +553,7 @@ mod tests {
         let key = Key::from("1011000010101111010010001001000011001100110001011010100001010111");
         assert_eq!(
             "1011000010101111010010001001000011001100110001011010100001010111",
-            key.to_string()
+            key.reveal().to_string()
         );
         assert_eq!(1, key.compute_range_parity(0, 63));
         assert_eq!(0, key.compute_range_parity(0, 62));
@@ -268,19 +563,93 @@ mod tests {
         assert_eq!(1, key.compute_range_parity(63, 63));
     }
 
+    #[test]
+    fn test_compute_range_parities() {
+        let key_str =
+            "100100011001000110010100011001000101000110010001010001100100011100010001".repeat(5);
+        let key = Key::from(key_str.as_str());
+        let nr_bits = key_str.len() as u32;
+
+        let mut ranges = Vec::new();
+        for start_bit_nr in (0..nr_bits).step_by(7) {
+            for end_bit_nr in (start_bit_nr..nr_bits).step_by(13) {
+                ranges.push((start_bit_nr, end_bit_nr));
+            }
+        }
+        ranges.push((nr_bits - 1, nr_bits - 1));
+
+        let parities = key.compute_range_parities(&ranges);
+        assert_eq!(ranges.len(), parities.len());
+        for (&(start_bit_nr, end_bit_nr), &parity) in ranges.iter().zip(&parities) {
+            let expected = key_str[start_bit_nr as usize..=end_bit_nr as usize]
+                .bytes()
+                .filter(|&b| b == b'1')
+                .count()
+                % 2;
+            assert_eq!(expected as u8, parity);
+            assert_eq!(parity, key.compute_range_parity(start_bit_nr, end_bit_nr));
+        }
+        assert!(key.compute_range_parities(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_parity_index() {
+        let mut rng = StdRng::seed_from_u64(2222);
+        let key_str =
+            "100100011001000110010100011001000101000110010001010001100100011100010001".repeat(50);
+        let nr_bits = key_str.len() as u32;
+        let mut key = Key::from(key_str.as_str());
+        let mut indexed_key = key.clone();
+        indexed_key.build_parity_index();
+        assert!(!key.has_parity_index());
+        assert!(indexed_key.has_parity_index());
+
+        for round in 0..10 {
+            if round > 0 {
+                let bit_nr = random_bit_nr(&mut rng, 0, nr_bits - 1);
+                key.flip_bit(bit_nr);
+                indexed_key.flip_bit(bit_nr);
+                let bit_nr = random_bit_nr(&mut rng, 0, nr_bits - 1);
+                key.set_bit(bit_nr, 1);
+                indexed_key.set_bit(bit_nr, 1);
+            }
+            for start_bit_nr in (0..nr_bits).step_by(97) {
+                for end_bit_nr in (start_bit_nr..nr_bits).step_by(89) {
+                    assert_eq!(
+                        key.compute_range_parity(start_bit_nr, end_bit_nr),
+                        indexed_key.compute_range_parity(start_bit_nr, end_bit_nr)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_discard_bits() {
+        let mut key = Key::from("1011000010101111010010001001000011001100110001011010100001010111");
+        key.build_parity_index();
+        key.discard_bits(&[0, 1, 2, 3, 60, 63]);
+        assert_eq!(58, key.get_nr_bits());
+        assert_eq!(
+            "0000101011110100100010010000110011001100010110101000010111",
+            key.reveal().to_string()
+        );
+        assert_eq!(1, key.compute_range_parity(0, 57));
+    }
+
     #[test]
     fn test_key_clone() {
-        set_random_uint32_seed(1111);
+        set_random_seed(1111);
 
         let mut key = Key::from("1011000010101111010010001001000011001100110001011010100001010111");
         assert_eq!(
             "1011000010101111010010001001000011001100110001011010100001010111",
-            key.to_string()
+            key.reveal().to_string()
         );
         let mut key_clone = key.clone();
         assert_eq!(
             "1011000010101111010010001001000011001100110001011010100001010111",
-            key_clone.to_string()
+            key_clone.reveal().to_string()
         );
         // Make sure that changing a bit in the original key does not affect the copied key,
         // and vice versa.
@@ -288,11 +657,54 @@ mod tests {
         key_clone.set_bit(61, 0);
         assert_eq!(
             "1011000010101111010010001001000011001100110001011010100001011111",
-            key.to_string()
+            key.reveal().to_string()
         );
         assert_eq!(
             "1011000010101111010010001001000011001100110001011010100001010011",
-            key_clone.to_string()
+            key_clone.reveal().to_string()
         );
     }
+
+    #[test]
+    fn test_compact_changes() {
+        let mut key = Key::from("1011000010101111010010001001000011001100110001011010100001010111");
+        key.flip_bit(1);
+        key.set_bit(2, 0);
+        assert_eq!(2, key.get_nr_changes());
+        assert_eq!(&[1, 2], key.changes_since(0));
+
+        // the numbering goes on after a compaction and in a clone
+        key.compact_changes();
+        key.flip_bit(3);
+        assert_eq!(3, key.get_nr_changes());
+        assert_eq!(&[3], key.changes_since(2));
+        let mut key_clone = key.clone();
+        assert!(key_clone.changes_since(3).is_empty());
+        key_clone.flip_bit(4);
+        assert_eq!(&[4], key_clone.changes_since(3));
+
+        // shuffled copies do not record their changes
+        let shuffle = Shuffle::new_shuffle_from_seed(2, 64, 1234, false).unwrap();
+        let mut shuffled_key = key.shuffled(&shuffle);
+        shuffled_key.flip_bit(0);
+        assert_eq!(0, shuffled_key.get_nr_changes());
+    }
+
+    #[test]
+    #[should_panic(expected = "changes compacted before the copy synced")]
+    fn test_changes_since_compaction() {
+        let mut key = Key::from("1011");
+        key.flip_bit(1);
+        key.compact_changes();
+        key.changes_since(0);
+    }
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let key = Key::from("1011000010101111010010001001000011001100110001011010100001010111");
+        let debug = format!("{:?}", key);
+        assert!(debug.contains("nr_bits: 64"));
+        assert!(debug.contains("<redacted>"));
+        assert!(!debug.contains(&key.reveal().to_string()));
+    }
 }
@@ -1,8 +1,24 @@
 pub mod algorithm;
+pub mod biconf;
 pub mod block;
+pub mod dot;
+pub mod error;
+pub mod estimation;
+pub mod feistel;
 pub mod iteration;
 pub mod key;
+pub mod observer;
+pub mod parity;
+pub mod partition;
+pub mod protocol;
+pub mod queue;
 pub mod random;
 pub mod reconciliation;
+pub mod scheduled;
+pub mod seed;
+pub mod session;
 pub mod shuffle;
+pub mod shuffle_rng;
 pub mod shuffled_key;
+pub mod split;
+pub mod stats;
@@ -1,57 +1,71 @@
-use std::{cell::RefCell, rc::Rc};
+use std::sync::Arc;
 
 use prototype::{
-    algorithm::OriginalAlgorithm,
-    block::{Block, BlockType},
-    iteration::Iteration,
-    key::Key,
-    reconciliation::Reconciliation,
-    shuffle::Shuffle,
-    shuffled_key::{SharedKey, ShuffledKey},
+    error::Result, estimation::QberEstimator, key::Key, reconciliation::Reconciliation,
+    seed::SeedSchedule,
 };
 
-fn create_test_shuffled_key(key_str: &str) -> (Rc<Key>, SharedKey) {
-    const SEED: u64 = 0x1234567890ABCDEF;
+fn create_test_shuffled_key(key_str: &str) -> Result<(Arc<Key>, Key)> {
+    const ESTIMATION_SEED: u64 = 0x1234567890ABCDEF;
     // correct key
-    let correct_key = Key::from(key_str);
+    let mut correct_key = Key::from(key_str);
     // noise key from file
     let mut noise_key = correct_key.clone();
     noise_key.set_estimated_ber(0.1); // 10% BER, about 3 errors
     noise_key.apply_noise();
 
-    assert_ne!(correct_key.to_string(), noise_key.to_string());
-    (Rc::new(correct_key), Rc::new(RefCell::new(noise_key)))
-}
+    // compare without printing, the keys are secret
+    assert_ne!(correct_key.nr_bits_different(&noise_key), 0);
 
-fn print_keys(correct_key: &Rc<Key>, noise_key: &SharedKey) {
-    println!("correct key: {}", correct_key.to_string());
-    println!("noise key:   {}", noise_key.borrow().to_string());
+    // disclose a sample of the bits to estimate the QBER
+    let estimator = QberEstimator::new(1000, 0.99);
+    let estimate =
+        estimator.estimate_and_discard(&mut noise_key, &mut correct_key, ESTIMATION_SEED)?;
+    println!(
+        "estimated QBER: {:.4} ({:.4}..{:.4})",
+        estimate.qber, estimate.lower_bound, estimate.upper_bound
+    );
+    Ok((Arc::new(correct_key), noise_key))
 }
 
-fn test_reconciliation_large() {
+fn test_reconciliation_large() -> Result<()> {
     const NUM_ITERATIONS: u32 = 9;
     let key_str =
         "100100011001000110010100011001000101000110010001010001100100011100010001".repeat(200);
     assert_eq!(key_str.len(), 14400);
 
-    let (correct_key, noise_key) = create_test_shuffled_key(&key_str);
+    let (correct_key, noise_key) = create_test_shuffled_key(&key_str)?;
 
-    let initial_bit_err = correct_key.nr_bits_different(&*noise_key.borrow());
-    let reconciliation =
-        Reconciliation::new(NUM_ITERATIONS, correct_key.clone(), noise_key.clone());
-    // print_keys(&correct_key, &noise_key);
-    reconciliation.start_iterations();
-    // print_keys(&correct_key, &noise_key);
+    let initial_bit_err = correct_key.nr_bits_different(&noise_key);
+    // both sides agree on the session nonce, the secret is pre-shared
+    let seed_schedule = SeedSchedule::new(b"pre-shared secret", b"session nonce");
+    let mut reconciliation = Reconciliation::new(
+        NUM_ITERATIONS,
+        &seed_schedule,
+        correct_key.clone(),
+        noise_key,
+    );
+    let stats = reconciliation.start_iterations()?;
 
-    let final_bit_err = correct_key.nr_bits_different(&*noise_key.borrow());
+    let final_bit_err = stats.residual_bit_errors.unwrap();
 
     println!(
         "bit differences: initial: {}, final: {}",
         initial_bit_err, final_bit_err
     );
+    println!("{}", stats);
     assert_eq!(final_bit_err, 0);
+    Ok(())
 }
 
-fn main() {
-    test_reconciliation_large();
+fn main() -> Result<()> {
+    // silent but for warnings, e.g. RUST_LOG=prototype=debug to follow the iterations
+    #[cfg(feature = "cli")]
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("warn")),
+        )
+        .init();
+    test_reconciliation_large()
 }
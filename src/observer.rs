@@ -0,0 +1,138 @@
+//! Callbacks while a reconciliation runs, for progress bars, live metrics or test assertions.
+//!
+//! [`Reconciliation`](crate::reconciliation::Reconciliation) owns an observer, see
+//! [`Reconciliation::with_observer`](crate::reconciliation::Reconciliation::with_observer), and
+//! passes it to the iterations, which report what happens in their block trees.
+//! All methods do nothing by default. An observer shared with the caller is an
+//! `Arc<Mutex<impl ReconciliationObserver>>`.
+
+use std::sync::{Arc, Mutex};
+
+use crate::stats::ReconciliationStats;
+
+pub trait ReconciliationObserver: Send + Sync {
+    /// The top block parities of the iteration are about to be asked.
+    fn iteration_started(&mut self, _iteration_nr: u32) {}
+
+    /// The correct parity of the top block `start_bit_nr..=end_bit_nr` of shuffled bits was asked.
+    fn top_block_parity_asked(
+        &mut self,
+        _iteration_nr: u32,
+        _start_bit_nr: u32,
+        _end_bit_nr: u32,
+        _correct_parity: u8,
+    ) {
+    }
+
+    fn sub_block_parity_asked(
+        &mut self,
+        _iteration_nr: u32,
+        _start_bit_nr: u32,
+        _end_bit_nr: u32,
+        _correct_parity: u8,
+    ) {
+    }
+
+    /// The correct parity of a sub block was inferred from its parent and siblings.
+    fn sub_block_parity_inferred(
+        &mut self,
+        _iteration_nr: u32,
+        _start_bit_nr: u32,
+        _end_bit_nr: u32,
+        _correct_parity: u8,
+    ) {
+    }
+
+    /// The binary search of the iteration corrected a bit of the noise key.
+    fn bit_corrected(&mut self, _iteration_nr: u32, _orig_bit_nr: u32) {}
+
+    /// A bit corrected by the iteration `from_iteration_nr` scheduled blocks of the iteration
+    /// `into_iteration_nr`, which now have an odd number of errors.
+    fn cascade_triggered(
+        &mut self,
+        _from_iteration_nr: u32,
+        _into_iteration_nr: u32,
+        _orig_bit_nr: u32,
+    ) {
+    }
+
+    fn reconciliation_finished(&mut self, _stats: &ReconciliationStats) {}
+}
+
+/// Observer ignoring all events, the default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoObserver;
+
+impl ReconciliationObserver for NoObserver {}
+
+impl<T: ReconciliationObserver> ReconciliationObserver for Arc<Mutex<T>> {
+    fn iteration_started(&mut self, iteration_nr: u32) {
+        self.lock().unwrap().iteration_started(iteration_nr);
+    }
+
+    fn top_block_parity_asked(
+        &mut self,
+        iteration_nr: u32,
+        start_bit_nr: u32,
+        end_bit_nr: u32,
+        correct_parity: u8,
+    ) {
+        self.lock().unwrap().top_block_parity_asked(
+            iteration_nr,
+            start_bit_nr,
+            end_bit_nr,
+            correct_parity,
+        );
+    }
+
+    fn sub_block_parity_asked(
+        &mut self,
+        iteration_nr: u32,
+        start_bit_nr: u32,
+        end_bit_nr: u32,
+        correct_parity: u8,
+    ) {
+        self.lock().unwrap().sub_block_parity_asked(
+            iteration_nr,
+            start_bit_nr,
+            end_bit_nr,
+            correct_parity,
+        );
+    }
+
+    fn sub_block_parity_inferred(
+        &mut self,
+        iteration_nr: u32,
+        start_bit_nr: u32,
+        end_bit_nr: u32,
+        correct_parity: u8,
+    ) {
+        self.lock().unwrap().sub_block_parity_inferred(
+            iteration_nr,
+            start_bit_nr,
+            end_bit_nr,
+            correct_parity,
+        );
+    }
+
+    fn bit_corrected(&mut self, iteration_nr: u32, orig_bit_nr: u32) {
+        self.lock()
+            .unwrap()
+            .bit_corrected(iteration_nr, orig_bit_nr);
+    }
+
+    fn cascade_triggered(
+        &mut self,
+        from_iteration_nr: u32,
+        into_iteration_nr: u32,
+        orig_bit_nr: u32,
+    ) {
+        self.lock()
+            .unwrap()
+            .cascade_triggered(from_iteration_nr, into_iteration_nr, orig_bit_nr);
+    }
+
+    fn reconciliation_finished(&mut self, stats: &ReconciliationStats) {
+        self.lock().unwrap().reconciliation_finished(stats);
+    }
+}
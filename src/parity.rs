@@ -0,0 +1,232 @@
+//! Parity kernels used by [`Key`](crate::key::Key).
+//!
+//! Computing parities is the inner loop of cascade on both sides: Bob computes the current parity
+//! of his blocks, and Alice answers the parity requests. A range parity is computed by XOR-folding
+//! all the words of the range into a single word, and then taking the parity of that word.
+//!
+//! The fold processes [`LANES`] words per step, so that the compiler can vectorize it.
+//! On x86_64 an AVX2 version of the same fold is selected at runtime when the CPU supports it.
+//!
+//! Keys that are queried while bits are being corrected can attach a [`ParityIndex`], a Fenwick
+//! tree over the word parities, which answers range parities and follows bit flips in O(log n).
+use zeroize::Zeroize;
+
+/// Number of words folded per step, 8 words fill two AVX2 registers.
+pub const LANES: usize = 8;
+
+/// Below this number of words the runtime feature detection costs more than it saves.
+const SIMD_MIN_WORDS: usize = 4 * LANES;
+
+/// Calculate the parity of a single word.
+///
+/// `count_ones` compiles to a single `popcnt` instruction on targets that support it.
+#[inline]
+pub fn word_parity(word: u64) -> u8 {
+    (word.count_ones() & 1) as u8
+}
+
+/// Calculate the parity of a sigle word.
+///
+/// This only need 8 XOR and 8 lookups operations.
+///
+/// This is the original table based approach, it is kept as a reference for
+/// testing and benchmarking [`word_parity`].
+pub fn word_parity_table(word: u64) -> u8 {
+    // precomputed parity values for all possible 256 byte values (from 0 to 255).
+    static BYTE_PARITY: [u8; 256] = [
+        0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0,
+        0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1,
+        0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0,
+        0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0,
+        0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0,
+        0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1,
+        0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0,
+        0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1,
+        0, 1, 1, 0, 1, 0, 0, 1, 1, 0, 0, 1, 0, 1, 1, 0,
+    ];
+
+    let mut parity = 0;
+    parity ^= BYTE_PARITY[(word & 0xff) as usize];
+    parity ^= BYTE_PARITY[((word >> 8) & 0xff) as usize];
+    parity ^= BYTE_PARITY[((word >> 16) & 0xff) as usize];
+    parity ^= BYTE_PARITY[((word >> 24) & 0xff) as usize];
+    parity ^= BYTE_PARITY[((word >> 32) & 0xff) as usize];
+    parity ^= BYTE_PARITY[((word >> 40) & 0xff) as usize];
+    parity ^= BYTE_PARITY[((word >> 48) & 0xff) as usize];
+    parity ^= BYTE_PARITY[((word >> 56) & 0xff) as usize];
+    parity
+}
+
+/// XOR all words into a single word.
+///
+/// The parity of the result is the parity of all the bits in `words`.
+pub fn xor_fold(words: &[u64]) -> u64 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if words.len() >= SIMD_MIN_WORDS && is_x86_feature_detected!("avx2") {
+            // SAFETY: the CPU supports AVX2, checked above.
+            return unsafe { xor_fold_avx2(words) };
+        }
+    }
+    xor_fold_lanes(words)
+}
+
+/// Parity of all the bits in `words`.
+pub fn words_parity(words: &[u64]) -> u8 {
+    let mut folded = xor_fold(words);
+    let parity = word_parity(folded);
+    folded.zeroize();
+    parity
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn xor_fold_avx2(words: &[u64]) -> u64 {
+    // same code as the portable version, but compiled with AVX2 enabled
+    xor_fold_lanes(words)
+}
+
+#[inline(always)]
+fn xor_fold_lanes(words: &[u64]) -> u64 {
+    let mut lanes = [0u64; LANES];
+    let chunks = words.chunks_exact(LANES);
+    let mut folded = chunks.remainder().iter().fold(0, |acc, word| acc ^ word);
+    for chunk in chunks {
+        for (lane, word) in lanes.iter_mut().zip(chunk) {
+            *lane ^= word;
+        }
+    }
+    for lane in lanes {
+        folded ^= lane;
+    }
+    // the lanes hold key bits
+    lanes.zeroize();
+    folded
+}
+
+/// Fenwick tree (binary indexed tree) over the parities of the words of a key.
+///
+/// Node `i` (1-based) holds the XOR of the word parities `(i - lowbit(i))..i`, so that both the
+/// parity of a prefix of words and the update after a bit flip touch O(log n) nodes.
+/// Only word parities are stored, the index does not hold key bits.
+#[derive(Clone, Debug)]
+pub struct ParityIndex {
+    tree: Vec<u8>,
+}
+
+impl ParityIndex {
+    /// Build the index in O(n).
+    pub fn new(words: &[u64]) -> Self {
+        let nr_words = words.len();
+        let mut tree = vec![0u8; nr_words + 1];
+        for (word_nr, &word) in words.iter().enumerate() {
+            let node = word_nr + 1;
+            tree[node] ^= word_parity(word);
+            let parent = node + lowbit(node);
+            if parent <= nr_words {
+                tree[parent] ^= tree[node];
+            }
+        }
+        Self { tree }
+    }
+
+    pub fn get_nr_words(&self) -> usize {
+        self.tree.len() - 1
+    }
+
+    /// Update the index after the parity of a word changed, e.g. because a bit in it was flipped.
+    pub fn flip_word_parity(&mut self, word_nr: usize) {
+        assert!(word_nr < self.get_nr_words());
+        let mut node = word_nr + 1;
+        while node < self.tree.len() {
+            self.tree[node] ^= 1;
+            node += lowbit(node);
+        }
+    }
+
+    /// Parity of the words in the range, inclusive, a.k.a `start_word_nr..=end_word_nr`.
+    pub fn range_parity(&self, start_word_nr: usize, end_word_nr: usize) -> u8 {
+        assert!(start_word_nr <= end_word_nr);
+        assert!(end_word_nr < self.get_nr_words());
+        self.prefix_parity(end_word_nr + 1) ^ self.prefix_parity(start_word_nr)
+    }
+
+    // Parity of the first `nr_words` words.
+    fn prefix_parity(&self, nr_words: usize) -> u8 {
+        let mut parity = 0;
+        let mut node = nr_words;
+        while node > 0 {
+            parity ^= self.tree[node];
+            node -= lowbit(node);
+        }
+        parity
+    }
+}
+
+impl Drop for ParityIndex {
+    fn drop(&mut self) {
+        self.tree.zeroize();
+    }
+}
+
+fn lowbit(node: usize) -> usize {
+    node & node.wrapping_neg()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::{word_parity, word_parity_table, words_parity, xor_fold, ParityIndex};
+
+    #[test]
+    fn test_word_parity() {
+        let mut rng = StdRng::seed_from_u64(1234);
+        for word in [0, 1, 3, u64::MAX, u64::MAX - 1, 1 << 63] {
+            assert_eq!(word_parity_table(word), word_parity(word));
+        }
+        for _ in 0..10000 {
+            let word = rng.gen();
+            assert_eq!(word_parity_table(word), word_parity(word));
+        }
+    }
+
+    #[test]
+    fn test_xor_fold() {
+        let mut rng = StdRng::seed_from_u64(1234);
+        let words: Vec<u64> = (0..1000).map(|_| rng.gen()).collect();
+        // cover the remainder, the portable and the SIMD path
+        for len in [0, 1, 7, 8, 9, 31, 32, 33, 100, 1000] {
+            let words = &words[..len];
+            let expected = words.iter().fold(0, |acc, word| acc ^ word);
+            assert_eq!(expected, xor_fold(words));
+            assert_eq!(word_parity_table(expected), words_parity(words));
+        }
+    }
+
+    #[test]
+    fn test_parity_index() {
+        let mut rng = StdRng::seed_from_u64(1234);
+        for nr_words in [1, 2, 3, 17, 64, 100] {
+            let mut words: Vec<u64> = (0..nr_words).map(|_| rng.gen()).collect();
+            let mut index = ParityIndex::new(&words);
+            assert_eq!(nr_words, index.get_nr_words());
+            for round in 0..20 {
+                if round > 0 {
+                    // flip a random bit and update the index
+                    let word_nr = rng.gen_range(0..nr_words);
+                    words[word_nr] ^= 1 << rng.gen_range(0..64);
+                    index.flip_word_parity(word_nr);
+                }
+                for start_word_nr in 0..nr_words {
+                    for end_word_nr in start_word_nr..nr_words {
+                        assert_eq!(
+                            words_parity(&words[start_word_nr..=end_word_nr]),
+                            index.range_parity(start_word_nr, end_word_nr)
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
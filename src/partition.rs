@@ -0,0 +1,124 @@
+//! How an iteration partitions the shuffled key into top blocks, see
+//! [`InnerConfig::partitioner`](crate::algorithm::InnerConfig::partitioner).
+//!
+//! The top blocks are contiguous in shuffled order and cover the whole key. The block size of the
+//! algorithm is the size of most top blocks; the partitioners differ in where the bits that do not
+//! fill a whole block go. A small block is likely to be error free, so its parity leaks a bit for
+//! little gain; a large one is more likely to hide an even number of errors.
+//!
+//! Both sides must partition alike. The randomized offset is derived from the shuffle seed of the
+//! iteration, so it needs no message:
+//!
+//! ```text
+//! offset = 1 + below(block_size) of the stream seeded with !shuffle_seed, see shuffle_rng
+//! ```
+
+use crate::shuffle_rng::ShuffleRng;
+
+/// Where the bits that do not fill a whole top block go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Partitioner {
+    /// Blocks of the block size, the last block takes the remainder and may be only a few bits.
+    #[default]
+    FixedSize,
+    /// As many blocks as with [`Partitioner::FixedSize`], of sizes differing by at most one bit,
+    /// the first blocks take the remainder.
+    EqualSize,
+    /// The first block takes a random number of bits, up to the block size, the next ones the
+    /// block size and the last one the remainder. The block boundaries move between sessions.
+    RandomOffset,
+}
+
+impl Partitioner {
+    /// The top blocks of a key of `nr_bits` bits, as inclusive ranges of shuffled bits in order.
+    ///
+    /// # Arguments
+    /// shuffle_seed: seed of the shuffle of the iteration, see [`Partitioner::RandomOffset`]
+    pub fn partition(&self, nr_bits: u32, block_size: u32, shuffle_seed: u64) -> Vec<(u32, u32)> {
+        assert!(block_size > 0, "the block size must be positive");
+        let nr_blocks = nr_bits.div_ceil(block_size);
+        let block_sizes: Vec<u32> = match self {
+            Self::FixedSize => vec![block_size; nr_blocks as usize],
+            Self::EqualSize => (0..nr_blocks)
+                .map(|block_nr| nr_bits / nr_blocks + u32::from(block_nr < nr_bits % nr_blocks))
+                .collect(),
+            Self::RandomOffset => {
+                let first_block_size = 1 + ShuffleRng::from_seed(!shuffle_seed).below(block_size);
+                let nr_next_blocks = nr_bits
+                    .saturating_sub(first_block_size)
+                    .div_ceil(block_size);
+                std::iter::once(first_block_size)
+                    .chain(std::iter::repeat_n(block_size, nr_next_blocks as usize))
+                    .collect()
+            }
+        };
+
+        let mut top_blocks = Vec::with_capacity(block_sizes.len());
+        let mut start_bit_nr = 0;
+        for nr_block_bits in block_sizes {
+            if start_bit_nr >= nr_bits {
+                break;
+            }
+            let end_bit_nr = std::cmp::min(start_bit_nr + nr_block_bits, nr_bits) - 1;
+            top_blocks.push((start_bit_nr, end_bit_nr));
+            start_bit_nr = end_bit_nr + 1;
+        }
+        top_blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Partitioner;
+
+    fn sizes(top_blocks: &[(u32, u32)]) -> Vec<u32> {
+        top_blocks
+            .iter()
+            .map(|(start_bit_nr, end_bit_nr)| end_bit_nr - start_bit_nr + 1)
+            .collect()
+    }
+
+    #[test]
+    fn test_partition() {
+        assert_eq!(
+            vec![(0, 7), (8, 15), (16, 17)],
+            Partitioner::FixedSize.partition(18, 8, 0)
+        );
+        assert_eq!(vec![(0, 5)], Partitioner::FixedSize.partition(6, 8, 0));
+        assert_eq!(
+            vec![6, 6, 6],
+            sizes(&Partitioner::EqualSize.partition(18, 8, 0))
+        );
+        assert_eq!(
+            vec![7, 7, 6],
+            sizes(&Partitioner::EqualSize.partition(20, 8, 0))
+        );
+        assert_eq!(vec![6], sizes(&Partitioner::EqualSize.partition(6, 8, 0)));
+        assert!(Partitioner::EqualSize.partition(0, 8, 0).is_empty());
+        assert!(Partitioner::RandomOffset.partition(0, 8, 0).is_empty());
+    }
+
+    #[test]
+    fn test_random_offset() {
+        let mut first_sizes = Vec::new();
+        for shuffle_seed in 0..32 {
+            let top_blocks = Partitioner::RandomOffset.partition(100, 8, shuffle_seed);
+            // contiguous, covering the key
+            assert_eq!(0, top_blocks[0].0);
+            assert_eq!(99, top_blocks.last().unwrap().1);
+            assert!(top_blocks.windows(2).all(|pair| pair[0].1 + 1 == pair[1].0));
+            let sizes = sizes(&top_blocks);
+            assert!(sizes.iter().all(|&size| (1..=8).contains(&size)));
+            assert!(sizes[1..sizes.len() - 1].iter().all(|&size| size == 8));
+            // both sides agree
+            assert_eq!(
+                top_blocks,
+                Partitioner::RandomOffset.partition(100, 8, shuffle_seed)
+            );
+            first_sizes.push(sizes[0]);
+        }
+        first_sizes.sort();
+        first_sizes.dedup();
+        assert!(first_sizes.len() > 1);
+    }
+}
@@ -0,0 +1,559 @@
+//! Parity requests from Bob to Alice, and their encoding on the wire.
+//!
+//! Bob asks the correct parity of a block of a shuffled key. A request identifies the bits of the
+//! block either by the list of their numbers in the original key, or by the iteration, the shuffle
+//! seed and the range of shuffled bits, from which Alice rebuilds the shuffle of the iteration.
+//! A block of `n` bits takes `5 + 4 * n` bytes as a bit list, and 21 bytes as a shuffle range.
+//!
+//! ```text
+//! bit list:       0x00 | nr_bits: u32 | orig_bit_nr: u32 ...
+//! shuffle range:  0x01 (table) or 0x02 (Feistel) | iteration_nr: u32 | shuffle_seed: u64
+//!                 | start_bit_nr: u32 | end_bit_nr: u32
+//! reply:          parity: u8
+//! ```
+//!
+//! All integers are big endian, the range is inclusive.
+//! Several requests may be sent together in one round trip, their replies come back together.
+//! Shuffles without a seed, e.g. recorded shuffles, are always sent as a bit list.
+//!
+//! Alice answers with a [`ParityResponder`], which only accepts the shuffle seeds of the agreed
+//! [`SeedSchedule`] and iterations, and keeps a shuffled copy of her key per shuffle to answer a
+//! range in O(log n).
+
+use std::{collections::HashMap, ops::AddAssign, sync::Arc};
+
+use crate::{
+    error::{Error, Result},
+    key::Key,
+    seed::SeedSchedule,
+    shuffle::{Shuffle, ShuffleBackend, ShuffleCache, ShuffleIndex},
+};
+
+const BIT_LIST_TAG: u8 = 0x00;
+const TABLE_SHUFFLE_RANGE_TAG: u8 = 0x01;
+const FEISTEL_SHUFFLE_RANGE_TAG: u8 = 0x02;
+const SHUFFLE_RANGE_LEN: usize = 21;
+const REPLY_LEN: u64 = 1;
+
+/// How Bob identifies the bits of a block, see `ask_correct_parity_using_shuffle_seed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParityRequestEncoding {
+    #[default]
+    BitList,
+    ShuffleSeed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParityRequest {
+    /// Bit numbers in the original key.
+    BitList(Vec<u32>),
+    ShuffleRange {
+        iteration_nr: u32,
+        backend: ShuffleBackend,
+        shuffle_seed: u64,
+        start_bit_nr: u32,
+        end_bit_nr: u32,
+    },
+}
+
+impl ParityRequest {
+    /// Request for the shuffled bits `start_bit_nr..=end_bit_nr`.
+    pub fn new(
+        encoding: ParityRequestEncoding,
+        shuffle: &Shuffle,
+        start_bit_nr: u32,
+        end_bit_nr: u32,
+    ) -> Self {
+        match encoding {
+            ParityRequestEncoding::ShuffleSeed if shuffle.has_seed() => Self::ShuffleRange {
+                iteration_nr: shuffle.get_iteration_nr(),
+                backend: shuffle.get_backend(),
+                shuffle_seed: shuffle.get_seed(),
+                start_bit_nr,
+                end_bit_nr,
+            },
+            _ => Self::BitList(
+                (start_bit_nr..=end_bit_nr)
+                    .map(|bit_nr| shuffle.shuffle_to_orig(bit_nr))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Number of bytes of the encoded request.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Self::BitList(bit_nrs) => 5 + 4 * bit_nrs.len(),
+            Self::ShuffleRange { .. } => SHUFFLE_RANGE_LEN,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::BitList(bit_nrs) => {
+                let mut bytes = Vec::with_capacity(self.encoded_len());
+                bytes.push(BIT_LIST_TAG);
+                bytes.extend_from_slice(&(bit_nrs.len() as u32).to_be_bytes());
+                for bit_nr in bit_nrs {
+                    bytes.extend_from_slice(&bit_nr.to_be_bytes());
+                }
+                bytes
+            }
+            Self::ShuffleRange {
+                iteration_nr,
+                backend,
+                shuffle_seed,
+                start_bit_nr,
+                end_bit_nr,
+            } => {
+                let mut bytes = Vec::with_capacity(SHUFFLE_RANGE_LEN);
+                bytes.push(match backend {
+                    ShuffleBackend::Table => TABLE_SHUFFLE_RANGE_TAG,
+                    ShuffleBackend::Feistel => FEISTEL_SHUFFLE_RANGE_TAG,
+                });
+                bytes.extend_from_slice(&iteration_nr.to_be_bytes());
+                bytes.extend_from_slice(&shuffle_seed.to_be_bytes());
+                bytes.extend_from_slice(&start_bit_nr.to_be_bytes());
+                bytes.extend_from_slice(&end_bit_nr.to_be_bytes());
+                bytes
+            }
+        }
+    }
+
+    /// Decode a request received from a peer.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let (&tag, rest) = bytes
+            .split_first()
+            .ok_or(Error::MalformedRequest("empty request"))?;
+        let u32_at = |offset: usize| {
+            let field = rest
+                .get(offset..offset + 4)
+                .ok_or(Error::MalformedRequest("truncated request"))?;
+            Ok(u32::from_be_bytes(field.try_into().unwrap()))
+        };
+        match tag {
+            BIT_LIST_TAG => {
+                let nr_bits = u32_at(0)? as usize;
+                if rest.len() != 4 + 4 * nr_bits {
+                    return Err(Error::MalformedRequest(
+                        "bit list length differs from the number of bits",
+                    ));
+                }
+                let bit_nrs: Result<Vec<u32>> = (0..nr_bits).map(|i| u32_at(4 + 4 * i)).collect();
+                bit_nrs.map(Self::BitList)
+            }
+            TABLE_SHUFFLE_RANGE_TAG | FEISTEL_SHUFFLE_RANGE_TAG => {
+                if bytes.len() != SHUFFLE_RANGE_LEN {
+                    return Err(Error::MalformedRequest("shuffle range of a wrong length"));
+                }
+                let backend = if tag == TABLE_SHUFFLE_RANGE_TAG {
+                    ShuffleBackend::Table
+                } else {
+                    ShuffleBackend::Feistel
+                };
+                Ok(Self::ShuffleRange {
+                    iteration_nr: u32_at(0)?,
+                    backend,
+                    shuffle_seed: u64::from_be_bytes(rest[4..12].try_into().unwrap()),
+                    start_bit_nr: u32_at(12)?,
+                    end_bit_nr: u32_at(16)?,
+                })
+            }
+            _ => Err(Error::MalformedRequest("unknown tag")),
+        }
+    }
+}
+
+/// Alice's side of the parity requests of one session: answers them from the correct key.
+pub struct ParityResponder {
+    correct_key: Arc<Key>,
+    // None accepts any shuffle seed
+    seed_schedule: Option<SeedSchedule>,
+    // None accepts any iteration
+    nr_iterations: Option<u32>,
+    // None rebuilds the shuffles
+    shuffle_cache: Option<Arc<ShuffleCache>>,
+    // the correct key in shuffled order with a parity index, per requested shuffle
+    shuffled_keys: HashMap<ShuffleIndex, Key>,
+}
+
+impl ParityResponder {
+    /// Responder accepting only the shuffle seeds of `seed_schedule`, None accepts any seed.
+    ///
+    /// The shuffles are looked up in `shuffle_cache`, e.g. a cache of the session,
+    /// None rebuilds them.
+    pub fn new(
+        correct_key: Arc<Key>,
+        seed_schedule: Option<SeedSchedule>,
+        shuffle_cache: Option<Arc<ShuffleCache>>,
+    ) -> Self {
+        Self {
+            correct_key,
+            seed_schedule,
+            nr_iterations: None,
+            shuffle_cache,
+            shuffled_keys: HashMap::new(),
+        }
+    }
+
+    /// Answer the shuffles of the iterations `1..=nr_iterations` only.
+    ///
+    /// Each requested shuffle keeps a shuffled copy of the key, the bound limits them.
+    pub fn with_nr_iterations(mut self, nr_iterations: u32) -> Self {
+        self.nr_iterations = Some(nr_iterations);
+        self
+    }
+
+    /// Alice's answer, the parity of the requested bits of her key.
+    ///
+    /// # Errors
+    ///
+    /// If the request asks bits out of the key, the shuffle of an iteration 0 or after the
+    /// agreed iterations, or a shuffle seed that is not the agreed one.
+    pub fn answer(&mut self, request: &ParityRequest) -> Result<u8> {
+        match *request {
+            ParityRequest::BitList(ref bit_nrs) => bit_nrs.iter().try_fold(0, |parity, &bit_nr| {
+                Ok(parity ^ self.correct_key.try_get_bit(bit_nr)?)
+            }),
+            ParityRequest::ShuffleRange {
+                iteration_nr,
+                backend,
+                shuffle_seed,
+                start_bit_nr,
+                end_bit_nr,
+            } => {
+                self.correct_key.check_range(start_bit_nr, end_bit_nr)?;
+                let shuffled_key = self.shuffled_key(iteration_nr, backend, shuffle_seed)?;
+                Ok(shuffled_key.compute_range_parity(start_bit_nr, end_bit_nr))
+            }
+        }
+    }
+
+    /// The correct key shuffled with the requested shuffle, created at the first request.
+    fn shuffled_key(
+        &mut self,
+        iteration_nr: u32,
+        backend: ShuffleBackend,
+        shuffle_seed: u64,
+    ) -> Result<&Key> {
+        if let Some(nr_iterations) = self.nr_iterations {
+            if !(1..=nr_iterations).contains(&iteration_nr) {
+                return Err(Error::IterationNrOutOfRange {
+                    iteration_nr,
+                    nr_iterations,
+                });
+            }
+        }
+        let nr_bits = self.correct_key.get_nr_bits();
+        let index = ShuffleIndex::new(iteration_nr, nr_bits, shuffle_seed).with_backend(backend);
+        if !self.shuffled_keys.contains_key(&index) {
+            if let Some(seed_schedule) = &self.seed_schedule {
+                if shuffle_seed != seed_schedule.shuffle_seed(iteration_nr) {
+                    return Err(Error::UnexpectedShuffleSeed { iteration_nr });
+                }
+            }
+            // Alice rebuilds the shuffle, or finds it in the shuffle cache
+            let shuffle = match &self.shuffle_cache {
+                Some(cache) => {
+                    cache.get_or_create_from_seed(iteration_nr, nr_bits, shuffle_seed, backend)?
+                }
+                None => Shuffle::new_shuffle_from_seed_with_backend(
+                    iteration_nr,
+                    nr_bits,
+                    shuffle_seed,
+                    backend,
+                    false,
+                )?,
+            };
+            let mut shuffled_key = self.correct_key.shuffled(&shuffle);
+            shuffled_key.build_parity_index();
+            self.shuffled_keys.insert(index, shuffled_key);
+        }
+        Ok(&self.shuffled_keys[&index])
+    }
+}
+
+/// Bytes sent on the wire for the parity requests and their replies.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WireStats {
+    pub nr_requests: u64,
+    /// Requests sent together count as one round trip.
+    pub nr_round_trips: u64,
+    pub request_bytes: u64,
+    pub reply_bytes: u64,
+}
+
+impl WireStats {
+    /// The bytes of the requests sent together, and of their replies.
+    pub fn of_round_trip(requests: &[ParityRequest]) -> Self {
+        Self {
+            nr_requests: requests.len() as u64,
+            nr_round_trips: u64::from(!requests.is_empty()),
+            request_bytes: requests
+                .iter()
+                .map(|request| request.encoded_len() as u64)
+                .sum(),
+            reply_bytes: REPLY_LEN * requests.len() as u64,
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.request_bytes + self.reply_bytes
+    }
+}
+
+impl AddAssign for WireStats {
+    fn add_assign(&mut self, other: Self) {
+        self.nr_requests += other.nr_requests;
+        self.nr_round_trips += other.nr_round_trips;
+        self.request_bytes += other.request_bytes;
+        self.reply_bytes += other.reply_bytes;
+    }
+}
+
+/// Where Bob's parity requests are answered, e.g. a simulated Alice, or a peer over a network.
+///
+/// A [`Reconciliation`](crate::reconciliation::Reconciliation) asks through the channel given to
+/// it; a session sends the requests as messages instead, see [`session`](crate::session).
+pub trait ParityChannel: Send + Sync {
+    /// Ask the correct parities of the requests in one round trip, the parities in order.
+    ///
+    /// # Errors
+    ///
+    /// if the requests were not answered, e.g. a failed or malformed reply
+    fn ask_correct_parities(&mut self, requests: &[ParityRequest]) -> Result<Vec<u8>>;
+}
+
+/// Simulated channel to Alice, who answers the parity requests from the correct key.
+///
+/// Every request is encoded and decoded, so the answer is the one of a real channel.
+/// The requests come from Bob's own shuffles, so any shuffle seed is accepted.
+pub struct SimulatedParityChannel {
+    responder: ParityResponder,
+}
+
+impl SimulatedParityChannel {
+    pub fn new(correct_key: Arc<Key>) -> Self {
+        Self {
+            responder: ParityResponder::new(correct_key, None, None),
+        }
+    }
+}
+
+impl ParityChannel for SimulatedParityChannel {
+    fn ask_correct_parities(&mut self, requests: &[ParityRequest]) -> Result<Vec<u8>> {
+        requests
+            .iter()
+            .map(|request| {
+                let request = ParityRequest::decode(&request.encode())?;
+                self.responder.answer(&request)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        error::Error,
+        key::Key,
+        seed::SeedSchedule,
+        shuffle::{Shuffle, ShuffleBackend, ShuffleCache},
+    };
+
+    use super::{
+        ParityChannel, ParityRequest, ParityRequestEncoding, ParityResponder,
+        SimulatedParityChannel, WireStats,
+    };
+
+    const KEY_STR: &str = "1011000010101111010010001001000011001100110001011010100001010111";
+    const SEED: u64 = 0x1234_0037;
+
+    fn shuffle_range(iteration_nr: u32, shuffle_seed: u64, end_bit_nr: u32) -> ParityRequest {
+        ParityRequest::ShuffleRange {
+            iteration_nr,
+            backend: ShuffleBackend::Table,
+            shuffle_seed,
+            start_bit_nr: 10,
+            end_bit_nr,
+        }
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let shuffle = Shuffle::new_shuffle_from_seed(2, 64, SEED, false).unwrap();
+        let bit_list = ParityRequest::new(ParityRequestEncoding::BitList, &shuffle, 3, 12);
+        let bytes = bit_list.encode();
+        assert_eq!(5 + 4 * 10, bytes.len());
+        assert_eq!(bytes.len(), bit_list.encoded_len());
+        assert_eq!(Ok(bit_list), ParityRequest::decode(&bytes));
+
+        let shuffle_range = ParityRequest::new(ParityRequestEncoding::ShuffleSeed, &shuffle, 3, 12);
+        let bytes = shuffle_range.encode();
+        assert_eq!(21, bytes.len());
+        assert_eq!(bytes.len(), shuffle_range.encoded_len());
+        assert_eq!(Ok(shuffle_range), ParityRequest::decode(&bytes));
+
+        assert!(ParityRequest::decode(&[]).is_err());
+        assert!(ParityRequest::decode(&bytes[..20]).is_err());
+        assert!(ParityRequest::decode(&[0x00, 0, 0, 0, 2, 0, 0, 0, 1]).is_err());
+        assert_eq!(
+            Err(Error::MalformedRequest("unknown tag")),
+            ParityRequest::decode(&[0x03])
+        );
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let mut responder = ParityResponder::new(Arc::new(Key::from(KEY_STR)), None, None);
+        assert_eq!(
+            Err(Error::BitOutOfRange {
+                bit_nr: 64,
+                nr_bits: 64
+            }),
+            responder.answer(&ParityRequest::BitList(vec![3, 64]))
+        );
+        assert_eq!(
+            Err(Error::InvalidRange {
+                start_bit_nr: 10,
+                end_bit_nr: 64,
+                nr_bits: 64
+            }),
+            responder.answer(&shuffle_range(2, SEED, 64))
+        );
+        assert_eq!(
+            Err(Error::InvalidIterationNr(0)),
+            responder.answer(&shuffle_range(0, SEED, 63))
+        );
+        assert!(responder.answer(&shuffle_range(2, SEED, 63)).is_ok());
+    }
+
+    #[test]
+    fn test_responder_checks_seeds() {
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let seed = seed_schedule.shuffle_seed(2);
+        let mut responder =
+            ParityResponder::new(Arc::new(Key::from(KEY_STR)), Some(seed_schedule), None);
+        assert!(responder.answer(&shuffle_range(2, seed, 63)).is_ok());
+        // the seed of another iteration, or of no iteration
+        assert_eq!(
+            Err(Error::UnexpectedShuffleSeed { iteration_nr: 3 }),
+            responder.answer(&shuffle_range(3, seed, 63))
+        );
+        assert_eq!(
+            Err(Error::UnexpectedShuffleSeed { iteration_nr: 2 }),
+            responder.answer(&shuffle_range(2, SEED, 63))
+        );
+    }
+
+    #[test]
+    fn test_responder_checks_iteration_nrs() {
+        let mut responder =
+            ParityResponder::new(Arc::new(Key::from(KEY_STR)), None, None).with_nr_iterations(2);
+        assert!(responder.answer(&shuffle_range(2, SEED, 63)).is_ok());
+        for iteration_nr in [0, 3, u32::MAX] {
+            assert_eq!(
+                Err(Error::IterationNrOutOfRange {
+                    iteration_nr,
+                    nr_iterations: 2
+                }),
+                responder.answer(&shuffle_range(iteration_nr, SEED, 63))
+            );
+        }
+        // nothing is kept for the rejected requests
+        assert_eq!(1, responder.shuffled_keys.len());
+    }
+
+    #[test]
+    fn test_responder_uses_given_cache() {
+        let correct_key = Arc::new(Key::from(KEY_STR));
+        let cache = Arc::new(ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY));
+        let mut responder = ParityResponder::new(correct_key.clone(), None, Some(cache.clone()));
+        let parity = responder.answer(&shuffle_range(2, SEED, 40)).unwrap();
+        assert_eq!(1, cache.len());
+        // later requests of the shuffle use the shuffled key of the responder
+        let _ = responder.answer(&shuffle_range(2, SEED, 63)).unwrap();
+        assert_eq!(1, cache.stats().misses);
+        assert_eq!(0, cache.stats().hits);
+
+        let mut no_cache = ParityResponder::new(correct_key, None, None);
+        assert_eq!(Ok(parity), no_cache.answer(&shuffle_range(2, SEED, 40)));
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn test_no_seed_falls_back_to_bit_list() {
+        let shuffle = Shuffle::from_shuffled_to_orig_map(2, vec![2, 0, 3, 1]).unwrap();
+        let request = ParityRequest::new(ParityRequestEncoding::ShuffleSeed, &shuffle, 1, 2);
+        assert_eq!(ParityRequest::BitList(vec![0, 3]), request);
+    }
+
+    #[test]
+    fn test_answers_agree() {
+        let correct_key = Arc::new(Key::from(KEY_STR));
+        let mut channel = SimulatedParityChannel::new(correct_key.clone());
+        for backend in [ShuffleBackend::Table, ShuffleBackend::Feistel] {
+            let shuffle =
+                Shuffle::new_shuffle_from_seed_with_backend(3, 64, SEED, backend, false).unwrap();
+            let shuffled_key = correct_key.shuffled(&shuffle);
+            let ranges = [(0, 63), (5, 5), (10, 40), (32, 63)];
+            let parities: Vec<u8> = ranges
+                .iter()
+                .map(|&(start_bit_nr, end_bit_nr)| {
+                    shuffled_key.compute_range_parity(start_bit_nr, end_bit_nr)
+                })
+                .collect();
+            for encoding in [
+                ParityRequestEncoding::BitList,
+                ParityRequestEncoding::ShuffleSeed,
+            ] {
+                let requests: Vec<ParityRequest> = ranges
+                    .iter()
+                    .map(|&(start_bit_nr, end_bit_nr)| {
+                        ParityRequest::new(encoding, &shuffle, start_bit_nr, end_bit_nr)
+                    })
+                    .collect();
+                assert_eq!(
+                    Ok(parities.clone()),
+                    channel.ask_correct_parities(&requests)
+                );
+            }
+        }
+        // a request the responder rejects fails the round trip
+        assert_eq!(
+            Err(Error::BitOutOfRange {
+                bit_nr: 64,
+                nr_bits: 64
+            }),
+            channel.ask_correct_parities(&[ParityRequest::BitList(vec![0, 64])])
+        );
+    }
+
+    #[test]
+    fn test_wire_stats_of_round_trip() {
+        let shuffle = Shuffle::new_shuffle_from_seed(3, 64, SEED, false).unwrap();
+        let ranges = [(0, 63), (5, 5), (10, 40), (32, 63)];
+        let stats = |encoding| {
+            let requests: Vec<ParityRequest> = ranges
+                .iter()
+                .map(|&(start_bit_nr, end_bit_nr)| {
+                    ParityRequest::new(encoding, &shuffle, start_bit_nr, end_bit_nr)
+                })
+                .collect();
+            WireStats::of_round_trip(&requests)
+        };
+        let bit_list = stats(ParityRequestEncoding::BitList);
+        assert_eq!(4, bit_list.nr_requests);
+        assert_eq!(1, bit_list.nr_round_trips);
+        assert_eq!(4 * 5 + 4 * (64 + 1 + 31 + 32), bit_list.request_bytes);
+        assert_eq!(4, bit_list.reply_bytes);
+        let shuffle_seed = stats(ParityRequestEncoding::ShuffleSeed);
+        assert_eq!(4 * 21, shuffle_seed.request_bytes);
+        assert_eq!(4 * 21 + 4, shuffle_seed.total_bytes());
+
+        // nothing asked, no round trip
+        assert_eq!(WireStats::default(), WireStats::of_round_trip(&[]));
+    }
+}
@@ -0,0 +1,117 @@
+//! Queue of the blocks waiting to be corrected.
+//!
+//! The blocks come out in the [`CorrectionOrder`] of the queue, blocks of the same priority in the
+//! order they were pushed, so that a reconciliation is reproducible.
+
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// Order in which the pending blocks with an odd number of errors are corrected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorrectionOrder {
+    /// Smallest block first, as cascade-python: the binary search of a small block asks
+    /// fewer parities, and the corrected bit may fix the larger blocks containing it.
+    #[default]
+    SmallestFirst,
+    LargestFirst,
+    /// In order of scheduling.
+    Fifo,
+}
+
+// priority, order of pushing, item; the smallest comes out first
+struct Entry<T> {
+    priority: u32,
+    nr_pushed: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.priority, self.nr_pushed) == (other.priority, other.nr_pushed)
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.priority, self.nr_pushed).cmp(&(other.priority, other.nr_pushed))
+    }
+}
+
+/// Priority queue of the pending blocks, `T` identifies a block.
+pub struct CorrectionQueue<T> {
+    order: CorrectionOrder,
+    entries: BinaryHeap<Reverse<Entry<T>>>,
+    nr_pushed: u64,
+}
+
+impl<T> CorrectionQueue<T> {
+    pub fn new(order: CorrectionOrder) -> Self {
+        Self {
+            order,
+            entries: BinaryHeap::new(),
+            nr_pushed: 0,
+        }
+    }
+
+    pub fn get_order(&self) -> CorrectionOrder {
+        self.order
+    }
+
+    /// Push a block of `nr_bits` bits.
+    pub fn push(&mut self, nr_bits: u32, item: T) {
+        let priority = match self.order {
+            CorrectionOrder::SmallestFirst => nr_bits,
+            CorrectionOrder::LargestFirst => u32::MAX - nr_bits,
+            CorrectionOrder::Fifo => 0,
+        };
+        self.nr_pushed += 1;
+        self.entries.push(Reverse(Entry {
+            priority,
+            nr_pushed: self.nr_pushed,
+            item,
+        }));
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.entries.pop().map(|Reverse(entry)| entry.item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CorrectionOrder, CorrectionQueue};
+
+    #[test]
+    fn test_orders() {
+        // block sizes, the item is the order of pushing
+        const NR_BITS: [u32; 5] = [8, 2, 8, 4, 2];
+        let pop_all = |order| {
+            let mut queue = CorrectionQueue::new(order);
+            for (item, nr_bits) in NR_BITS.into_iter().enumerate() {
+                queue.push(nr_bits, item);
+            }
+            assert_eq!(NR_BITS.len(), queue.len());
+            let items: Vec<usize> = std::iter::from_fn(|| queue.pop()).collect();
+            assert!(queue.is_empty());
+            items
+        };
+        assert_eq!(vec![1, 4, 3, 0, 2], pop_all(CorrectionOrder::SmallestFirst));
+        assert_eq!(vec![0, 2, 3, 1, 4], pop_all(CorrectionOrder::LargestFirst));
+        assert_eq!(vec![0, 1, 2, 3, 4], pop_all(CorrectionOrder::Fifo));
+    }
+}
@@ -1,3 +1,12 @@
+//! Default random generator of this thread.
+//!
+//! Functions that need randomness take the generator as an argument, e.g.
+//! [`Key::apply_noise_with_rng`](crate::key::Key::apply_noise_with_rng) or
+//! [`Shuffle::new_random_shuffle_with_rng`](crate::shuffle::Shuffle::new_random_shuffle_with_rng).
+//! The variants without a generator use the generator of this thread, which is seeded from the
+//! operating system unless [`set_random_seed`] is called, so a whole run can be reproduced from
+//! one seed. `StdRng` is cryptographically secure, it is also used where the choices must not be
+//! predictable.
 use std::cell::RefCell;
 
 use rand::distributions::Uniform;
@@ -10,18 +19,23 @@ thread_local! {
     static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
 }
 
-pub(crate) fn set_random_uint32_seed(seed: u32) {
+/// Reseed the generator of this thread.
+pub fn set_random_seed(seed: u64) {
     RNG.with(|rng| {
-        let new_rng = StdRng::seed_from_u64(seed as u64);
-        rng.replace(new_rng);
+        rng.replace(StdRng::seed_from_u64(seed));
     });
 }
 
-pub(crate) fn random_uint32() -> u32 {
-    RNG.with(|rng| rng.borrow_mut().gen())
+/// Run `f` with the generator of this thread.
+pub(crate) fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
 }
 
-pub(crate) fn random_bit_nr(start_bit_nr: u32, end_bit_nr: u32) -> u32 {
+pub(crate) fn random_bit_nr<R: Rng + ?Sized>(
+    rng: &mut R,
+    start_bit_nr: u32,
+    end_bit_nr: u32,
+) -> u32 {
     let distribution = Uniform::new_inclusive(start_bit_nr, end_bit_nr);
-    RNG.with(|rng| rng.borrow_mut().sample(distribution))
+    rng.sample(distribution)
 }
@@ -1,150 +1,641 @@
 //! This is the top module to implement cascade protocol to reconcile two keys.
 //!
 
-use std::rc::Rc;
+use std::{sync::Arc, time::Instant};
+
+use tracing::{debug, info, info_span, trace};
 
 use crate::{
-    algorithm::OriginalAlgorithm, iteration::Iteration, key::Key, shuffled_key::SharedKey,
+    algorithm::OriginalAlgorithm,
+    block::BlockId,
+    dot,
+    error::{Error, Result},
+    iteration::{Iteration, SearchStep},
+    key::Key,
+    observer::{NoObserver, ReconciliationObserver},
+    protocol::{ParityChannel, ParityRequest, SimulatedParityChannel, WireStats},
+    queue::CorrectionQueue,
+    seed::SeedSchedule,
+    stats::ReconciliationStats,
 };
 
+pub use crate::queue::CorrectionOrder;
+
+// iteration index, block, cascade index
+type PendingBlock = (usize, BlockId, usize);
+
+// the correct parities the reconciliation waits for
+enum AwaitedParities {
+    // of the top blocks of the next iteration
+    TopBlocks {
+        iteration_index: usize,
+        blocks: Vec<BlockId>,
+    },
+    // of the sub blocks of the current binary search
+    SubBlocks {
+        step: SearchStep,
+    },
+}
+
+/// What the reconciliation needs next, see [`Reconciliation::step`].
+#[derive(Debug)]
+pub enum ReconciliationStep {
+    /// Ask Alice the correct parities of the requests in one round trip, and give the replies
+    /// to [`Reconciliation::resume`].
+    AskParities(Vec<ParityRequest>),
+    /// All iterations are done.
+    Done(ReconciliationStats),
+}
+
+/// A reconciliation session.
+///
+/// The session owns the noise key while it corrects it, [`Reconciliation::into_noise_key`]
+/// gives it back. The session is `Send` and `Sync`, so it can run on a worker thread
+/// or in an async task.
+///
+/// Blocks with an odd number of errors wait in a priority queue, in every started iteration and
+/// at any level of the block trees. Correcting a bit schedules the blocks containing it in all
+/// started iterations, so cascading is iterative, without recursion.
+///
+/// [`Reconciliation::start_iterations`] asks the correct parities through a [`ParityChannel`].
+/// A session without the correct key drives the reconciliation with [`Reconciliation::step`]
+/// and [`Reconciliation::resume`] instead, sending the parity requests as messages.
 pub struct Reconciliation {
     iterations: Vec<Iteration<OriginalAlgorithm>>,
+    // the first iterations, whose top block parities are known
+    nr_started_iterations: usize,
+    // for the initial and residual bit errors of the stats, if known
+    correct_key: Option<Arc<Key>>,
+    initial_bit_errors: Option<u32>,
+    noise_key: Key,
+    parity_channel: Option<Box<dyn ParityChannel>>,
+    pending_blocks: CorrectionQueue<PendingBlock>,
+    // the pending block being corrected, and the block of its binary search
+    current_search: Option<(PendingBlock, BlockId)>,
+    awaited_parities: Option<AwaitedParities>,
+    start: Option<Instant>,
+    stats: Option<ReconciliationStats>,
+    corrected_bits_per_iteration: Vec<u32>,
+    corrected_bits_per_cascade: Vec<u32>,
+    observer: Box<dyn ReconciliationObserver>,
 }
 
 impl Reconciliation {
-    pub fn new(num_iterations: u32, correct_key: Rc<Key>, noise_key: SharedKey) -> Self {
+    /// Both sides create the seed schedule from the same session secret and nonce,
+    /// so that they use the same shuffle in every iteration.
+    pub fn new(
+        num_iterations: u32,
+        seed_schedule: &SeedSchedule,
+        correct_key: Arc<Key>,
+        noise_key: Key,
+    ) -> Self {
+        Self::with_algorithm(
+            num_iterations,
+            seed_schedule,
+            correct_key,
+            noise_key,
+            OriginalAlgorithm::default(),
+        )
+    }
+
+    /// Reconciliation whose iterations use the given algorithm configuration,
+    /// e.g. another [`Partitioner`](crate::partition::Partitioner).
+    ///
+    /// The correct parities are asked from a simulated Alice knowing `correct_key`, see
+    /// [`Reconciliation::with_parity_channel`].
+    pub fn with_algorithm(
+        num_iterations: u32,
+        seed_schedule: &SeedSchedule,
+        correct_key: Arc<Key>,
+        noise_key: Key,
+        algo: OriginalAlgorithm,
+    ) -> Self {
+        let mut reconciliation = Self::remote(num_iterations, seed_schedule, noise_key, algo)
+            .with_parity_channel(SimulatedParityChannel::new(correct_key.clone()));
+        reconciliation.initial_bit_errors =
+            Some(correct_key.nr_bits_different(&reconciliation.noise_key));
+        reconciliation.correct_key = Some(correct_key);
+        reconciliation
+    }
+
+    /// Reconciliation of Bob, who does not know the correct key.
+    ///
+    /// It has no parity channel, drive it with [`Reconciliation::step`] and
+    /// [`Reconciliation::resume`], or give it one with [`Reconciliation::with_parity_channel`].
+    /// Its stats have no initial and residual bit errors.
+    pub fn remote(
+        num_iterations: u32,
+        seed_schedule: &SeedSchedule,
+        noise_key: Key,
+        algo: OriginalAlgorithm,
+    ) -> Self {
+        // no shuffled copy needs the changes made so far
+        let mut noise_key = noise_key;
+        noise_key.compact_changes();
         let mut iterations = Vec::with_capacity(num_iterations as usize);
 
         for iteration_nr in 0..num_iterations {
             let iteration = Iteration::new(
                 iteration_nr + 1,
-                correct_key.clone(),
-                noise_key.clone(),
-                OriginalAlgorithm::default(),
-            );
+                seed_schedule.shuffle_seed(iteration_nr + 1),
+                &noise_key,
+                algo.clone(),
+            )
+            .expect("the iteration numbers start at 1");
             iterations.push(iteration);
         }
 
-        Self { iterations }
+        Self {
+            iterations,
+            nr_started_iterations: 0,
+            correct_key: None,
+            initial_bit_errors: None,
+            noise_key,
+            parity_channel: None,
+            pending_blocks: CorrectionQueue::new(CorrectionOrder::default()),
+            current_search: None,
+            awaited_parities: None,
+            start: None,
+            stats: None,
+            corrected_bits_per_iteration: vec![0; num_iterations as usize],
+            corrected_bits_per_cascade: Vec::new(),
+            observer: Box::new(NoObserver),
+        }
+    }
+
+    pub fn with_correction_order(mut self, correction_order: CorrectionOrder) -> Self {
+        self.pending_blocks = CorrectionQueue::new(correction_order);
+        self
+    }
+
+    /// Ask the correct parities through `parity_channel` in [`Reconciliation::start_iterations`].
+    pub fn with_parity_channel(mut self, parity_channel: impl ParityChannel + 'static) -> Self {
+        self.parity_channel = Some(Box::new(parity_channel));
+        self
+    }
+
+    /// Report the events of the reconciliation to `observer`, see [`observer`](crate::observer).
+    pub fn with_observer(mut self, observer: impl ReconciliationObserver + 'static) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    /// The noise key, as corrected so far.
+    pub fn get_noise_key(&self) -> &Key {
+        &self.noise_key
+    }
+
+    /// End the session and take back the corrected noise key.
+    pub fn into_noise_key(self) -> Key {
+        self.noise_key
     }
 
-    pub fn start_iterations(&self) {
-        for iter_nr in 0..self.iterations.len() {
-            let iteration = &self.iterations[iter_nr];
-            println!(
-                "--------- ITERATION {} ---------",
-                iteration.get_iteration_nr()
+    /// Run all iterations, and report what was asked, leaked and corrected.
+    ///
+    /// # Errors
+    ///
+    /// the error of the parity channel, or of its replies, see [`Reconciliation::resume`];
+    /// the reconciliation cannot continue then
+    ///
+    /// # Panics
+    ///
+    /// Panics if the reconciliation has no parity channel
+    pub fn start_iterations(&mut self) -> Result<ReconciliationStats> {
+        let mut parity_channel = self
+            .parity_channel
+            .take()
+            .expect("the reconciliation has a parity channel");
+        let result = self.run_iterations(parity_channel.as_mut());
+        self.parity_channel = Some(parity_channel);
+        result
+    }
+
+    fn run_iterations(
+        &mut self,
+        parity_channel: &mut dyn ParityChannel,
+    ) -> Result<ReconciliationStats> {
+        loop {
+            match self.step() {
+                ReconciliationStep::AskParities(requests) => {
+                    let correct_parities = parity_channel.ask_correct_parities(&requests)?;
+                    self.resume(&correct_parities)?;
+                }
+                ReconciliationStep::Done(stats) => return Ok(stats),
+            }
+        }
+    }
+
+    /// Correct the noise key until correct parities must be asked, or all iterations are done.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the replies of the last [`ReconciliationStep::AskParities`] were not given to
+    /// [`Reconciliation::resume`]
+    pub fn step(&mut self) -> ReconciliationStep {
+        assert!(
+            self.awaited_parities.is_none(),
+            "the correct parities asked are given to resume"
+        );
+        if let Some(stats) = &self.stats {
+            return ReconciliationStep::Done(stats.clone());
+        }
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let _span = info_span!(
+            "reconciliation",
+            nr_key_bits = self.noise_key.get_nr_bits(),
+            nr_iterations = self.iterations.len()
+        )
+        .entered();
+        loop {
+            // continue the binary search of the block being corrected
+            if let Some((pending_block, block)) = self.current_search {
+                if let Some(requests) = self.search(pending_block, block) {
+                    return ReconciliationStep::AskParities(requests);
+                }
+                continue;
+            }
+            if let Some((iteration_index, block, cascade_index)) = self.pending_blocks.pop() {
+                let iteration = &mut self.iterations[iteration_index];
+                // a bit corrected since the block was scheduled may have fixed it
+                iteration.sync(&self.noise_key);
+                if iteration.has_odd_error_parity(block) {
+                    self.current_search = Some(((iteration_index, block, cascade_index), block));
+                }
+                continue;
+            }
+            // the started iterations are done
+            self.compact_changes();
+            if self.nr_started_iterations == self.iterations.len() {
+                break;
+            }
+            let iteration_index = self.nr_started_iterations;
+            let iteration = &mut self.iterations[iteration_index];
+            debug!(
+                iteration_nr = iteration.get_iteration_nr(),
+                "start iteration"
             );
-            iteration.schedule_top_block_ask_correct_parity_task();
-            let corrected_orig_bits_nr = iteration.schedule_top_block_correct_task();
+            let blocks = iteration.start(self.observer.as_mut());
+            let requests = iteration.parity_requests(&blocks);
+            self.awaited_parities = Some(AwaitedParities::TopBlocks {
+                iteration_index,
+                blocks,
+            });
+            return ReconciliationStep::AskParities(requests);
+        }
+        let stats = self.get_stats(start.elapsed());
+        info!(
+            nr_asked_parities = stats.nr_asked_parities,
+            nr_corrected_bits = stats.nr_corrected_bits(),
+            "reconciliation finished"
+        );
+        self.observer.reconciliation_finished(&stats);
+        self.stats = Some(stats.clone());
+        ReconciliationStep::Done(stats)
+    }
 
-            self.cascade(iteration.get_iteration_nr(), corrected_orig_bits_nr);
+    /// Continue with the correct parities replied to the requests of the last
+    /// [`ReconciliationStep::AskParities`], in order.
+    ///
+    /// # Errors
+    ///
+    /// [`Error::MalformedMessage`] if no parities were asked, or the replies are not one parity
+    /// of 0 or 1 per request
+    pub fn resume(&mut self, correct_parities: &[u8]) -> Result<()> {
+        let Some(awaited_parities) = self.awaited_parities.take() else {
+            return Err(Error::MalformedMessage("no parities were asked"));
+        };
+        let (iteration_index, blocks) = match &awaited_parities {
+            AwaitedParities::TopBlocks {
+                iteration_index,
+                blocks,
+            } => (*iteration_index, blocks.as_slice()),
+            AwaitedParities::SubBlocks { step } => {
+                let ((iteration_index, _, _), _) = self
+                    .current_search
+                    .expect("the sub blocks are of the current search");
+                (iteration_index, step.get_blocks_to_ask())
+            }
+        };
+        // nothing is set on invalid replies, they can be given again
+        if let Err(error) = self.iterations[iteration_index].set_asked_parities(
+            blocks,
+            correct_parities,
+            self.observer.as_mut(),
+        ) {
+            self.awaited_parities = Some(awaited_parities);
+            return Err(error);
+        }
+        match awaited_parities {
+            AwaitedParities::TopBlocks {
+                iteration_index, ..
+            } => {
+                let iteration = &self.iterations[iteration_index];
+                self.nr_started_iterations = iteration_index + 1;
+                let top_blocks: Vec<BlockId> = iteration.get_top_blocks().collect();
+                for top_block in top_blocks {
+                    self.schedule_try_correct(iteration_index, top_block, None);
+                }
+            }
+            AwaitedParities::SubBlocks { step } => self.finish_search_step(step),
         }
+        Ok(())
     }
 
-    pub fn cascade(&self, trigger_iteration_nr: u32, corrected_orig_bits_nr: Vec<u32>) {
-        // cascade to other iterations
-        let cascade_iterations = self.iterations.iter().filter(|cascade_iteration| {
-            cascade_iteration.get_iteration_nr() < trigger_iteration_nr
-            // && cascade_iteration.is_started()
+    /// # Arguments
+    /// cascade_index: the cascade scheduling the block, `None` to start a new one
+    ///
+    /// # Returns
+    ///
+    /// whether the block has an odd number of errors and was scheduled
+    fn schedule_try_correct(
+        &mut self,
+        iteration_index: usize,
+        block: BlockId,
+        cascade_index: Option<usize>,
+    ) -> bool {
+        let iteration = &mut self.iterations[iteration_index];
+        iteration.sync(&self.noise_key);
+        if !iteration.has_odd_error_parity(block) {
+            return false;
+        }
+        let nr_bits = iteration.get_blocks().get_nr_bits(block);
+        let cascade_index = cascade_index.unwrap_or_else(|| {
+            self.corrected_bits_per_cascade.push(0);
+            self.corrected_bits_per_cascade.len() - 1
         });
-        let other_iterations = cascade_iterations
-            .clone()
-            .map(|it| it.get_iteration_nr().to_string())
-            .reduce(|a, b| format!("{}, {},", a, b));
-        match other_iterations {
-            Some(other_iterations) => {
-                println!("cascade to Iteration {}", other_iterations,);
+        trace!(
+            iteration_nr = iteration.get_iteration_nr(),
+            start_bit_nr = iteration.get_blocks().get_start_bit_nr(block),
+            end_bit_nr = iteration.get_blocks().get_end_bit_nr(block),
+            cascade_index,
+            "schedule block with an odd number of errors"
+        );
+        self.pending_blocks
+            .push(nr_bits, (iteration_index, block, cascade_index));
+        true
+    }
+
+    /// Continue the binary search of a pending block, down to the bit to correct.
+    ///
+    /// # Returns
+    ///
+    /// the requests of the correct parities of the sub blocks, if any must be asked
+    fn search(
+        &mut self,
+        pending_block: PendingBlock,
+        block: BlockId,
+    ) -> Option<Vec<ParityRequest>> {
+        let (iteration_index, _, cascade_index) = pending_block;
+        let iteration = &mut self.iterations[iteration_index];
+        if iteration.get_blocks().get_nr_bits(block) == 1 {
+            let orig_bit_nr =
+                iteration.correct_bit(&mut self.noise_key, block, self.observer.as_mut());
+            debug!(orig_bit_nr, "corrected bit");
+            self.current_search = None;
+            self.corrected_bits_per_iteration[iteration_index] += 1;
+            self.corrected_bits_per_cascade[cascade_index] += 1;
+            self.cascade(iteration_index, orig_bit_nr, cascade_index);
+            return None;
+        }
+        let step = iteration.start_search_step(&self.noise_key, block);
+        if step.get_blocks_to_ask().is_empty() {
+            self.finish_search_step(step);
+            return None;
+        }
+        let requests = iteration.parity_requests(step.get_blocks_to_ask());
+        self.awaited_parities = Some(AwaitedParities::SubBlocks { step });
+        Some(requests)
+    }
+
+    // continue the current search with the sub block with an odd number of errors
+    fn finish_search_step(&mut self, step: SearchStep) {
+        let (pending_block, _) = self
+            .current_search
+            .expect("the step is of the current search");
+        let iteration = &mut self.iterations[pending_block.0];
+        let block = iteration.finish_search_step(step, self.observer.as_mut());
+        self.current_search = Some((pending_block, block));
+    }
+
+    /// Schedule the blocks containing a corrected bit in all started iterations.
+    ///
+    /// The block containing the bit now has an odd error parity at every level of the block tree,
+    /// unless another error in it was corrected too.
+    ///
+    /// The recursive cascade of the original code only went to iterations earlier than the one
+    /// correcting the bit. That misses blocks: a bit corrected in iteration 1 while cascading from
+    /// iteration 3 also flips the parity of its block in iterations 2 and 3, which were started
+    /// and would keep an odd error parity. Every started iteration is checked instead; in the one
+    /// correcting the bit the blocks containing it are even again and are not scheduled. An
+    /// iteration not started yet knows no correct parities, it checks its top blocks when it starts.
+    fn cascade(&mut self, from_iteration_index: usize, orig_bit_nr: u32, cascade_index: usize) {
+        for iteration_index in 0..self.nr_started_iterations {
+            let blocks =
+                self.iterations[iteration_index].get_blocks_containing_orig_bit(orig_bit_nr);
+            let mut scheduled = false;
+            for block in blocks {
+                if self.iterations[iteration_index]
+                    .get_blocks()
+                    .get_correct_parity(block)
+                    .is_some()
+                {
+                    scheduled |=
+                        self.schedule_try_correct(iteration_index, block, Some(cascade_index));
+                }
             }
-            None => {
-                println!("no other iterations to cascade");
+            if scheduled && iteration_index != from_iteration_index {
+                self.observer.cascade_triggered(
+                    self.iterations[from_iteration_index].get_iteration_nr(),
+                    self.iterations[iteration_index].get_iteration_nr(),
+                    orig_bit_nr,
+                );
             }
         }
+    }
 
-        for orig_bit_nr in corrected_orig_bits_nr {
-            cascade_iterations.clone().for_each(|cascade_iteration| {
-                println!(
-                    "cascade to Iteration {}, orig bit nr: {}",
-                    cascade_iteration.get_iteration_nr(),
-                    orig_bit_nr
-                );
-                for top_block in cascade_iteration.get_top_blocks() {
-                    let bit_nr = cascade_iteration
-                        .get_shuffled_key()
-                        .orig_to_shuffle_bit_nr(orig_bit_nr);
-
-                    if top_block.contains_bit(bit_nr) {
-                        println!(
-                            "cascade to Iteration {}, trigger Iteration {},
-                                        block: {},
-                                        flip parity downstream, shuffle bit nr: {}",
-                            cascade_iteration.get_iteration_nr(),
-                            trigger_iteration_nr,
-                            top_block,
-                            bit_nr
-                        );
-                        // to reduce re-computation
-                        cascade_iteration.flip_parity_downstream(top_block, bit_nr);
-                        // rely on parity flip is correctly done
-                        let more_bit_nrs = cascade_iteration.schedule_top_block_correct_task();
-                        self.cascade(cascade_iteration.get_iteration_nr(), more_bit_nrs);
-                    }
-                }
-            });
+    /// Sync the shuffled keys of all iterations, then forget the changes of the noise key.
+    fn compact_changes(&mut self) {
+        for iteration in &mut self.iterations {
+            iteration.sync(&self.noise_key);
+        }
+        self.noise_key.compact_changes();
+    }
+
+    /// Bytes on the wire of the parity requests of all iterations.
+    pub fn get_wire_stats(&self) -> WireStats {
+        let mut wire_stats = WireStats::default();
+        for iteration in &self.iterations {
+            wire_stats += iteration.get_wire_stats();
+        }
+        wire_stats
+    }
+
+    /// Statistics of the reconciliation so far.
+    fn get_stats(&self, elapsed: std::time::Duration) -> ReconciliationStats {
+        let wire_stats = self.get_wire_stats();
+        let nr_inferred_parities = self
+            .iterations
+            .iter()
+            .map(|iteration| {
+                let blocks = iteration.get_blocks();
+                blocks
+                    .get_all_blocks()
+                    .filter(|&block| blocks.was_inferred(block))
+                    .count() as u64
+            })
+            .sum();
+        ReconciliationStats {
+            nr_key_bits: self.noise_key.get_nr_bits(),
+            estimated_ber: self.noise_key.get_estimated_ber(),
+            nr_iterations: self.iterations.len() as u32,
+            elapsed,
+            nr_asked_parities: wire_stats.nr_requests,
+            nr_inferred_parities,
+            // a request and a reply per round trip
+            nr_messages: 2 * wire_stats.nr_round_trips,
+            nr_round_trips: wire_stats.nr_round_trips,
+            wire_stats,
+            corrected_bits_per_iteration: self.corrected_bits_per_iteration.clone(),
+            corrected_bits_per_cascade: self.corrected_bits_per_cascade.clone(),
+            initial_bit_errors: self.initial_bit_errors,
+            residual_bit_errors: self
+                .correct_key
+                .as_ref()
+                .map(|correct_key| correct_key.nr_bits_different(&self.noise_key)),
         }
     }
+
+    /// DOT graph of the block trees of all iterations, see [`dot`].
+    pub fn to_dot(&mut self) -> String {
+        for iteration in &mut self.iterations {
+            iteration.sync(&self.noise_key);
+        }
+        dot::graph(|out| {
+            for iteration in &self.iterations {
+                iteration.write_dot(out)?;
+            }
+            Ok(())
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{cell::RefCell, rc::Rc};
+    use std::sync::{Arc, Mutex};
+
+    use rand::{rngs::StdRng, SeedableRng};
+    use rayon::prelude::*;
 
     use crate::{
         algorithm::OriginalAlgorithm,
-        block::{Block, BlockType},
+        error::{Error, Result},
         key::Key,
-        reconciliation::Reconciliation,
-        shuffle::Shuffle,
-        shuffled_key::{SharedKey, ShuffledKey},
+        observer::ReconciliationObserver,
+        partition::Partitioner,
+        protocol::{ParityChannel, ParityRequest, SimulatedParityChannel},
+        reconciliation::{CorrectionOrder, Reconciliation, ReconciliationStep},
+        seed::SeedSchedule,
+        stats::{binary_entropy, ReconciliationStats},
     };
 
-    use super::Iteration;
-    fn create_test_shuffled_key(key_str: &str) -> (Rc<Key>, SharedKey) {
-        const SEED: u64 = 0x1234567890ABCDEF;
+    // the noise is reproducible from the seed
+    fn create_test_shuffled_key(key_str: &str, noise_seed: u64) -> (Arc<Key>, Key) {
         // correct key
         let correct_key = Key::from(key_str);
         // noise key from file
         let mut noise_key = correct_key.clone();
         noise_key.set_estimated_ber(0.1); // 10% BER, about 3 errors
-        noise_key.apply_noise();
+        noise_key.apply_noise_with_rng(&mut StdRng::seed_from_u64(noise_seed));
 
-        assert_ne!(correct_key.to_string(), noise_key.to_string());
-        (Rc::new(correct_key), Rc::new(RefCell::new(noise_key)))
-    }
-
-    fn print_keys(correct_key: &Rc<Key>, noise_key: &SharedKey) {
-        println!("correct key: {}", correct_key.to_string());
-        println!("noise key:   {}", noise_key.borrow().to_string());
+        assert_ne!(
+            correct_key.reveal().to_string(),
+            noise_key.reveal().to_string()
+        );
+        (Arc::new(correct_key), noise_key)
     }
 
     #[test]
     fn test_reconciliation() {
         const NUM_ITERATIONS: u32 = 4;
         const KEY_STR: &str = "10010001100100011001000110010001";
+        const NOISE_SEED: u64 = 1;
         assert_eq!(KEY_STR.len(), 32);
 
-        let (correct_key, noise_key) = create_test_shuffled_key(KEY_STR);
+        let (correct_key, noise_key) = create_test_shuffled_key(KEY_STR, NOISE_SEED);
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let initial_bit_errors = correct_key.nr_bits_different(&noise_key);
+        let mut reconciliation = Reconciliation::new(
+            NUM_ITERATIONS,
+            &seed_schedule,
+            correct_key.clone(),
+            noise_key,
+        );
+        let stats = reconciliation.start_iterations().unwrap();
+        assert_eq!(Some(0), stats.residual_bit_errors);
+        // every correction fixes an error
+        assert_eq!(initial_bit_errors, stats.nr_corrected_bits());
+        assert_eq!(
+            stats.nr_corrected_bits(),
+            stats.corrected_bits_per_cascade.iter().sum::<u32>()
+        );
+        assert_eq!(
+            NUM_ITERATIONS as usize,
+            stats.corrected_bits_per_iteration.len()
+        );
+        assert_eq!(
+            reconciliation.get_wire_stats().nr_requests,
+            stats.nr_leaked_bits()
+        );
+        assert_eq!(2 * stats.nr_round_trips, stats.nr_messages);
+        assert!(stats.nr_inferred_parities > 0);
+        assert_eq!(Some(initial_bit_errors), stats.initial_bit_errors);
+        // the Shannon limit of the real number of errors
+        assert_eq!(
+            32.0 * binary_entropy(f64::from(initial_bit_errors) / 32.0),
+            stats.shannon_limit()
+        );
+        assert_eq!(
+            stats.nr_leaked_bits() as f64 / stats.shannon_limit(),
+            stats.efficiency()
+        );
+        let dot = reconciliation.to_dot();
+        for iteration_nr in 1..=NUM_ITERATIONS {
+            assert!(dot.contains(&format!("subgraph cluster_iteration_{} {{", iteration_nr)));
+        }
+        assert!(dot.contains("corrected bit: "));
+        let noise_key = reconciliation.into_noise_key();
+        assert_eq!(
+            correct_key.reveal().to_string(),
+            noise_key.reveal().to_string()
+        );
+    }
+
+    #[test]
+    fn test_iterations_use_different_shuffles() {
+        const NUM_ITERATIONS: u32 = 4;
+        const KEY_STR: &str = "10010001100100011001000110010001";
+        let (correct_key, noise_key) = create_test_shuffled_key(KEY_STR, 2);
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
         let reconciliation =
-            Reconciliation::new(NUM_ITERATIONS, correct_key.clone(), noise_key.clone());
-        print_keys(&correct_key, &noise_key);
-        reconciliation.start_iterations();
-        print_keys(&correct_key, &noise_key);
+            Reconciliation::new(NUM_ITERATIONS, &seed_schedule, correct_key, noise_key);
 
-        println!(
-            "bit differences: {}",
-            correct_key.nr_bits_different(&*noise_key.borrow())
-        );
-        assert_eq!(correct_key.to_string(), noise_key.borrow().to_string());
+        let permutations: Vec<Vec<u32>> = reconciliation
+            .iterations
+            .iter()
+            .map(|iteration| {
+                let shuffled_key = iteration.get_shuffled_key();
+                (0..shuffled_key.get_nr_bits())
+                    .map(|bit_nr| shuffled_key.shuffle_to_orig_bit_nr(bit_nr))
+                    .collect()
+            })
+            .collect();
+        // iteration 1 is not shuffled, all later iterations have their own shuffle
+        assert_eq!((0..32).collect::<Vec<u32>>(), permutations[0]);
+        for (i, permutation) in permutations.iter().enumerate() {
+            for other_permutation in &permutations[i + 1..] {
+                assert_ne!(permutation, other_permutation);
+            }
+        }
     }
 
     #[test]
@@ -154,21 +645,328 @@ mod tests {
             "100100011001000110010100011001000101000110010001010001100100011100010001".repeat(200);
         assert_eq!(key_str.len(), 14400);
 
-        let (correct_key, noise_key) = create_test_shuffled_key(&key_str);
+        let (correct_key, noise_key) = create_test_shuffled_key(&key_str, 3);
 
-        let initial_bit_err = correct_key.nr_bits_different(&*noise_key.borrow());
-        let reconciliation =
-            Reconciliation::new(NUM_ITERATIONS, correct_key.clone(), noise_key.clone());
-        // print_keys(&correct_key, &noise_key);
-        reconciliation.start_iterations();
-        // print_keys(&correct_key, &noise_key);
+        let initial_bit_err = correct_key.nr_bits_different(&noise_key);
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let mut reconciliation = Reconciliation::new(
+            NUM_ITERATIONS,
+            &seed_schedule,
+            correct_key.clone(),
+            noise_key,
+        );
+        reconciliation.start_iterations().unwrap();
+        let noise_key = reconciliation.get_noise_key();
 
-        let final_bit_err = correct_key.nr_bits_different(&*noise_key.borrow());
+        let final_bit_err = correct_key.nr_bits_different(noise_key);
 
-        println!(
-            "bit differences: initial: {}, final: {}",
-            initial_bit_err, final_bit_err
+        assert_ne!(0, initial_bit_err);
+        assert_eq!(0, final_bit_err);
+        assert_eq!(
+            correct_key.reveal().to_string(),
+            noise_key.reveal().to_string()
         );
-        assert_eq!(correct_key.to_string(), noise_key.borrow().to_string());
+    }
+
+    #[test]
+    fn test_cascade_to_all_started_iterations() {
+        // few iterations leave residual errors, in blocks with an even number of errors
+        const NUM_ITERATIONS: u32 = 2;
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let mut nr_residual_errors = 0;
+        for noise_seed in 0..10 {
+            let (correct_key, noise_key) = create_test_shuffled_key(&test_key_str(), noise_seed);
+            let mut reconciliation =
+                Reconciliation::new(NUM_ITERATIONS, &seed_schedule, correct_key, noise_key);
+            let stats = reconciliation.start_iterations().unwrap();
+            nr_residual_errors += stats.residual_bit_errors.unwrap();
+            // no block with a known correct parity is left with an odd number of errors
+            for iteration in &mut reconciliation.iterations {
+                iteration.sync(&reconciliation.noise_key);
+                let blocks = iteration.get_blocks();
+                for block in blocks.get_all_blocks() {
+                    if blocks.get_correct_parity(block).is_some() {
+                        assert!(!iteration.has_odd_error_parity(block));
+                    }
+                }
+            }
+        }
+        assert!(nr_residual_errors > 0);
+    }
+
+    // 1440 bits, with enough errors at 10% noise to cascade between iterations
+    fn test_key_str() -> String {
+        "100100011001000110010100011001000101000110010001010001100100011100010001".repeat(20)
+    }
+
+    /// Reconciliation of the test key in 6 iterations, with the noise of seed 4.
+    fn create_test_reconciliation(algorithm: OriginalAlgorithm) -> (Arc<Key>, Reconciliation) {
+        const NUM_ITERATIONS: u32 = 6;
+        let (correct_key, noise_key) = create_test_shuffled_key(&test_key_str(), 4);
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let reconciliation = Reconciliation::with_algorithm(
+            NUM_ITERATIONS,
+            &seed_schedule,
+            correct_key.clone(),
+            noise_key,
+            algorithm,
+        );
+        (correct_key, reconciliation)
+    }
+
+    // the corrected bits, in order
+    #[derive(Default)]
+    struct RecordingObserver {
+        corrected_bits: Vec<(u32, u32)>,
+    }
+
+    impl ReconciliationObserver for RecordingObserver {
+        fn bit_corrected(&mut self, iteration_nr: u32, orig_bit_nr: u32) {
+            self.corrected_bits.push((iteration_nr, orig_bit_nr));
+        }
+    }
+
+    #[test]
+    fn test_correction_orders() {
+        let mut corrected_bits = Vec::new();
+        for correction_order in [
+            CorrectionOrder::SmallestFirst,
+            CorrectionOrder::LargestFirst,
+            CorrectionOrder::Fifo,
+        ] {
+            let observer = Arc::new(Mutex::new(RecordingObserver::default()));
+            let (correct_key, reconciliation) =
+                create_test_reconciliation(OriginalAlgorithm::default());
+            let mut reconciliation = reconciliation
+                .with_correction_order(correction_order)
+                .with_observer(observer.clone());
+            reconciliation.start_iterations().unwrap();
+            assert_ne!(0, reconciliation.get_wire_stats().nr_requests);
+            assert_eq!(
+                0,
+                correct_key.nr_bits_different(reconciliation.get_noise_key())
+            );
+            let observer = observer.lock().unwrap();
+            corrected_bits.push(observer.corrected_bits.clone());
+        }
+        // the orders correct the same bits, in different sequences
+        let sorted_bit_nrs = |corrected: &[(u32, u32)]| {
+            let mut bit_nrs: Vec<u32> = corrected.iter().map(|&(_, bit_nr)| bit_nr).collect();
+            bit_nrs.sort_unstable();
+            bit_nrs
+        };
+        for (index, corrected) in corrected_bits.iter().enumerate() {
+            assert_eq!(
+                sorted_bit_nrs(&corrected_bits[0]),
+                sorted_bit_nrs(corrected)
+            );
+            for other in &corrected_bits[index + 1..] {
+                assert_ne!(corrected, other);
+            }
+        }
+    }
+
+    #[test]
+    fn test_partitioners() {
+        for partitioner in [
+            Partitioner::FixedSize,
+            Partitioner::EqualSize,
+            Partitioner::RandomOffset,
+        ] {
+            let (_, mut reconciliation) = create_test_reconciliation(
+                OriginalAlgorithm::default().with_partitioner(partitioner),
+            );
+            let stats = reconciliation.start_iterations().unwrap();
+            // every partitioner reconciles the key, at its own leakage
+            assert_ne!(0, stats.nr_leaked_bits());
+            assert_eq!(Some(0), stats.residual_bit_errors);
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingObserver {
+        nr_started_iterations: u32,
+        nr_asked_parities: u64,
+        nr_inferred_parities: u64,
+        nr_corrected_bits: u32,
+        nr_cascades_into_earlier_iterations: u32,
+        nr_finished: u32,
+    }
+
+    impl ReconciliationObserver for CountingObserver {
+        fn iteration_started(&mut self, iteration_nr: u32) {
+            self.nr_started_iterations += 1;
+            assert_eq!(self.nr_started_iterations, iteration_nr);
+        }
+
+        fn top_block_parity_asked(&mut self, _: u32, _: u32, _: u32, _: u8) {
+            self.nr_asked_parities += 1;
+        }
+
+        fn sub_block_parity_asked(&mut self, _: u32, _: u32, _: u32, _: u8) {
+            self.nr_asked_parities += 1;
+        }
+
+        fn sub_block_parity_inferred(&mut self, _: u32, _: u32, _: u32, _: u8) {
+            self.nr_inferred_parities += 1;
+        }
+
+        fn bit_corrected(&mut self, iteration_nr: u32, _: u32) {
+            assert!(iteration_nr <= self.nr_started_iterations);
+            self.nr_corrected_bits += 1;
+        }
+
+        fn cascade_triggered(&mut self, from_iteration_nr: u32, into_iteration_nr: u32, _: u32) {
+            assert_ne!(from_iteration_nr, into_iteration_nr);
+            if into_iteration_nr < from_iteration_nr {
+                self.nr_cascades_into_earlier_iterations += 1;
+            }
+        }
+
+        fn reconciliation_finished(&mut self, stats: &ReconciliationStats) {
+            self.nr_finished += 1;
+            assert_eq!(Some(0), stats.residual_bit_errors);
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let observer = Arc::new(Mutex::new(CountingObserver::default()));
+        let (_, reconciliation) = create_test_reconciliation(OriginalAlgorithm::default());
+        let mut reconciliation = reconciliation.with_observer(observer.clone());
+        let stats = reconciliation.start_iterations().unwrap();
+
+        let observer = observer.lock().unwrap();
+        assert_eq!(6, observer.nr_started_iterations);
+        assert_eq!(stats.nr_asked_parities, observer.nr_asked_parities);
+        assert_eq!(stats.nr_inferred_parities, observer.nr_inferred_parities);
+        assert_eq!(stats.nr_corrected_bits(), observer.nr_corrected_bits);
+        assert!(observer.nr_cascades_into_earlier_iterations > 0);
+        assert_eq!(1, observer.nr_finished);
+    }
+
+    #[test]
+    fn test_step_and_resume() {
+        const NUM_ITERATIONS: u32 = 4;
+        let (correct_key, noise_key) = create_test_shuffled_key(&test_key_str(), 4);
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let mut simulated = Reconciliation::new(
+            NUM_ITERATIONS,
+            &seed_schedule,
+            correct_key.clone(),
+            noise_key.clone(),
+        );
+        let simulated_stats = simulated.start_iterations().unwrap();
+
+        // Bob without the correct key, Alice answers every step
+        let mut reconciliation = Reconciliation::remote(
+            NUM_ITERATIONS,
+            &seed_schedule,
+            noise_key,
+            OriginalAlgorithm::default(),
+        );
+        assert_eq!(
+            Err(Error::MalformedMessage("no parities were asked")),
+            reconciliation.resume(&[0])
+        );
+        let mut alice = SimulatedParityChannel::new(correct_key.clone());
+        let mut nr_round_trips = 0;
+        let stats = loop {
+            match reconciliation.step() {
+                ReconciliationStep::AskParities(requests) => {
+                    assert_eq!(
+                        Err(Error::MalformedMessage("not one parity per request")),
+                        reconciliation.resume(&vec![0; requests.len() + 1])
+                    );
+                    assert_eq!(
+                        Err(Error::MalformedMessage("parity is not 0 or 1")),
+                        reconciliation.resume(&vec![2; requests.len()])
+                    );
+                    let correct_parities = alice.ask_correct_parities(&requests).unwrap();
+                    reconciliation.resume(&correct_parities).unwrap();
+                    nr_round_trips += 1;
+                }
+                ReconciliationStep::Done(stats) => break stats,
+            }
+        };
+        assert_eq!(nr_round_trips, stats.nr_round_trips);
+        assert_eq!(simulated_stats.wire_stats, stats.wire_stats);
+        assert_eq!(
+            simulated_stats.corrected_bits_per_cascade,
+            stats.corrected_bits_per_cascade
+        );
+        assert_eq!(None, stats.initial_bit_errors);
+        assert_eq!(None, stats.residual_bit_errors);
+        assert_eq!(
+            simulated.get_noise_key().reveal().to_string(),
+            reconciliation.get_noise_key().reveal().to_string()
+        );
+        // done only once
+        assert!(matches!(reconciliation.step(), ReconciliationStep::Done(_)));
+    }
+
+    // replies one parity short
+    struct ShortChannel(SimulatedParityChannel);
+
+    impl ParityChannel for ShortChannel {
+        fn ask_correct_parities(&mut self, requests: &[ParityRequest]) -> Result<Vec<u8>> {
+            let mut correct_parities = self.0.ask_correct_parities(requests)?;
+            correct_parities.pop();
+            Ok(correct_parities)
+        }
+    }
+
+    #[test]
+    fn test_parity_channel_errors() {
+        let (correct_key, noise_key) = create_test_shuffled_key(&test_key_str(), 4);
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        let mut reconciliation =
+            Reconciliation::remote(4, &seed_schedule, noise_key, OriginalAlgorithm::default())
+                .with_parity_channel(ShortChannel(SimulatedParityChannel::new(correct_key)));
+        assert_eq!(
+            Err(Error::MalformedMessage("not one parity per request")),
+            reconciliation.start_iterations()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "the reconciliation has a parity channel")]
+    fn test_remote_needs_parity_channel() {
+        let (_, noise_key) = create_test_shuffled_key(&test_key_str(), 4);
+        let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+        Reconciliation::remote(4, &seed_schedule, noise_key, OriginalAlgorithm::default())
+            .start_iterations()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_reconciliation_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Reconciliation>();
+    }
+
+    #[test]
+    fn test_reconciliations_on_worker_threads() {
+        const NUM_ITERATIONS: u32 = 4;
+        const KEY_STR: &str = "10010001100100011001000110010001";
+        let sessions: Vec<_> = (1..=3)
+            .map(|noise_seed| {
+                let (correct_key, noise_key) = create_test_shuffled_key(KEY_STR, noise_seed);
+                let seed_schedule = SeedSchedule::new(b"secret", b"nonce");
+                Reconciliation::new(NUM_ITERATIONS, &seed_schedule, correct_key, noise_key)
+            })
+            .collect();
+
+        // every session moves to a rayon worker thread
+        let noise_keys: Vec<Key> = sessions
+            .into_par_iter()
+            .map(|mut reconciliation| {
+                reconciliation.start_iterations().unwrap();
+                reconciliation.into_noise_key()
+            })
+            .collect();
+        for noise_key in noise_keys {
+            assert_eq!(KEY_STR, noise_key.reveal().to_string());
+        }
     }
 }
@@ -0,0 +1,422 @@
+//! Reconciliation scheduled message by message, following the rules of
+//! [Cascade-CPP](https://github.com/brunorijsman/cascade-cpp).
+//!
+//! [`ScheduledReconciliation`] runs the original Cascade algorithm with the shuffles it is given,
+//! and records every parity query in order:
+//!
+//! - the block size of iteration 1 is `ceil(0.73 / ber)` and doubles in every later iteration,
+//!   as [`OriginalAlgorithm`] does
+//! - the left sub block takes the larger half of an odd sized block
+//! - an iteration asks the correct parities of all its top blocks in one message, then all pending
+//!   work is serviced before the next iteration starts
+//! - pending asks are sent together in one message, pending corrections are tried smallest block
+//!   first, blocks of the same size in the order they were scheduled
+//! - a block with an even error parity corrects its right sibling instead, if it was reached from
+//!   its parent
+//! - a corrected bit schedules every block containing it, in all started iterations
+//!
+//! The shuffles are injected, e.g. with [`Shuffle::from_shuffled_to_orig_map`], not generated.
+//! The golden runs in `tests/golden` are worked out by hand from the rules above, they pin the
+//! schedule; the runs are not compared with Cascade-CPP.
+//!
+//! [`Shuffle::from_shuffled_to_orig_map`]: crate::shuffle::Shuffle::from_shuffled_to_orig_map
+
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    algorithm::OriginalAlgorithm,
+    block::BlockId,
+    error::Result,
+    iteration::Iteration,
+    key::Key,
+    observer::NoObserver,
+    protocol::SimulatedParityChannel,
+    queue::{CorrectionOrder, CorrectionQueue},
+    shuffle::SharedShuffle,
+};
+
+/// A block whose correct parity is asked, bit numbers of the shuffled key of the iteration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParityQuery {
+    pub iteration_nr: u32,
+    pub start_bit_nr: u32,
+    pub end_bit_nr: u32,
+}
+
+/// Statistics of the parity queries, named as in the Cascade-CPP output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduledStats {
+    pub ask_parity_messages: u64,
+    pub ask_parity_blocks: u64,
+    pub infer_parity_blocks: u64,
+    pub remaining_bit_errors: u64,
+}
+
+struct PendingBlock {
+    iteration_index: usize,
+    block: BlockId,
+    correct_right_sibling: bool,
+}
+
+pub struct ScheduledReconciliation {
+    correct_key: Arc<Key>,
+    noise_key: Key,
+    // Alice answering the parity queries
+    parity_channel: SimulatedParityChannel,
+    // shuffle of every iteration, iteration 1 first
+    shuffles: Vec<SharedShuffle>,
+    iterations: Vec<Iteration<OriginalAlgorithm>>,
+    // sub blocks created by the schedule, the tree creates both sub blocks at once
+    created_sub_blocks: HashSet<(usize, BlockId)>,
+    pending_ask_correct_parity: Vec<PendingBlock>,
+    pending_try_correct: CorrectionQueue<PendingBlock>,
+    queries: Vec<ParityQuery>,
+    stats: ScheduledStats,
+}
+
+impl ScheduledReconciliation {
+    /// # Arguments
+    /// shuffles: shuffle of every iteration, iteration 1 first
+    pub fn new(correct_key: Arc<Key>, noise_key: Key, shuffles: Vec<SharedShuffle>) -> Self {
+        Self {
+            parity_channel: SimulatedParityChannel::new(correct_key.clone()),
+            correct_key,
+            noise_key,
+            shuffles,
+            iterations: Vec::new(),
+            created_sub_blocks: HashSet::new(),
+            pending_ask_correct_parity: Vec::new(),
+            pending_try_correct: CorrectionQueue::new(CorrectionOrder::SmallestFirst),
+            queries: Vec::new(),
+            stats: ScheduledStats::default(),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// the error of the parity channel
+    pub fn reconcile(&mut self) -> Result<()> {
+        for shuffle in self.shuffles.clone() {
+            let iteration_nr = self.iterations.len() as u32 + 1;
+            let iteration = Iteration::with_shuffle(
+                iteration_nr,
+                shuffle,
+                &self.noise_key,
+                OriginalAlgorithm::default(),
+            );
+            let iteration_index = self.iterations.len();
+            for block in iteration.get_top_blocks() {
+                self.pending_ask_correct_parity.push(PendingBlock {
+                    iteration_index,
+                    block,
+                    correct_right_sibling: false,
+                });
+            }
+            self.iterations.push(iteration);
+            self.service_all_pending_work()?;
+            // every shuffled copy syncs, then the changes of the noise key are forgotten
+            for iteration in &mut self.iterations {
+                iteration.sync(&self.noise_key);
+            }
+            self.noise_key.compact_changes();
+        }
+        self.stats.remaining_bit_errors =
+            self.correct_key.nr_bits_different(&self.noise_key) as u64;
+        Ok(())
+    }
+
+    /// The parity queries in the order they were asked.
+    pub fn get_queries(&self) -> &[ParityQuery] {
+        &self.queries
+    }
+
+    pub fn get_stats(&self) -> ScheduledStats {
+        self.stats
+    }
+
+    fn service_all_pending_work(&mut self) -> Result<()> {
+        while !self.pending_ask_correct_parity.is_empty() || !self.pending_try_correct.is_empty() {
+            self.service_pending_ask_correct_parity()?;
+            self.service_pending_try_correct();
+        }
+        Ok(())
+    }
+
+    fn service_pending_ask_correct_parity(&mut self) -> Result<()> {
+        if self.pending_ask_correct_parity.is_empty() {
+            return Ok(());
+        }
+        self.stats.ask_parity_messages += 1;
+        for pending in std::mem::take(&mut self.pending_ask_correct_parity) {
+            // scheduled twice before the message was sent
+            let iteration = &mut self.iterations[pending.iteration_index];
+            if iteration
+                .get_blocks()
+                .get_correct_parity(pending.block)
+                .is_some()
+            {
+                continue;
+            }
+            self.stats.ask_parity_blocks += 1;
+            self.queries.push(ParityQuery {
+                iteration_nr: pending.iteration_index as u32 + 1,
+                start_bit_nr: iteration.get_blocks().get_start_bit_nr(pending.block),
+                end_bit_nr: iteration.get_blocks().get_end_bit_nr(pending.block),
+            });
+            iteration.ask_correct_parity(
+                pending.block,
+                &mut self.parity_channel,
+                &mut NoObserver,
+            )?;
+            self.schedule_try_correct(pending);
+        }
+        Ok(())
+    }
+
+    fn service_pending_try_correct(&mut self) {
+        while let Some(pending) = self.pending_try_correct.pop() {
+            self.try_correct_block(pending);
+        }
+    }
+
+    fn schedule_try_correct(&mut self, pending: PendingBlock) {
+        let nr_bits = self.iterations[pending.iteration_index]
+            .get_blocks()
+            .get_nr_bits(pending.block);
+        self.pending_try_correct.push(nr_bits, pending);
+    }
+
+    fn try_correct_block(&mut self, pending: PendingBlock) {
+        if !self.try_to_infer_correct_parity(pending.iteration_index, pending.block) {
+            self.pending_ask_correct_parity.push(pending);
+            return;
+        }
+        let PendingBlock {
+            iteration_index,
+            block,
+            correct_right_sibling,
+        } = pending;
+
+        let iteration = &mut self.iterations[iteration_index];
+        iteration.sync(&self.noise_key);
+        if !iteration.has_odd_error_parity(block) {
+            if correct_right_sibling {
+                let parent_block = iteration
+                    .get_blocks()
+                    .get_parent_block(block)
+                    .expect("only sub blocks correct their sibling");
+                let (_, right_sub_block) = iteration
+                    .get_blocks_mut()
+                    .get_or_create_sub_blocks(parent_block);
+                self.created_sub_blocks
+                    .insert((iteration_index, right_sub_block));
+                self.try_correct_block(PendingBlock {
+                    iteration_index,
+                    block: right_sub_block,
+                    correct_right_sibling: false,
+                });
+            }
+            return;
+        }
+
+        if iteration.get_blocks().get_nr_bits(block) == 1 {
+            self.correct_bit(iteration_index, block);
+            return;
+        }
+
+        let (left_sub_block, _) = iteration.get_blocks_mut().get_or_create_sub_blocks(block);
+        self.created_sub_blocks
+            .insert((iteration_index, left_sub_block));
+        self.try_correct_block(PendingBlock {
+            iteration_index,
+            block: left_sub_block,
+            correct_right_sibling: true,
+        });
+    }
+
+    fn try_to_infer_correct_parity(&mut self, iteration_index: usize, block: BlockId) -> bool {
+        let blocks = self.iterations[iteration_index].get_blocks_mut();
+        if blocks.get_correct_parity(block).is_some() {
+            return true;
+        }
+        let inferred = blocks.try_to_infer_correct_parity(block);
+        if inferred {
+            self.stats.infer_parity_blocks += 1;
+        }
+        inferred
+    }
+
+    fn correct_bit(&mut self, iteration_index: usize, single_bit_block: BlockId) {
+        let orig_bit_nr = self.iterations[iteration_index].correct_bit(
+            &mut self.noise_key,
+            single_bit_block,
+            &mut NoObserver,
+        );
+
+        // cascade to every block containing the bit, smaller blocks are tried first
+        let mut affected_blocks = Vec::new();
+        for (index, iteration) in self.iterations.iter().enumerate() {
+            let bit_nr = iteration
+                .get_shuffled_key()
+                .orig_to_shuffle_bit_nr(orig_bit_nr);
+            let blocks = iteration.get_blocks();
+            let mut block = Some(iteration.get_top_block_containing_bit(bit_nr));
+            while let Some(current_block) = block {
+                block = [
+                    blocks.get_left_sub_block(current_block),
+                    blocks.get_right_sub_block(current_block),
+                ]
+                .into_iter()
+                .flatten()
+                .find(|&sub_block| {
+                    blocks.contains_bit(sub_block, bit_nr)
+                        && self.created_sub_blocks.contains(&(index, sub_block))
+                });
+                if (index, current_block) != (iteration_index, single_bit_block) {
+                    affected_blocks.push((index, current_block));
+                }
+            }
+        }
+        for (iteration_index, block) in affected_blocks {
+            self.schedule_try_correct(PendingBlock {
+                iteration_index,
+                block,
+                correct_right_sibling: false,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::Arc};
+
+    use crate::{
+        algorithm::{Algorithm, OriginalAlgorithm},
+        key::Key,
+        shuffle::Shuffle,
+    };
+
+    use super::{ParityQuery, ScheduledReconciliation, ScheduledStats};
+
+    /// A run parsed from a golden file, see `tests/golden/README.md`.
+    struct GoldenRun {
+        key: String,
+        ber: f32,
+        errors: Vec<u32>,
+        shuffles: Vec<Vec<u32>>,
+        queries: Vec<ParityQuery>,
+        stats: ScheduledStats,
+    }
+
+    fn numbers<T: std::str::FromStr>(fields: &[&str]) -> Vec<T>
+    where
+        T::Err: std::fmt::Debug,
+    {
+        fields.iter().map(|field| field.parse().unwrap()).collect()
+    }
+
+    fn parse_golden_run(text: &str) -> GoldenRun {
+        let mut run = GoldenRun {
+            key: String::new(),
+            ber: 0.0,
+            errors: Vec::new(),
+            shuffles: Vec::new(),
+            queries: Vec::new(),
+            stats: ScheduledStats::default(),
+        };
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                ["key", key] => run.key = key.to_string(),
+                ["ber", ber] => run.ber = ber.parse().unwrap(),
+                ["errors", errors @ ..] => run.errors = numbers(errors),
+                ["shuffle", map @ ..] => run.shuffles.push(numbers(map)),
+                ["query", query @ ..] => {
+                    let query: Vec<u32> = numbers(query);
+                    run.queries.push(ParityQuery {
+                        iteration_nr: query[0],
+                        start_bit_nr: query[1],
+                        end_bit_nr: query[2],
+                    });
+                }
+                ["stats", name, value] => {
+                    let value = value.parse().unwrap();
+                    match *name {
+                        "ask_parity_messages" => run.stats.ask_parity_messages = value,
+                        "ask_parity_blocks" => run.stats.ask_parity_blocks = value,
+                        "infer_parity_blocks" => run.stats.infer_parity_blocks = value,
+                        "remaining_bit_errors" => run.stats.remaining_bit_errors = value,
+                        _ => panic!("unknown statistic: {}", name),
+                    }
+                }
+                _ => panic!("invalid line: {}", line),
+            }
+        }
+        run
+    }
+
+    fn check_golden_run(path: &Path) {
+        let run = parse_golden_run(&fs::read_to_string(path).unwrap());
+        let correct_key = Key::from(run.key.as_str());
+        let mut noise_key = correct_key.clone();
+        noise_key.set_estimated_ber(run.ber);
+        for &bit_nr in &run.errors {
+            noise_key.flip_bit(bit_nr);
+        }
+        let shuffles = run
+            .shuffles
+            .into_iter()
+            .enumerate()
+            .map(|(index, map)| Shuffle::from_shuffled_to_orig_map(index as u32 + 1, map).unwrap())
+            .collect();
+
+        let mut reconciliation =
+            ScheduledReconciliation::new(Arc::new(correct_key), noise_key, shuffles);
+        reconciliation.reconcile().unwrap();
+        assert_eq!(
+            run.queries,
+            reconciliation.get_queries(),
+            "queries of {}",
+            path.display()
+        );
+        assert_eq!(
+            run.stats,
+            reconciliation.get_stats(),
+            "stats of {}",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn test_golden_runs() {
+        let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let entries = fs::read_dir(&golden_dir)
+            .unwrap_or_else(|err| panic!("{}: {}", golden_dir.display(), err));
+        let mut nr_runs = 0;
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "golden")
+            {
+                check_golden_run(&path);
+                nr_runs += 1;
+            }
+        }
+        assert!(nr_runs > 0, "no golden runs in {}", golden_dir.display());
+    }
+
+    #[test]
+    fn test_block_size() {
+        // Cascade-CPP original_block_size_function
+        assert_eq!(3, <OriginalAlgorithm as Algorithm>::block_size(1, 0.25, 8));
+        assert_eq!(6, <OriginalAlgorithm as Algorithm>::block_size(2, 0.25, 8));
+        assert_eq!(
+            73000,
+            <OriginalAlgorithm as Algorithm>::block_size(1, 1e-6, 8)
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Shuffle seeds derived from a session secret.
+//!
+//! Every iteration after the first shuffles the key with its own permutation. Alice and Bob must
+//! use the same permutations, and the permutations must differ between iterations and sessions.
+//! Both sides derive the shuffle seed of each iteration with HKDF-SHA256:
+//!
+//! ```text
+//! prk  = HKDF-Extract(salt = session_nonce, ikm = session_secret)
+//! seed = HKDF-Expand(prk, info = "cascade shuffle seed" || iteration_nr as u32 big endian, 8 bytes)
+//! ```
+//!
+//! The seed is the 8 output bytes read as a big endian `u64`.
+//! The session nonce is agreed by both sides at the start of every session, so that
+//! two sessions with the same secret still get different permutations.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+const SHUFFLE_SEED_INFO: &[u8] = b"cascade shuffle seed";
+
+pub struct SeedSchedule {
+    // pseudorandom key from the extract step
+    prk: [u8; 32],
+}
+
+impl SeedSchedule {
+    pub fn new(session_secret: &[u8], session_nonce: &[u8]) -> Self {
+        let (prk, _) = Hkdf::<Sha256>::extract(Some(session_nonce), session_secret);
+        Self { prk: prk.into() }
+    }
+
+    /// Seed of the shuffle used in iteration `iteration_nr`.
+    pub fn shuffle_seed(&self, iteration_nr: u32) -> u64 {
+        let hkdf =
+            Hkdf::<Sha256>::from_prk(&self.prk).expect("PRK has the length of a SHA-256 hash");
+        let mut info = Vec::with_capacity(SHUFFLE_SEED_INFO.len() + 4);
+        info.extend_from_slice(SHUFFLE_SEED_INFO);
+        info.extend_from_slice(&iteration_nr.to_be_bytes());
+
+        let mut seed = [0u8; 8];
+        hkdf.expand(&info, &mut seed)
+            .expect("8 bytes is a valid length for HKDF-SHA256");
+        u64::from_be_bytes(seed)
+    }
+}
+
+impl Drop for SeedSchedule {
+    fn drop(&mut self) {
+        self.prk.zeroize();
+    }
+}
+
+impl std::fmt::Debug for SeedSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeedSchedule")
+            .field("prk", &"<redacted>")
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::shuffle::Shuffle;
+
+    use super::SeedSchedule;
+
+    const SECRET: &[u8] = b"session secret";
+
+    #[test]
+    fn test_seeds_differ() {
+        let schedule = SeedSchedule::new(SECRET, b"nonce 1");
+        let seeds: HashSet<u64> = (1..=16).map(|nr| schedule.shuffle_seed(nr)).collect();
+        assert_eq!(16, seeds.len());
+
+        // a new session nonce gives new seeds
+        let other_schedule = SeedSchedule::new(SECRET, b"nonce 2");
+        assert!((1..=16).all(|nr| !seeds.contains(&other_schedule.shuffle_seed(nr))));
+    }
+
+    #[test]
+    fn test_alice_and_bob_agree() {
+        const NR_BITS: u32 = 1000;
+        let alice = SeedSchedule::new(SECRET, b"nonce");
+        let bob = SeedSchedule::new(SECRET, b"nonce");
+
+        let mut shuffles = Vec::new();
+        for iteration_nr in 2..=4 {
+            let alice_shuffle = Shuffle::new_shuffle_from_seed(
+                iteration_nr,
+                NR_BITS,
+                alice.shuffle_seed(iteration_nr),
+                false,
+            )
+            .unwrap();
+            let bob_shuffle = Shuffle::new_shuffle_from_seed(
+                iteration_nr,
+                NR_BITS,
+                bob.shuffle_seed(iteration_nr),
+                false,
+            )
+            .unwrap();
+            assert!(
+                (0..NR_BITS).all(|bit_nr| alice_shuffle.shuffle_to_orig(bit_nr)
+                    == bob_shuffle.shuffle_to_orig(bit_nr))
+            );
+            shuffles.push(bob_shuffle);
+        }
+
+        // the iterations use different permutations
+        for (i, shuffle) in shuffles.iter().enumerate() {
+            for other_shuffle in &shuffles[i + 1..] {
+                assert!((0..NR_BITS).any(|bit_nr| shuffle.shuffle_to_orig(bit_nr)
+                    != other_shuffle.shuffle_to_orig(bit_nr)));
+            }
+        }
+    }
+}
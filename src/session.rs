@@ -0,0 +1,1040 @@
+//! Lifecycle of a reconciliation session between two peers, for both sides.
+//!
+//! Bob holds the noise key and drives the session, Alice holds the correct key and answers:
+//!
+//! ```text
+//! Negotiating  Bob: Hello                      Alice: HelloAccepted
+//! Estimating   Bob: EstimationRequest          Alice: EstimationReply
+//! Iterating    Bob: ParityRequests ...         Alice: ParityReplies ...
+//!              Bob: IterationsDone
+//! Biconf       Bob: ParityRequests ...         Alice: ParityReplies ...
+//!              Bob: BiconfDone
+//! Verifying    Bob: Verify                     Alice: Verified
+//! Done
+//! ```
+//!
+//! The sessions do no I/O: the caller passes every received message to `handle_message`, sends
+//! the returned messages in order, and calls `check_timeout` regularly. A message out of order, an
+//! invalid message, a stalled peer or a failed check aborts the session; the returned
+//! [`SessionMessage::Abort`] tells the peer why, which then aborts too. A finished session ignores
+//! all messages.
+//!
+//! The cascade iterations run in a [`Reconciliation`] without the correct key: Bob sends the parity
+//! requests of each of its steps in one message, and resumes it with Alice's replies. BICONF asks
+//! Alice with bit list requests: every iteration compares the parity of a random subset, a
+//! differing parity starts a binary search of the subset for the error.
+
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{
+    algorithm::OriginalAlgorithm,
+    biconf,
+    error::{Error, Result},
+    estimation::{disclose_bits, QberEstimate, QberEstimator},
+    key::Key,
+    protocol::{ParityRequest, ParityResponder},
+    random::with_rng,
+    reconciliation::{Reconciliation, ReconciliationStep},
+    seed::SeedSchedule,
+    shuffle::ShuffleCache,
+    stats::ReconciliationStats,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+// consecutive BICONF iterations without a differing parity
+const DEFAULT_NR_BICONF_ITERATIONS: u32 = 10;
+// above it, BB84 cannot distill a secret key
+const DEFAULT_MAX_QBER: f32 = 0.11;
+// the estimation sample must leave most of the key to reconcile
+const DEFAULT_MAX_DISCLOSED_FRACTION: f64 = 0.25;
+const VERIFY_SALT_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Negotiating,
+    Estimating,
+    Iterating,
+    Biconf,
+    Verifying,
+    Done,
+    /// See the abort reason of the session.
+    Aborted,
+}
+
+impl SessionState {
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Done | Self::Aborted)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AbortReason {
+    /// The message is not expected in the state.
+    UnexpectedMessage {
+        state: SessionState,
+        message: &'static str,
+    },
+    InvalidMessage(Error),
+    /// No message from the peer within the timeout.
+    Timeout {
+        state: SessionState,
+    },
+    /// The estimated QBER is too high to reconcile the key securely.
+    QberTooHigh {
+        qber: f32,
+        max_qber: f32,
+    },
+    /// The keys still differ after BICONF.
+    VerificationFailed,
+    PeerAborted(Box<AbortReason>),
+    /// Aborted by the caller.
+    Cancelled,
+}
+
+impl fmt::Display for AbortReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedMessage { state, message } => {
+                write!(f, "unexpected message {} in state {:?}", message, state)
+            }
+            Self::InvalidMessage(error) => write!(f, "invalid message: {}", error),
+            Self::Timeout { state } => write!(f, "peer timed out in state {:?}", state),
+            Self::QberTooHigh { qber, max_qber } => write!(
+                f,
+                "estimated QBER {:.4} is above the maximum {:.4}",
+                qber, max_qber
+            ),
+            Self::VerificationFailed => write!(f, "the keys differ after reconciliation"),
+            Self::PeerAborted(reason) => write!(f, "peer aborted: {}", reason),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionMessage {
+    /// Bob proposes the session parameters.
+    Hello {
+        nr_key_bits: u32,
+        nr_iterations: u32,
+    },
+    HelloAccepted,
+    /// The bits Alice discloses for the QBER estimation, sorted.
+    EstimationRequest {
+        bit_nrs: Vec<u32>,
+    },
+    EstimationReply {
+        bits: Vec<u8>,
+    },
+    IterationsDone,
+    /// Encoded parity requests of one round trip, see [`protocol`](crate::protocol).
+    ParityRequests(Vec<Vec<u8>>),
+    ParityReplies(Vec<u8>),
+    BiconfDone,
+    /// SHA-256 of the salt and Bob's key, see `key_hash`.
+    Verify {
+        salt: [u8; VERIFY_SALT_LEN],
+        key_hash: [u8; 32],
+    },
+    Verified,
+    Abort(AbortReason),
+}
+
+impl SessionMessage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Hello { .. } => "Hello",
+            Self::HelloAccepted => "HelloAccepted",
+            Self::EstimationRequest { .. } => "EstimationRequest",
+            Self::EstimationReply { .. } => "EstimationReply",
+            Self::IterationsDone => "IterationsDone",
+            Self::ParityRequests(_) => "ParityRequests",
+            Self::ParityReplies(_) => "ParityReplies",
+            Self::BiconfDone => "BiconfDone",
+            Self::Verify { .. } => "Verify",
+            Self::Verified => "Verified",
+            Self::Abort(_) => "Abort",
+        }
+    }
+}
+
+// state and timeout of either side
+struct SessionCore {
+    state: SessionState,
+    abort_reason: Option<AbortReason>,
+    timeout: Duration,
+    // when the last message was received or sent
+    last_activity: Instant,
+}
+
+impl SessionCore {
+    fn new() -> Self {
+        Self {
+            state: SessionState::Negotiating,
+            abort_reason: None,
+            timeout: DEFAULT_TIMEOUT,
+            last_activity: Instant::now(),
+        }
+    }
+
+    fn set_state(&mut self, state: SessionState) {
+        debug!(from = ?self.state, to = ?state, "session state");
+        self.state = state;
+    }
+
+    /// Abort, and tell the peer why unless it aborted itself.
+    fn abort(&mut self, reason: AbortReason) -> Vec<SessionMessage> {
+        warn!(state = ?self.state, %reason, "session aborted");
+        self.set_state(SessionState::Aborted);
+        self.abort_reason = Some(reason.clone());
+        match reason {
+            AbortReason::PeerAborted(_) => Vec::new(),
+            _ => vec![SessionMessage::Abort(reason)],
+        }
+    }
+
+    fn abort_unexpected(&mut self, message: &SessionMessage) -> Vec<SessionMessage> {
+        self.abort(AbortReason::UnexpectedMessage {
+            state: self.state,
+            message: message.name(),
+        })
+    }
+
+    fn abort_invalid(&mut self, reason: &'static str) -> Vec<SessionMessage> {
+        self.abort(AbortReason::InvalidMessage(Error::MalformedMessage(reason)))
+    }
+
+    fn check_timeout(&mut self, now: Instant) -> Option<SessionMessage> {
+        if self.state.is_finished()
+            || now.saturating_duration_since(self.last_activity) <= self.timeout
+        {
+            return None;
+        }
+        self.abort(AbortReason::Timeout { state: self.state }).pop()
+    }
+}
+
+/// Alice's side of a session, answering from the correct key.
+pub struct AliceSession {
+    core: SessionCore,
+    correct_key: Key,
+    // given to the responder once the estimation bits are discarded
+    seed_schedule: Option<SeedSchedule>,
+    shuffle_cache: Option<Arc<ShuffleCache>>,
+    max_disclosed_fraction: f64,
+    // agreed in the Hello, the responder answers these iterations only
+    nr_iterations: u32,
+    responder: Option<ParityResponder>,
+}
+
+impl AliceSession {
+    /// Session answering the parity requests of the shuffles of `seed_schedule` only.
+    pub fn new(correct_key: Key, seed_schedule: SeedSchedule) -> Self {
+        Self {
+            core: SessionCore::new(),
+            correct_key,
+            seed_schedule: Some(seed_schedule),
+            shuffle_cache: None,
+            max_disclosed_fraction: DEFAULT_MAX_DISCLOSED_FRACTION,
+            nr_iterations: 0,
+            responder: None,
+        }
+    }
+
+    /// Look up the shuffles of the parity requests in `shuffle_cache`, instead of rebuilding them.
+    pub fn with_shuffle_cache(mut self, shuffle_cache: Arc<ShuffleCache>) -> Self {
+        self.shuffle_cache = Some(shuffle_cache);
+        self
+    }
+
+    /// Abort if Bob asks to disclose more than `max_disclosed_fraction` of the key for the
+    /// estimation, a quarter by default.
+    pub fn with_max_disclosed_fraction(mut self, max_disclosed_fraction: f64) -> Self {
+        assert!(max_disclosed_fraction > 0.0 && max_disclosed_fraction < 1.0);
+        self.max_disclosed_fraction = max_disclosed_fraction;
+        self
+    }
+
+    /// Abort if Bob sends nothing for `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.core.timeout = timeout;
+        self
+    }
+
+    pub fn get_state(&self) -> SessionState {
+        self.core.state
+    }
+
+    pub fn get_abort_reason(&self) -> Option<&AbortReason> {
+        self.core.abort_reason.as_ref()
+    }
+
+    /// The reconciled key, without the disclosed bits, once the session is done.
+    pub fn get_key(&self) -> Option<&Key> {
+        (self.core.state == SessionState::Done).then_some(&self.correct_key)
+    }
+
+    /// Abort the session, the returned message tells Bob.
+    pub fn abort(&mut self) -> Option<SessionMessage> {
+        if self.core.state.is_finished() {
+            return None;
+        }
+        self.core.abort(AbortReason::Cancelled).pop()
+    }
+
+    /// Abort if Bob stalled, the returned message tells Bob.
+    pub fn check_timeout(&mut self, now: Instant) -> Option<SessionMessage> {
+        self.core.check_timeout(now)
+    }
+
+    /// Handle a message from Bob, and return the messages to send back.
+    pub fn handle_message(&mut self, message: SessionMessage) -> Vec<SessionMessage> {
+        if self.core.state.is_finished() {
+            return Vec::new();
+        }
+        let replies = match (self.core.state, message) {
+            (_, SessionMessage::Abort(reason)) => {
+                self.core.abort(AbortReason::PeerAborted(Box::new(reason)))
+            }
+            (
+                SessionState::Negotiating,
+                SessionMessage::Hello {
+                    nr_key_bits,
+                    nr_iterations,
+                },
+            ) => {
+                if nr_key_bits != self.correct_key.get_nr_bits() {
+                    return self.core.abort_invalid("the key sizes differ");
+                }
+                if nr_iterations == 0 {
+                    return self.core.abort_invalid("no iterations");
+                }
+                info!(nr_key_bits, nr_iterations, "session accepted");
+                self.nr_iterations = nr_iterations;
+                self.core.set_state(SessionState::Estimating);
+                vec![SessionMessage::HelloAccepted]
+            }
+            (SessionState::Estimating, SessionMessage::EstimationRequest { bit_nrs }) => {
+                if let Err(error) = self.check_estimation_request(&bit_nrs) {
+                    return self.core.abort(AbortReason::InvalidMessage(error));
+                }
+                let bits = disclose_bits(&self.correct_key, &bit_nrs);
+                self.correct_key.discard_bits(&bit_nrs);
+                self.responder = Some(
+                    ParityResponder::new(
+                        Arc::new(self.correct_key.clone()),
+                        self.seed_schedule.take(),
+                        self.shuffle_cache.clone(),
+                    )
+                    .with_nr_iterations(self.nr_iterations),
+                );
+                self.core.set_state(SessionState::Iterating);
+                vec![SessionMessage::EstimationReply { bits }]
+            }
+            (
+                SessionState::Iterating | SessionState::Biconf,
+                SessionMessage::ParityRequests(requests),
+            ) => {
+                let responder = self
+                    .responder
+                    .as_mut()
+                    .expect("the responder is created at the estimation");
+                let parities: Result<Vec<u8>> = requests
+                    .iter()
+                    .map(|bytes| responder.answer(&ParityRequest::decode(bytes)?))
+                    .collect();
+                match parities {
+                    Ok(parities) => vec![SessionMessage::ParityReplies(parities)],
+                    Err(error) => self.core.abort(AbortReason::InvalidMessage(error)),
+                }
+            }
+            (SessionState::Iterating, SessionMessage::IterationsDone) => {
+                self.core.set_state(SessionState::Biconf);
+                Vec::new()
+            }
+            (SessionState::Biconf, SessionMessage::BiconfDone) => {
+                self.core.set_state(SessionState::Verifying);
+                Vec::new()
+            }
+            (SessionState::Verifying, SessionMessage::Verify { salt, key_hash }) => {
+                if self::key_hash(&self.correct_key, &salt) != key_hash {
+                    return self.core.abort(AbortReason::VerificationFailed);
+                }
+                self.core.set_state(SessionState::Done);
+                vec![SessionMessage::Verified]
+            }
+            (_, message) => self.core.abort_unexpected(&message),
+        };
+        self.core.last_activity = Instant::now();
+        replies
+    }
+
+    /// The bits must be sorted, distinct and in the key, and at most the allowed fraction of it.
+    fn check_estimation_request(&self, bit_nrs: &[u32]) -> Result<()> {
+        let nr_bits = self.correct_key.get_nr_bits();
+        if let Some(&bit_nr) = bit_nrs.iter().find(|&&bit_nr| bit_nr >= nr_bits) {
+            return Err(Error::BitOutOfRange { bit_nr, nr_bits });
+        }
+        if !bit_nrs.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(Error::MalformedMessage(
+                "estimation bits not sorted and distinct",
+            ));
+        }
+        check_disclosed_bits(bit_nrs.len(), nr_bits, self.max_disclosed_fraction)
+    }
+}
+
+/// Bob's side of a session, reconciling the noise key.
+pub struct BobSession {
+    core: SessionCore,
+    nr_iterations: u32,
+    seed_schedule: SeedSchedule,
+    noise_key: Key,
+    algo: OriginalAlgorithm,
+    // the cascade iterations, while iterating
+    reconciliation: Option<Reconciliation>,
+    estimator: QberEstimator,
+    max_qber: f32,
+    nr_biconf_iterations: u32,
+    estimation_bit_nrs: Vec<u32>,
+    estimate: Option<QberEstimate>,
+    stats: Option<ReconciliationStats>,
+    nr_clean_biconf_iterations: u32,
+    // the subset of the BICONF iteration, then the part of it with an odd number of errors
+    biconf_bit_nrs: Vec<u32>,
+    // the first bits of biconf_bit_nrs whose parity was asked
+    nr_asked_biconf_bits: usize,
+    nr_biconf_corrected_bits: u32,
+}
+
+impl BobSession {
+    /// Both sides create the seed schedule from the same session secret and nonce, see
+    /// [`Reconciliation::new`].
+    ///
+    /// # Errors
+    ///
+    /// [`Error::TooManyDisclosedBits`] if the estimator samples more of the noise key than Alice
+    /// accepts by default, see [`AliceSession::with_max_disclosed_fraction`]
+    pub fn new(
+        num_iterations: u32,
+        seed_schedule: SeedSchedule,
+        noise_key: Key,
+        estimator: QberEstimator,
+    ) -> Result<Self> {
+        check_disclosed_bits(
+            estimator.get_nr_sampled_bits() as usize,
+            noise_key.get_nr_bits(),
+            DEFAULT_MAX_DISCLOSED_FRACTION,
+        )?;
+        Ok(Self {
+            core: SessionCore::new(),
+            nr_iterations: num_iterations,
+            seed_schedule,
+            noise_key,
+            algo: OriginalAlgorithm::default(),
+            reconciliation: None,
+            estimator,
+            max_qber: DEFAULT_MAX_QBER,
+            nr_biconf_iterations: DEFAULT_NR_BICONF_ITERATIONS,
+            estimation_bit_nrs: Vec::new(),
+            estimate: None,
+            stats: None,
+            nr_clean_biconf_iterations: 0,
+            biconf_bit_nrs: Vec::new(),
+            nr_asked_biconf_bits: 0,
+            nr_biconf_corrected_bits: 0,
+        })
+    }
+
+    pub fn with_algorithm(mut self, algo: OriginalAlgorithm) -> Self {
+        self.algo = algo;
+        self
+    }
+
+    /// Abort if Alice sends nothing for `timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.core.timeout = timeout;
+        self
+    }
+
+    /// Abort if the estimated QBER is above `max_qber`.
+    pub fn with_max_qber(mut self, max_qber: f32) -> Self {
+        self.max_qber = max_qber;
+        self
+    }
+
+    /// BICONF ends after `nr_biconf_iterations` consecutive iterations without an error found.
+    pub fn with_nr_biconf_iterations(mut self, nr_biconf_iterations: u32) -> Self {
+        self.nr_biconf_iterations = nr_biconf_iterations;
+        self
+    }
+
+    pub fn get_state(&self) -> SessionState {
+        self.core.state
+    }
+
+    pub fn get_abort_reason(&self) -> Option<&AbortReason> {
+        self.core.abort_reason.as_ref()
+    }
+
+    pub fn get_estimate(&self) -> Option<&QberEstimate> {
+        self.estimate.as_ref()
+    }
+
+    /// Statistics of the cascade iterations, once they ran; Bob does not know the initial and
+    /// residual bit errors.
+    pub fn get_reconciliation_stats(&self) -> Option<&ReconciliationStats> {
+        self.stats.as_ref()
+    }
+
+    pub fn get_nr_biconf_corrected_bits(&self) -> u32 {
+        self.nr_biconf_corrected_bits
+    }
+
+    /// The reconciled key, without the disclosed bits, once the session is done.
+    pub fn get_key(&self) -> Option<&Key> {
+        (self.core.state == SessionState::Done).then_some(&self.noise_key)
+    }
+
+    /// The first message of the session, to send to Alice.
+    pub fn start(&mut self) -> SessionMessage {
+        assert_eq!(SessionState::Negotiating, self.core.state);
+        self.core.last_activity = Instant::now();
+        SessionMessage::Hello {
+            nr_key_bits: self.noise_key.get_nr_bits(),
+            nr_iterations: self.nr_iterations,
+        }
+    }
+
+    /// Abort the session, the returned message tells Alice.
+    pub fn abort(&mut self) -> Option<SessionMessage> {
+        if self.core.state.is_finished() {
+            return None;
+        }
+        self.core.abort(AbortReason::Cancelled).pop()
+    }
+
+    /// Abort if Alice stalled, the returned message tells Alice.
+    pub fn check_timeout(&mut self, now: Instant) -> Option<SessionMessage> {
+        self.core.check_timeout(now)
+    }
+
+    /// Handle a message from Alice, and return the messages to send back.
+    pub fn handle_message(&mut self, message: SessionMessage) -> Vec<SessionMessage> {
+        if self.core.state.is_finished() {
+            return Vec::new();
+        }
+        let replies = match (self.core.state, message) {
+            (_, SessionMessage::Abort(reason)) => {
+                self.core.abort(AbortReason::PeerAborted(Box::new(reason)))
+            }
+            (SessionState::Negotiating, SessionMessage::HelloAccepted) => {
+                let nr_bits = self.noise_key.get_nr_bits();
+                self.estimation_bit_nrs =
+                    with_rng(|rng| self.estimator.sample_bit_nrs_with_rng(nr_bits, rng));
+                self.core.set_state(SessionState::Estimating);
+                vec![SessionMessage::EstimationRequest {
+                    bit_nrs: self.estimation_bit_nrs.clone(),
+                }]
+            }
+            (SessionState::Estimating, SessionMessage::EstimationReply { bits }) => {
+                self.handle_estimation_reply(&bits)
+            }
+            (SessionState::Iterating, SessionMessage::ParityReplies(parities)) => {
+                let reconciliation = self
+                    .reconciliation
+                    .as_mut()
+                    .expect("the reconciliation runs while iterating");
+                match reconciliation.resume(&parities) {
+                    Ok(()) => self.step_reconciliation(),
+                    Err(error) => self.core.abort(AbortReason::InvalidMessage(error)),
+                }
+            }
+            (SessionState::Biconf, SessionMessage::ParityReplies(parities)) => match parities[..] {
+                [correct_parity] if correct_parity <= 1 => {
+                    self.handle_biconf_parity(correct_parity)
+                }
+                _ => self.core.abort_invalid("expected one parity"),
+            },
+            (SessionState::Verifying, SessionMessage::Verified) => {
+                info!(
+                    nr_biconf_corrected_bits = self.nr_biconf_corrected_bits,
+                    "session done"
+                );
+                self.core.set_state(SessionState::Done);
+                Vec::new()
+            }
+            (_, message) => self.core.abort_unexpected(&message),
+        };
+        self.core.last_activity = Instant::now();
+        replies
+    }
+
+    /// Estimate the QBER, discard the disclosed bits and start the cascade iterations.
+    fn handle_estimation_reply(&mut self, bits: &[u8]) -> Vec<SessionMessage> {
+        let estimate = self
+            .estimator
+            .estimate(&self.noise_key, &self.estimation_bit_nrs, bits);
+        let estimate = match estimate {
+            Ok(estimate) => estimate,
+            Err(error) => return self.core.abort(AbortReason::InvalidMessage(error)),
+        };
+        info!(qber = estimate.qber, "QBER estimated");
+        if estimate.qber > self.max_qber {
+            let reason = AbortReason::QberTooHigh {
+                qber: estimate.qber,
+                max_qber: self.max_qber,
+            };
+            self.estimate = Some(estimate);
+            return self.core.abort(reason);
+        }
+
+        self.noise_key.discard_bits(&self.estimation_bit_nrs);
+        self.noise_key.set_estimated_ber(estimate.qber);
+        self.estimate = Some(estimate);
+
+        self.core.set_state(SessionState::Iterating);
+        self.reconciliation = Some(Reconciliation::remote(
+            self.nr_iterations,
+            &self.seed_schedule,
+            self.noise_key.clone(),
+            self.algo.clone(),
+        ));
+        self.step_reconciliation()
+    }
+
+    /// Send the next parity requests of the cascade iterations, or start BICONF once they are done.
+    fn step_reconciliation(&mut self) -> Vec<SessionMessage> {
+        let step = self
+            .reconciliation
+            .as_mut()
+            .expect("the reconciliation runs while iterating")
+            .step();
+        match step {
+            ReconciliationStep::AskParities(requests) => vec![SessionMessage::ParityRequests(
+                requests.iter().map(ParityRequest::encode).collect(),
+            )],
+            ReconciliationStep::Done(stats) => {
+                self.stats = Some(stats);
+                let reconciliation = self.reconciliation.take().expect("the reconciliation ran");
+                self.noise_key = reconciliation.into_noise_key();
+
+                self.core.set_state(SessionState::Biconf);
+                let mut replies = vec![SessionMessage::IterationsDone];
+                replies.extend(self.start_biconf_iteration());
+                replies
+            }
+        }
+    }
+
+    /// Ask the parity of a new random subset, or verify the key after enough clean iterations.
+    fn start_biconf_iteration(&mut self) -> Vec<SessionMessage> {
+        if self.nr_clean_biconf_iterations >= self.nr_biconf_iterations {
+            self.core.set_state(SessionState::Verifying);
+            let mut salt = [0; VERIFY_SALT_LEN];
+            with_rng(|rng| rng.fill_bytes(&mut salt));
+            return vec![
+                SessionMessage::BiconfDone,
+                SessionMessage::Verify {
+                    salt,
+                    key_hash: key_hash(&self.noise_key, &salt),
+                },
+            ];
+        }
+        let nr_bits = self.noise_key.get_nr_bits();
+        self.biconf_bit_nrs = with_rng(|rng| biconf::random_subset(nr_bits, rng));
+        self.nr_asked_biconf_bits = self.biconf_bit_nrs.len();
+        vec![self.biconf_request()]
+    }
+
+    fn biconf_request(&self) -> SessionMessage {
+        let bit_nrs = self.biconf_bit_nrs[..self.nr_asked_biconf_bits].to_vec();
+        SessionMessage::ParityRequests(vec![ParityRequest::BitList(bit_nrs).encode()])
+    }
+
+    fn handle_biconf_parity(&mut self, correct_parity: u8) -> Vec<SessionMessage> {
+        let parity = self.biconf_bit_nrs[..self.nr_asked_biconf_bits]
+            .iter()
+            .fold(0, |parity, &bit_nr| parity ^ self.noise_key.get_bit(bit_nr));
+        let differs = parity != correct_parity;
+        if self.nr_asked_biconf_bits < self.biconf_bit_nrs.len() {
+            // binary search: keep the half with an odd number of errors
+            if differs {
+                self.biconf_bit_nrs.truncate(self.nr_asked_biconf_bits);
+            } else {
+                self.biconf_bit_nrs.drain(..self.nr_asked_biconf_bits);
+            }
+        } else if !differs {
+            self.nr_clean_biconf_iterations += 1;
+            return self.start_biconf_iteration();
+        } else if self.biconf_bit_nrs.is_empty() {
+            return self.core.abort_invalid("odd parity of no bits");
+        }
+
+        if let [bit_nr] = self.biconf_bit_nrs[..] {
+            debug!(bit_nr, "BICONF corrected bit");
+            self.noise_key.flip_bit(bit_nr);
+            // no shuffled copy of the key is left to sync
+            self.noise_key.compact_changes();
+            self.nr_biconf_corrected_bits += 1;
+            self.nr_clean_biconf_iterations = 0;
+            return self.start_biconf_iteration();
+        }
+        self.nr_asked_biconf_bits = self.biconf_bit_nrs.len() / 2;
+        vec![self.biconf_request()]
+    }
+}
+
+/// At most `max_disclosed_fraction` of the `nr_bits` of the key are disclosed, at least one bit
+/// is left.
+fn check_disclosed_bits(
+    nr_disclosed_bits: usize,
+    nr_bits: u32,
+    max_disclosed_fraction: f64,
+) -> Result<()> {
+    let max_disclosed_bits = (max_disclosed_fraction * f64::from(nr_bits)) as u32;
+    if nr_disclosed_bits > max_disclosed_bits as usize {
+        return Err(Error::TooManyDisclosedBits {
+            nr_disclosed_bits: u32::try_from(nr_disclosed_bits).unwrap_or(u32::MAX),
+            max_disclosed_bits,
+        });
+    }
+    Ok(())
+}
+
+/// SHA-256 of the salt, the number of bits as a big endian `u32` and the bits, 8 per byte
+/// from the least significant bit.
+fn key_hash(key: &Key, salt: &[u8]) -> [u8; 32] {
+    let nr_bits = key.get_nr_bits();
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(nr_bits.to_be_bytes());
+    for start_bit_nr in (0..nr_bits).step_by(8) {
+        let byte = (start_bit_nr..nr_bits.min(start_bit_nr + 8)).fold(0u8, |byte, bit_nr| {
+            byte | key.get_bit(bit_nr) << (bit_nr - start_bit_nr)
+        });
+        hasher.update([byte]);
+    }
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{
+        error::Error, estimation::QberEstimator, key::Key, protocol::ParityRequest,
+        random::set_random_seed, seed::SeedSchedule, shuffle::ShuffleBackend,
+    };
+
+    use super::{AbortReason, AliceSession, BobSession, SessionMessage, SessionState};
+
+    const NR_KEY_BITS: usize = 2000;
+
+    fn create_sessions(estimated_ber: f32, seed: u64) -> (AliceSession, BobSession) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let key_str: String = (0..NR_KEY_BITS)
+            .map(|_| if rng.gen() { '1' } else { '0' })
+            .collect();
+        let correct_key = Key::from(key_str.as_str());
+        let mut noise_key = correct_key.clone();
+        noise_key.set_estimated_ber(estimated_ber);
+        noise_key.apply_noise_with_rng(&mut rng);
+
+        let alice = AliceSession::new(correct_key, SeedSchedule::new(b"secret", b"nonce"));
+        let bob = BobSession::new(
+            4,
+            SeedSchedule::new(b"secret", b"nonce"),
+            noise_key,
+            QberEstimator::new(200, 0.99),
+        )
+        .unwrap();
+        (alice, bob)
+    }
+
+    // deliver the messages both ways until no message is left
+    fn run(alice: &mut AliceSession, bob: &mut BobSession, first_message: SessionMessage) {
+        let mut to_alice = vec![first_message];
+        while !to_alice.is_empty() {
+            let mut to_bob = Vec::new();
+            for message in to_alice {
+                to_bob.extend(alice.handle_message(message));
+            }
+            to_alice = Vec::new();
+            for message in to_bob {
+                to_alice.extend(bob.handle_message(message));
+            }
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        set_random_seed(1234);
+        let (mut alice, mut bob) = create_sessions(0.03, 7);
+        let hello = bob.start();
+        run(&mut alice, &mut bob, hello);
+
+        assert_eq!(SessionState::Done, alice.get_state());
+        assert_eq!(SessionState::Done, bob.get_state());
+        assert_eq!(None, bob.get_abort_reason());
+        let estimate = bob.get_estimate().unwrap();
+        assert!(estimate.qber > 0.0 && estimate.qber < 0.11);
+        let stats = bob.get_reconciliation_stats().unwrap();
+        assert_eq!(NR_KEY_BITS as u32 - 200, stats.nr_key_bits);
+        assert!(stats.nr_corrected_bits() > 0);
+        assert_eq!(None, stats.residual_bit_errors);
+        let alice_key = alice.get_key().unwrap();
+        let bob_key = bob.get_key().unwrap();
+        assert_eq!(NR_KEY_BITS as u32 - 200, alice_key.get_nr_bits());
+        assert_eq!(0, alice_key.nr_bits_different(bob_key));
+
+        // a finished session ignores all messages
+        assert!(alice
+            .handle_message(SessionMessage::HelloAccepted)
+            .is_empty());
+        assert_eq!(SessionState::Done, alice.get_state());
+    }
+
+    #[test]
+    fn test_unexpected_message() {
+        let (mut alice, mut bob) = create_sessions(0.03, 2);
+        let replies = alice.handle_message(SessionMessage::BiconfDone);
+        let reason = AbortReason::UnexpectedMessage {
+            state: SessionState::Negotiating,
+            message: "BiconfDone",
+        };
+        assert_eq!(vec![SessionMessage::Abort(reason.clone())], replies);
+        assert_eq!(SessionState::Aborted, alice.get_state());
+        assert_eq!(Some(&reason), alice.get_abort_reason());
+        assert!(alice.get_key().is_none());
+
+        // Bob aborts without replying
+        bob.start();
+        assert!(bob.handle_message(replies[0].clone()).is_empty());
+        assert_eq!(
+            Some(&AbortReason::PeerAborted(Box::new(reason))),
+            bob.get_abort_reason()
+        );
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let (mut alice, _) = create_sessions(0.03, 3);
+        let replies = alice.handle_message(SessionMessage::Hello {
+            nr_key_bits: 1000,
+            nr_iterations: 4,
+        });
+        assert!(matches!(
+            replies[..],
+            [SessionMessage::Abort(AbortReason::InvalidMessage(
+                Error::MalformedMessage(_)
+            ))]
+        ));
+
+        let (mut alice, _) = create_sessions(0.03, 3);
+        alice.handle_message(SessionMessage::Hello {
+            nr_key_bits: NR_KEY_BITS as u32,
+            nr_iterations: 4,
+        });
+        alice.handle_message(SessionMessage::EstimationRequest {
+            bit_nrs: vec![1, 2],
+        });
+        assert_eq!(SessionState::Iterating, alice.get_state());
+        let replies = alice.handle_message(SessionMessage::ParityRequests(vec![vec![0x07]]));
+        assert_eq!(
+            vec![SessionMessage::Abort(AbortReason::InvalidMessage(
+                Error::MalformedRequest("unknown tag")
+            ))],
+            replies
+        );
+
+        // only the shuffles of the agreed iterations are answered
+        let (mut alice, _) = create_sessions(0.03, 3);
+        alice.handle_message(SessionMessage::Hello {
+            nr_key_bits: NR_KEY_BITS as u32,
+            nr_iterations: 4,
+        });
+        alice.handle_message(SessionMessage::EstimationRequest {
+            bit_nrs: vec![1, 2],
+        });
+        let request = ParityRequest::ShuffleRange {
+            iteration_nr: 5,
+            backend: ShuffleBackend::Table,
+            shuffle_seed: SeedSchedule::new(b"secret", b"nonce").shuffle_seed(5),
+            start_bit_nr: 0,
+            end_bit_nr: 10,
+        };
+        let replies = alice.handle_message(SessionMessage::ParityRequests(vec![request.encode()]));
+        assert_eq!(
+            vec![SessionMessage::Abort(AbortReason::InvalidMessage(
+                Error::IterationNrOutOfRange {
+                    iteration_nr: 5,
+                    nr_iterations: 4
+                }
+            ))],
+            replies
+        );
+
+        // too many errors to reconcile
+        let (mut alice, mut bob) = create_sessions(0.3, 4);
+        let hello = bob.start();
+        run(&mut alice, &mut bob, hello);
+        assert!(matches!(
+            bob.get_abort_reason(),
+            Some(AbortReason::QberTooHigh { .. })
+        ));
+        assert!(matches!(
+            alice.get_abort_reason(),
+            Some(AbortReason::PeerAborted(_))
+        ));
+        assert!(bob.get_reconciliation_stats().is_none());
+    }
+
+    #[test]
+    fn test_disclosed_bits() {
+        // Bob cannot sample more than a quarter of the key
+        let bob = BobSession::new(
+            4,
+            SeedSchedule::new(b"secret", b"nonce"),
+            Key::from("011"),
+            QberEstimator::new(1, 0.99),
+        );
+        assert_eq!(
+            Some(Error::TooManyDisclosedBits {
+                nr_disclosed_bits: 1,
+                max_disclosed_bits: 0
+            }),
+            bob.err()
+        );
+
+        let (alice, _) = create_sessions(0.03, 6);
+        let mut alice = alice.with_max_disclosed_fraction(0.1);
+        alice.handle_message(SessionMessage::Hello {
+            nr_key_bits: NR_KEY_BITS as u32,
+            nr_iterations: 4,
+        });
+        let replies = alice.handle_message(SessionMessage::EstimationRequest {
+            bit_nrs: (0..201).collect(),
+        });
+        assert_eq!(
+            vec![SessionMessage::Abort(AbortReason::InvalidMessage(
+                Error::TooManyDisclosedBits {
+                    nr_disclosed_bits: 201,
+                    max_disclosed_bits: 200
+                }
+            ))],
+            replies
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let (alice, mut bob) = create_sessions(0.03, 5);
+        let mut alice = alice.with_timeout(Duration::from_secs(60));
+        let hello = bob.start();
+        alice.handle_message(hello);
+
+        assert_eq!(None, alice.check_timeout(Instant::now()));
+        let later = Instant::now() + Duration::from_secs(61);
+        let reason = AbortReason::Timeout {
+            state: SessionState::Estimating,
+        };
+        assert_eq!(
+            Some(SessionMessage::Abort(reason.clone())),
+            alice.check_timeout(later)
+        );
+        assert_eq!(Some(&reason), alice.get_abort_reason());
+        // only once
+        assert_eq!(None, alice.check_timeout(later));
+        assert_eq!(None, alice.abort());
+
+        assert_eq!(
+            Some(SessionMessage::Abort(AbortReason::Cancelled)),
+            bob.abort()
+        );
+        assert_eq!(SessionState::Aborted, bob.get_state());
+    }
+
+    // the messages until Bob sends the first parity requests of the iterations
+    fn start_iterating(alice: &mut AliceSession, bob: &mut BobSession) -> SessionMessage {
+        let accepted = alice.handle_message(bob.start()).remove(0);
+        let estimation_request = bob.handle_message(accepted).remove(0);
+        let estimation_reply = alice.handle_message(estimation_request).remove(0);
+        let mut requests = bob.handle_message(estimation_reply);
+        assert_eq!(SessionState::Iterating, bob.get_state());
+        assert_eq!(1, requests.len());
+        requests.remove(0)
+    }
+
+    #[test]
+    fn test_iterating() {
+        set_random_seed(1234);
+        let (mut alice, mut bob) = create_sessions(0.03, 7);
+        let requests = start_iterating(&mut alice, &mut bob);
+        let SessionMessage::ParityRequests(encoded_requests) = &requests else {
+            panic!("expected parity requests, got {:?}", requests);
+        };
+        assert!(!encoded_requests.is_empty());
+
+        // every step of the iterations is a round trip
+        let replies = alice.handle_message(requests);
+        let [SessionMessage::ParityReplies(_)] = &replies[..] else {
+            panic!("expected parity replies, got {:?}", replies);
+        };
+        let mut nr_round_trips = 1;
+        let mut messages = bob.handle_message(replies[0].clone());
+        while bob.get_state() == SessionState::Iterating {
+            assert!(matches!(messages[..], [SessionMessage::ParityRequests(_)]));
+            let replies = alice.handle_message(messages.remove(0));
+            messages = bob.handle_message(replies[0].clone());
+            nr_round_trips += 1;
+        }
+        assert_eq!(SessionMessage::IterationsDone, messages[0]);
+        let stats = bob.get_reconciliation_stats().unwrap();
+        assert_eq!(stats.nr_round_trips, nr_round_trips);
+    }
+
+    #[test]
+    fn test_invalid_parity_replies() {
+        let (mut alice, mut bob) = create_sessions(0.03, 8);
+        start_iterating(&mut alice, &mut bob);
+        let replies = bob.handle_message(SessionMessage::ParityReplies(Vec::new()));
+        assert_eq!(
+            vec![SessionMessage::Abort(AbortReason::InvalidMessage(
+                Error::MalformedMessage("not one parity per request")
+            ))],
+            replies
+        );
+        assert_eq!(SessionState::Aborted, bob.get_state());
+    }
+
+    #[test]
+    fn test_timeout_while_iterating() {
+        let (mut alice, bob) = create_sessions(0.03, 9);
+        let mut bob = bob.with_timeout(Duration::from_secs(60));
+        start_iterating(&mut alice, &mut bob);
+
+        // Alice does not reply to the parity requests
+        assert_eq!(None, bob.check_timeout(Instant::now()));
+        let later = Instant::now() + Duration::from_secs(61);
+        let reason = AbortReason::Timeout {
+            state: SessionState::Iterating,
+        };
+        assert_eq!(
+            Some(SessionMessage::Abort(reason.clone())),
+            bob.check_timeout(later)
+        );
+        assert_eq!(Some(&reason), bob.get_abort_reason());
+        assert!(bob.get_reconciliation_stats().is_none());
+    }
+}
@@ -1,73 +1,324 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
 
-use rand::rngs::StdRng;
-use rand::{seq::SliceRandom, Rng, SeedableRng};
+use lazy_static::lazy_static;
 
-#[derive(PartialEq, Eq, Hash)]
+use rand::{seq::SliceRandom, CryptoRng, Rng, RngCore};
+
+use crate::{
+    error::{Error, Result},
+    feistel::FeistelPermutation,
+    random,
+    shuffle_rng::ShuffleRng,
+};
+
+/// How a shuffle stores its permutation.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+pub enum ShuffleBackend {
+    /// Tables of both directions, 8 bytes per key bit, a lookup is a memory access.
+    #[default]
+    Table,
+    /// Keyed Feistel permutation computed on every lookup, constant memory.
+    /// Only shuffles created from a seed use it, both peers compute the same permutation.
+    Feistel,
+}
+
+/// Key of a shuffle in the cache.
+///
+/// Only shuffles created from a seed are cached, and only shared with shuffles created from the
+/// same seed. Random shuffles without a seed are never shared, so that sessions do not reuse
+/// each other's permutations.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct ShuffleIndex {
     iteration_nr: u32,
     nr_bits: u32,
-    has_seed: bool,
+    seed: u64,
+    backend: ShuffleBackend,
 }
 
 impl ShuffleIndex {
-    pub fn new(iteration_nr: u32, nr_bits: u32, has_seed: bool) -> Self {
+    pub fn new(iteration_nr: u32, nr_bits: u32, seed: u64) -> Self {
         Self {
             iteration_nr,
             nr_bits,
-            has_seed,
+            seed,
+            backend: ShuffleBackend::Table,
+        }
+    }
+
+    pub fn with_backend(self, backend: ShuffleBackend) -> Self {
+        Self { backend, ..self }
+    }
+}
+
+/// Cache statistics, since the cache was created or cleared.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Shuffles built outside of the cache and stored under their seed, not counted as lookups.
+    pub inserts: u64,
+    pub evictions: u64,
+}
+
+/// Size-bounded cache of shuffles created from a seed, evicting the least recently used shuffle
+/// when full.
+///
+/// Every entry is a cell that is initialized once, outside of the cache lock.
+/// Threads asking for the same shuffle wait for the first one to create it,
+/// threads asking for other shuffles are not blocked.
+/// The constructors of [`Shuffle`] with `cache` use the process-wide [`ShuffleCache::global`];
+/// a session can keep a cache of its own, see
+/// [`OriginalAlgorithm::with_shuffle_cache`](crate::algorithm::OriginalAlgorithm::with_shuffle_cache).
+pub struct ShuffleCache(Mutex<CacheEntries>);
+
+struct CacheEntries {
+    capacity: usize,
+    // shuffle and the tick of its last use
+    entries: HashMap<ShuffleIndex, (Arc<OnceLock<SharedShuffle>>, u64)>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+lazy_static! {
+    static ref CACHE: ShuffleCache = ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY);
+}
+
+impl ShuffleCache {
+    /// Capacity of the process-wide cache; a shuffle of a 1 Mbit key takes 8 MB.
+    pub const DEFAULT_CAPACITY: usize = 32;
+
+    /// A capacity of 0 disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self(Mutex::new(CacheEntries {
+            capacity,
+            entries: HashMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }))
+    }
+
+    /// The process-wide cache.
+    pub fn global() -> &'static ShuffleCache {
+        &CACHE
+    }
+
+    /// Same as [`Shuffle::new_shuffle_from_seed_with_backend`], cached in this cache.
+    pub fn get_or_create_from_seed(
+        &self,
+        iteration_nr: u32,
+        nr_bits: u32,
+        seed: u64,
+        backend: ShuffleBackend,
+    ) -> Result<SharedShuffle> {
+        check_iteration_nr(iteration_nr)?;
+        let index = ShuffleIndex::new(iteration_nr, nr_bits, seed).with_backend(backend);
+        Ok(self.get_or_insert_with(index, || match backend {
+            ShuffleBackend::Table => Shuffle::from_seed(iteration_nr, nr_bits, seed),
+            ShuffleBackend::Feistel => Shuffle::feistel_from_seed(iteration_nr, nr_bits, seed),
+        }))
+    }
+
+    fn get_or_insert_with(
+        &self,
+        index: ShuffleIndex,
+        create: impl FnOnce() -> Shuffle,
+    ) -> SharedShuffle {
+        let Some(cell) = self.cell(index) else {
+            self.lock().stats.misses += 1;
+            return Arc::new(create());
+        };
+
+        let mut created = false;
+        let shuffle = Arc::clone(cell.get_or_init(|| {
+            created = true;
+            Arc::new(create())
+        }));
+        let mut cache = self.lock();
+        if created {
+            cache.stats.misses += 1;
+        } else {
+            cache.stats.hits += 1;
+        }
+        shuffle
+    }
+
+    /// Store a shuffle that is already built, e.g. a random shuffle, under its index.
+    ///
+    /// A shuffle already cached under the index is kept and returned.
+    fn insert(&self, index: ShuffleIndex, shuffle: Shuffle) -> SharedShuffle {
+        let Some(cell) = self.cell(index) else {
+            return Arc::new(shuffle);
+        };
+        self.lock().stats.inserts += 1;
+        Arc::clone(cell.get_or_init(|| Arc::new(shuffle)))
+    }
+
+    // The cell of the shuffle, added if missing; None if the cache is disabled.
+    fn cell(&self, index: ShuffleIndex) -> Option<Arc<OnceLock<SharedShuffle>>> {
+        let mut cache = self.lock();
+        if cache.capacity == 0 {
+            return None;
+        }
+        cache.tick += 1;
+        let tick = cache.tick;
+        if let Some((cell, last_used)) = cache.entries.get_mut(&index) {
+            *last_used = tick;
+            return Some(Arc::clone(cell));
+        }
+        if cache.entries.len() >= cache.capacity {
+            cache.evict_least_recently_used();
+        }
+        let cell = Arc::new(OnceLock::new());
+        cache.entries.insert(index, (Arc::clone(&cell), tick));
+        Some(cell)
+    }
+
+    /// Statistics since the cache was created or cleared.
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    /// Number of shuffles in the cache.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, index: &ShuffleIndex) -> bool {
+        self.lock().entries.contains_key(index)
+    }
+
+    /// Set the maximum number of shuffles.
+    ///
+    /// The least recently used shuffles are evicted if the cache holds more shuffles.
+    /// A capacity of 0 disables the cache.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut cache = self.lock();
+        cache.capacity = capacity;
+        while cache.entries.len() > cache.capacity {
+            cache.evict_least_recently_used();
+        }
+    }
+
+    /// Remove all shuffles and reset the statistics.
+    pub fn clear(&self) {
+        let mut cache = self.lock();
+        cache.entries.clear();
+        cache.stats = CacheStats::default();
+    }
+
+    // The cache stays consistent if a thread panics while holding the lock,
+    // the shuffles themselves are created outside of the lock.
+    fn lock(&self) -> MutexGuard<'_, CacheEntries> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl CacheEntries {
+    // O(capacity), the cache is small compared to the cost of creating a shuffle.
+    fn evict_least_recently_used(&mut self) {
+        let lru_index = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (_, last_used))| *last_used)
+            .map(|(index, _)| *index);
+        if let Some(index) = lru_index {
+            self.entries.remove(&index);
+            self.stats.evictions += 1;
         }
     }
 }
 
 /// Shuffle is a permutation of the bit index of a key.
+///
+/// Shuffles never change after they are created, so they are `Send + Sync`
+/// and shared between threads and sessions through the process-wide cache.
 #[derive(Debug)]
 pub struct Shuffle {
     iteration_nr: u32,
     nr_bits: u32,
     has_seed: bool,
     seed: u64,
-    // the value is the index of the shuffled bit
-    orig_to_shuffled_map: Vec<u32>,
-    // the value is the index of the original bit
-    shuffled_to_orig_map: Vec<u32>,
+    permutation: Permutation,
+}
+
+#[derive(Debug)]
+enum Permutation {
+    // iteration 1 is not shuffled
+    Identity,
+    Table {
+        // the value is the index of the shuffled bit
+        orig_to_shuffled_map: Vec<u32>,
+        // the value is the index of the original bit
+        shuffled_to_orig_map: Vec<u32>,
+    },
+    // maps original bits to shuffled bits
+    Feistel(FeistelPermutation),
 }
 
-// Both ShuffledKey and CATCHE hold a reference to Shuffle.
-pub type SharedShuffle = Rc<Shuffle>;
+impl Permutation {
+    fn table(shuffled_to_orig_map: Vec<u32>) -> Self {
+        // Compute the reverse mapping of original key bits to shuffled key bits.
+        let mut orig_to_shuffled_map = vec![0; shuffled_to_orig_map.len()];
+        for (shuffled_bit_nr, &orig_bit_nr) in shuffled_to_orig_map.iter().enumerate() {
+            orig_to_shuffled_map[orig_bit_nr as usize] = shuffled_bit_nr as u32;
+        }
+        Self::Table {
+            orig_to_shuffled_map,
+            shuffled_to_orig_map,
+        }
+    }
+}
 
-thread_local! {
-    static CACHE: RefCell<HashMap<ShuffleIndex, SharedShuffle>> = RefCell::new(HashMap::new());
+// Both ShuffledKey and CACHE hold a reference to Shuffle.
+pub type SharedShuffle = Arc<Shuffle>;
 
+// the iterations are numbered from 1, the shuffle of iteration 1 is the identity
+fn check_iteration_nr(iteration_nr: u32) -> Result<()> {
+    if iteration_nr == 0 {
+        return Err(Error::InvalidIterationNr(iteration_nr));
+    }
+    Ok(())
 }
 
 impl Shuffle {
+    /// Random shuffle from the generator of this thread, see [`random`](crate::random).
     pub fn new_random_shuffle(
         iteration_nr: u32,
         nr_bits: u32,
         assign_seed: bool,
         cache: bool,
-    ) -> SharedShuffle {
-        assert!(iteration_nr > 0);
-        let index = ShuffleIndex::new(iteration_nr, nr_bits, assign_seed);
-        let shuffle = if cache {
-            CACHE.with(|c| {
-                if let Some(shuffle) = c.borrow().get(&index) {
-                    return Rc::clone(shuffle);
-                }
-
-                let shuffle = Rc::new(Shuffle::new(iteration_nr, nr_bits, assign_seed));
-                c.borrow_mut().insert(index, Rc::clone(&shuffle));
-                shuffle
-            })
-        } else {
-            Rc::new(Shuffle::new(iteration_nr, nr_bits, assign_seed))
-        };
+    ) -> Result<SharedShuffle> {
+        random::with_rng(|rng| {
+            Self::new_random_shuffle_with_rng(iteration_nr, nr_bits, assign_seed, cache, rng)
+        })
+    }
 
-        shuffle
+    /// Random shuffle from the given generator.
+    ///
+    /// The generator picks the seed or the permutation, so it must be cryptographically secure.
+    /// With `cache`, a shuffle with a seed is cached under the seed it was created from, so that
+    /// [`new_shuffle_from_seed`](Self::new_shuffle_from_seed) finds it; a shuffle without a seed
+    /// is never cached. The shuffle is always created from the generator, so it is reproducible
+    /// from the generator whether it is cached or not.
+    pub fn new_random_shuffle_with_rng<R: RngCore + CryptoRng + ?Sized>(
+        iteration_nr: u32,
+        nr_bits: u32,
+        assign_seed: bool,
+        cache: bool,
+        rng: &mut R,
+    ) -> Result<SharedShuffle> {
+        check_iteration_nr(iteration_nr)?;
+        let shuffle = Shuffle::new(iteration_nr, nr_bits, assign_seed, rng);
+        if !cache || !shuffle.has_seed() {
+            return Ok(Arc::new(shuffle));
+        }
+        let index = ShuffleIndex::new(iteration_nr, nr_bits, shuffle.get_seed());
+        Ok(ShuffleCache::global().insert(index, shuffle))
     }
 
     pub fn new_shuffle_from_seed(
@@ -75,75 +326,156 @@ impl Shuffle {
         nr_bits: u32,
         seed: u64,
         cache: bool,
-    ) -> SharedShuffle {
-        assert!(iteration_nr > 0);
-        let index = ShuffleIndex::new(iteration_nr, nr_bits, true);
-        let shuffle = if cache {
-            CACHE.with(|c| {
-                if let Some(shuffle) = c.borrow().get(&index) {
-                    return Rc::clone(shuffle);
-                }
-
-                let shuffle = Rc::new(Shuffle::from_seed(iteration_nr, nr_bits, seed));
-                c.borrow_mut().insert(index, Rc::clone(&shuffle));
-                shuffle
-            })
-        } else {
-            Rc::new(Shuffle::from_seed(iteration_nr, nr_bits, seed))
-        };
+    ) -> Result<SharedShuffle> {
+        Self::new_shuffle_from_seed_with_backend(
+            iteration_nr,
+            nr_bits,
+            seed,
+            ShuffleBackend::Table,
+            cache,
+        )
+    }
 
-        shuffle
+    /// Same as [`new_shuffle_from_seed`](Self::new_shuffle_from_seed), with the given backend.
+    ///
+    /// The backends compute different permutations from the same seed,
+    /// both peers must use the same backend.
+    pub fn new_shuffle_from_seed_with_backend(
+        iteration_nr: u32,
+        nr_bits: u32,
+        seed: u64,
+        backend: ShuffleBackend,
+        cache: bool,
+    ) -> Result<SharedShuffle> {
+        if cache {
+            return ShuffleCache::global().get_or_create_from_seed(
+                iteration_nr,
+                nr_bits,
+                seed,
+                backend,
+            );
+        }
+        check_iteration_nr(iteration_nr)?;
+        Ok(Arc::new(match backend {
+            ShuffleBackend::Table => Shuffle::from_seed(iteration_nr, nr_bits, seed),
+            ShuffleBackend::Feistel => Shuffle::feistel_from_seed(iteration_nr, nr_bits, seed),
+        }))
     }
 
-    fn new(iteration_nr: u32, nr_bits: u32, assign_seed: bool) -> Self {
-        let mut shuffle = Self {
+    /// Shuffle of a recorded permutation, e.g. received from a peer or read from a golden run.
+    ///
+    /// The map must be a permutation of `0..shuffled_to_orig_map.len()`.
+    pub fn from_shuffled_to_orig_map(
+        iteration_nr: u32,
+        shuffled_to_orig_map: Vec<u32>,
+    ) -> Result<SharedShuffle> {
+        let nr_bits = shuffled_to_orig_map.len() as u32;
+        let mut orig_to_shuffled_map = vec![u32::MAX; nr_bits as usize];
+        for (shuffled_bit_nr, &orig_bit_nr) in shuffled_to_orig_map.iter().enumerate() {
+            // out of range, or shuffled twice
+            if orig_bit_nr >= nr_bits || orig_to_shuffled_map[orig_bit_nr as usize] != u32::MAX {
+                return Err(Error::InvalidPermutation { orig_bit_nr });
+            }
+            orig_to_shuffled_map[orig_bit_nr as usize] = shuffled_bit_nr as u32;
+        }
+        Ok(Arc::new(Self {
             iteration_nr,
             nr_bits,
             has_seed: false,
             seed: 0,
-            orig_to_shuffled_map: vec![0; nr_bits as usize],
-            shuffled_to_orig_map: Vec::with_capacity(nr_bits as usize),
-        };
-        shuffle.initialize(assign_seed);
-        shuffle
+            permutation: Permutation::Table {
+                orig_to_shuffled_map,
+                shuffled_to_orig_map,
+            },
+        }))
+    }
+
+    /// Statistics of the process-wide shuffle cache.
+    pub fn cache_stats() -> CacheStats {
+        ShuffleCache::global().stats()
+    }
+
+    /// Number of shuffles in the process-wide cache.
+    pub fn cache_len() -> usize {
+        ShuffleCache::global().len()
+    }
+
+    pub fn is_cached(index: &ShuffleIndex) -> bool {
+        ShuffleCache::global().contains(index)
+    }
+
+    /// Set the maximum number of shuffles in the process-wide cache, see
+    /// [`ShuffleCache::set_capacity`].
+    pub fn set_cache_capacity(capacity: usize) {
+        ShuffleCache::global().set_capacity(capacity);
+    }
+
+    /// Remove all shuffles from the process-wide cache and reset the statistics.
+    pub fn clear_cache() {
+        ShuffleCache::global().clear();
+    }
+
+    fn new<R: RngCore + CryptoRng + ?Sized>(
+        iteration_nr: u32,
+        nr_bits: u32,
+        assign_seed: bool,
+        rng: &mut R,
+    ) -> Self {
+        let mut shuffled_to_orig_map: Vec<u32> = (0..nr_bits).collect();
+        let mut seed = 0;
+        if iteration_nr != 1 {
+            if assign_seed {
+                seed = rng.gen();
+                // portable, see the specification in shuffle_rng
+                ShuffleRng::from_seed(seed).shuffle(&mut shuffled_to_orig_map);
+            } else {
+                shuffled_to_orig_map.shuffle(rng);
+            }
+        }
+        Self {
+            iteration_nr,
+            nr_bits,
+            has_seed: assign_seed && iteration_nr != 1,
+            seed,
+            permutation: Permutation::table(shuffled_to_orig_map),
+        }
     }
 
     fn from_seed(iteration_nr: u32, nr_bits: u32, seed: u64) -> Self {
-        let mut shuffle = Self {
+        let mut shuffled_to_orig_map: Vec<u32> = (0..nr_bits).collect();
+        if iteration_nr != 1 {
+            ShuffleRng::from_seed(seed).shuffle(&mut shuffled_to_orig_map);
+        }
+        Self {
             iteration_nr,
             nr_bits,
             has_seed: true,
             seed,
-            orig_to_shuffled_map: vec![0; nr_bits as usize],
-            shuffled_to_orig_map: Vec::with_capacity(nr_bits as usize),
-        };
-        shuffle.initialize(false);
-        shuffle
+            permutation: Permutation::table(shuffled_to_orig_map),
+        }
     }
 
-    fn initialize(&mut self, assign_seed: bool) {
-        for bit_nr in 0..self.nr_bits {
-            self.shuffled_to_orig_map.push(bit_nr);
+    fn feistel_from_seed(iteration_nr: u32, nr_bits: u32, seed: u64) -> Self {
+        let permutation = if iteration_nr == 1 {
+            Permutation::Identity
+        } else {
+            Permutation::Feistel(FeistelPermutation::new(nr_bits, seed))
+        };
+        Self {
+            iteration_nr,
+            nr_bits,
+            has_seed: true,
+            seed,
+            permutation,
         }
+    }
 
-        if self.iteration_nr != 1 {
-            if assign_seed {
-                assert!(!self.has_seed);
-                self.has_seed = true;
-                self.seed = rand::thread_rng().gen();
-            }
-            if self.has_seed {
-                let mut rng = StdRng::seed_from_u64(self.seed);
-                self.shuffled_to_orig_map.shuffle(&mut rng);
-            } else {
-                // lazily-initialized thread local RNG, avoids the cost of constructing a new one
-                self.shuffled_to_orig_map.shuffle(&mut rand::thread_rng());
-            }
-        }
-        // Compute the reverse mapping of original key bits to shuffled key bits.
-        for (shuffled_bit_nr, &orig_bit_nr) in self.shuffled_to_orig_map.iter().enumerate() {
-            self.orig_to_shuffled_map[orig_bit_nr as usize] = shuffled_bit_nr as u32;
-        }
+    pub fn get_iteration_nr(&self) -> u32 {
+        self.iteration_nr
+    }
+
+    pub fn has_seed(&self) -> bool {
+        self.has_seed
     }
 
     pub fn get_seed(&self) -> u64 {
@@ -154,44 +486,230 @@ impl Shuffle {
         self.nr_bits
     }
 
+    pub fn get_backend(&self) -> ShuffleBackend {
+        match self.permutation {
+            Permutation::Table { .. } => ShuffleBackend::Table,
+            // the table backend stores the identity of iteration 1 as tables
+            Permutation::Identity | Permutation::Feistel(_) => ShuffleBackend::Feistel,
+        }
+    }
+
+    /// Heap memory used by the permutation, in bytes.
+    pub fn heap_size(&self) -> usize {
+        match &self.permutation {
+            Permutation::Table {
+                orig_to_shuffled_map,
+                shuffled_to_orig_map,
+            } => {
+                std::mem::size_of_val(orig_to_shuffled_map.as_slice())
+                    + std::mem::size_of_val(shuffled_to_orig_map.as_slice())
+            }
+            Permutation::Identity | Permutation::Feistel(_) => 0,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the bit is out of range, see [`Shuffle::try_orig_to_shuffle`]
     pub fn orig_to_shuffle(&self, orig_bit_nr: u32) -> u32 {
-        self.orig_to_shuffled_map[orig_bit_nr as usize]
+        match &self.permutation {
+            Permutation::Identity => {
+                assert!(orig_bit_nr < self.nr_bits);
+                orig_bit_nr
+            }
+            Permutation::Table {
+                orig_to_shuffled_map,
+                ..
+            } => orig_to_shuffled_map[orig_bit_nr as usize],
+            Permutation::Feistel(permutation) => permutation.permute(orig_bit_nr),
+        }
+    }
+
+    /// [`Shuffle::orig_to_shuffle`] of a bit from outside, e.g. a peer request.
+    pub fn try_orig_to_shuffle(&self, orig_bit_nr: u32) -> Result<u32> {
+        self.check_bit_nr(orig_bit_nr)?;
+        Ok(self.orig_to_shuffle(orig_bit_nr))
     }
 
+    /// # Panics
+    ///
+    /// Panics if the bit is out of range, see [`Shuffle::try_shuffle_to_orig`]
     pub fn shuffle_to_orig(&self, shuffle_bit_nr: u32) -> u32 {
-        self.shuffled_to_orig_map[shuffle_bit_nr as usize]
+        match &self.permutation {
+            Permutation::Identity => {
+                assert!(shuffle_bit_nr < self.nr_bits);
+                shuffle_bit_nr
+            }
+            Permutation::Table {
+                shuffled_to_orig_map,
+                ..
+            } => shuffled_to_orig_map[shuffle_bit_nr as usize],
+            Permutation::Feistel(permutation) => permutation.invert(shuffle_bit_nr),
+        }
+    }
+
+    /// [`Shuffle::shuffle_to_orig`] of a bit from outside, e.g. a peer request.
+    pub fn try_shuffle_to_orig(&self, shuffle_bit_nr: u32) -> Result<u32> {
+        self.check_bit_nr(shuffle_bit_nr)?;
+        Ok(self.shuffle_to_orig(shuffle_bit_nr))
+    }
+
+    fn check_bit_nr(&self, bit_nr: u32) -> Result<()> {
+        if bit_nr >= self.nr_bits {
+            return Err(Error::BitOutOfRange {
+                bit_nr,
+                nr_bits: self.nr_bits,
+            });
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::shuffle::{self, CACHE};
+    use std::sync::Arc;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use crate::{
+        error::Error,
+        shuffle::{CacheStats, ShuffleCache, ShuffleIndex},
+    };
+
+    use super::{Shuffle, ShuffleBackend};
 
-    use super::Shuffle;
+    fn orig_to_shuffled_map(shuffle: &Shuffle) -> Vec<u32> {
+        (0..shuffle.get_nr_bits())
+            .map(|bit_nr| shuffle.orig_to_shuffle(bit_nr))
+            .collect()
+    }
+
+    fn shuffled_to_orig_map(shuffle: &Shuffle) -> Vec<u32> {
+        (0..shuffle.get_nr_bits())
+            .map(|bit_nr| shuffle.shuffle_to_orig(bit_nr))
+            .collect()
+    }
 
     #[test]
     fn test_random_shuffle() {
         // no shuffle at iteration 1
-        let shuffle = Shuffle::new_random_shuffle(1, 10, true, false);
-        assert_eq!(shuffle.orig_to_shuffled_map, shuffle.shuffled_to_orig_map);
+        let shuffle = Shuffle::new_random_shuffle(1, 10, true, false).unwrap();
+        assert_eq!(
+            orig_to_shuffled_map(&shuffle),
+            shuffled_to_orig_map(&shuffle)
+        );
         assert_eq!(0, shuffle.get_seed());
         // shuffle at iteration 2
-        let shuffle = Shuffle::new_random_shuffle(2, 10, true, false);
-        assert_ne!(shuffle.orig_to_shuffled_map, shuffle.shuffled_to_orig_map);
+        let shuffle = Shuffle::new_random_shuffle(2, 10, true, false).unwrap();
+        assert_ne!(
+            orig_to_shuffled_map(&shuffle),
+            shuffled_to_orig_map(&shuffle)
+        );
         let shuffled_bit_nr = 5;
         let ori_bit_nr = shuffle.shuffle_to_orig(shuffled_bit_nr);
         assert_eq!(shuffled_bit_nr, shuffle.orig_to_shuffle(ori_bit_nr));
+
+        // random shuffles are not shared between sessions, even with the cache
+        for assign_seed in [false, true] {
+            let shuffle = Shuffle::new_random_shuffle(2, 100, assign_seed, true).unwrap();
+            let other_shuffle = Shuffle::new_random_shuffle(2, 100, assign_seed, true).unwrap();
+            assert!(!Arc::ptr_eq(&shuffle, &other_shuffle));
+            assert_ne!(
+                shuffled_to_orig_map(&shuffle),
+                shuffled_to_orig_map(&other_shuffle)
+            );
+        }
+    }
+
+    #[test]
+    fn test_random_shuffle_with_rng() {
+        let random_shuffle = |assign_seed, cache, rng_seed| {
+            Shuffle::new_random_shuffle_with_rng(
+                2,
+                100,
+                assign_seed,
+                cache,
+                &mut StdRng::seed_from_u64(rng_seed),
+            )
+            .unwrap()
+        };
+        // the generator decides, with or without the cache
+        for assign_seed in [false, true] {
+            for cache in [false, true] {
+                let shuffle = random_shuffle(assign_seed, cache, 1234);
+                assert_eq!(assign_seed, shuffle.has_seed());
+                assert_eq!(
+                    shuffled_to_orig_map(&shuffle),
+                    shuffled_to_orig_map(&random_shuffle(assign_seed, cache, 1234))
+                );
+                assert_ne!(
+                    shuffled_to_orig_map(&shuffle),
+                    shuffled_to_orig_map(&random_shuffle(assign_seed, cache, 4321))
+                );
+            }
+        }
     }
 
     #[test]
     fn test_random_shuffle_from_seed() {
         const SEED: u64 = 123456789;
-        let shuffle = Shuffle::new_shuffle_from_seed(2, 10, SEED, false);
+        let shuffle = Shuffle::new_shuffle_from_seed(2, 10, SEED, false).unwrap();
         assert_eq!(SEED, shuffle.get_seed());
-        assert_ne!(shuffle.orig_to_shuffled_map, shuffle.shuffled_to_orig_map);
+        assert_ne!(
+            orig_to_shuffled_map(&shuffle),
+            shuffled_to_orig_map(&shuffle)
+        );
         let shuffled_bit_nr = 5;
         let ori_bit_nr = shuffle.shuffle_to_orig(shuffled_bit_nr);
         assert_eq!(shuffled_bit_nr, shuffle.orig_to_shuffle(ori_bit_nr));
+
+        // test vector of the portable shuffle, see shuffle_rng
+        let shuffle = Shuffle::new_shuffle_from_seed(2, 10, 0, false).unwrap();
+        assert_eq!(
+            vec![6, 8, 2, 7, 9, 5, 1, 0, 3, 4],
+            shuffled_to_orig_map(&shuffle)
+        );
+    }
+
+    #[test]
+    fn test_shuffle_from_map() {
+        let shuffle = Shuffle::from_shuffled_to_orig_map(2, vec![2, 0, 3, 1]).unwrap();
+        assert_eq!(vec![2, 0, 3, 1], shuffled_to_orig_map(&shuffle));
+        assert_eq!(vec![1, 3, 0, 2], orig_to_shuffled_map(&shuffle));
+        assert_eq!(Ok(3), shuffle.try_orig_to_shuffle(1));
+        assert_eq!(Ok(0), shuffle.try_shuffle_to_orig(1));
+        let out_of_range = Err(Error::BitOutOfRange {
+            bit_nr: 4,
+            nr_bits: 4,
+        });
+        assert_eq!(out_of_range, shuffle.try_orig_to_shuffle(4));
+        assert_eq!(out_of_range, shuffle.try_shuffle_to_orig(4));
+    }
+
+    #[test]
+    fn test_shuffle_from_invalid_map() {
+        // shuffled twice
+        assert_eq!(
+            Some(Error::InvalidPermutation { orig_bit_nr: 2 }),
+            Shuffle::from_shuffled_to_orig_map(2, vec![2, 0, 2, 1]).err()
+        );
+        // out of range
+        assert_eq!(
+            Some(Error::InvalidPermutation { orig_bit_nr: 4 }),
+            Shuffle::from_shuffled_to_orig_map(2, vec![4, 0, 2, 1]).err()
+        );
+    }
+
+    #[test]
+    fn test_invalid_iteration_nr() {
+        assert_eq!(
+            Some(Error::InvalidIterationNr(0)),
+            Shuffle::new_shuffle_from_seed(0, 10, 1, false).err()
+        );
+        assert_eq!(
+            Some(Error::InvalidIterationNr(0)),
+            Shuffle::new_random_shuffle(0, 10, true, false).err()
+        );
     }
 
     #[test]
@@ -199,12 +717,209 @@ mod tests {
         const SEED: u64 = 123456789;
         const NUM_BITS: u32 = 4;
         let max_nr = 2u32.pow(4);
+        // the process-wide cache is shared with the other tests, use a local one
+        let cache = ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY);
         // fill the cache
         for i in 1..=max_nr {
-            let _ = Shuffle::new_shuffle_from_seed(i, NUM_BITS, SEED, true);
+            let _ = cache
+                .get_or_create_from_seed(i, NUM_BITS, SEED, ShuffleBackend::Table)
+                .unwrap();
         }
-        CACHE.with(|c| {
-            assert_eq!(max_nr as usize, c.borrow().len());
+        assert_eq!(max_nr as usize, cache.len());
+        assert_eq!(
+            Some(Error::InvalidIterationNr(0)),
+            cache
+                .get_or_create_from_seed(0, NUM_BITS, SEED, ShuffleBackend::Table)
+                .err()
+        );
+    }
+
+    #[test]
+    fn test_shuffle_cache_uses_seed() {
+        const NUM_BITS: u32 = 100;
+        let cache = ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY);
+        let get = |seed: u64| {
+            cache
+                .get_or_create_from_seed(2, NUM_BITS, seed, ShuffleBackend::Table)
+                .unwrap()
+        };
+        let shuffle = get(1111);
+        let other_shuffle = get(2222);
+        assert_eq!(2222, other_shuffle.get_seed());
+        assert_ne!(
+            shuffled_to_orig_map(&shuffle),
+            shuffled_to_orig_map(&other_shuffle)
+        );
+
+        let same_shuffle = get(1111);
+        assert!(Arc::ptr_eq(&shuffle, &same_shuffle));
+        assert!(cache.contains(&ShuffleIndex::new(2, NUM_BITS, 1111)));
+        // the same permutation as without the cache
+        assert_eq!(
+            shuffled_to_orig_map(&shuffle),
+            shuffled_to_orig_map(
+                &Shuffle::new_shuffle_from_seed(2, NUM_BITS, 1111, false).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_shuffle_cache_inserts() {
+        const NUM_BITS: u32 = 100;
+        let cache = ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY);
+        let shuffle = cache.insert(
+            ShuffleIndex::new(2, NUM_BITS, 1111),
+            Shuffle::from_seed(2, NUM_BITS, 1111),
+        );
+        // an insert is not a lookup, the first lookup finds the shuffle
+        let same_shuffle = cache
+            .get_or_create_from_seed(2, NUM_BITS, 1111, ShuffleBackend::Table)
+            .unwrap();
+        assert!(Arc::ptr_eq(&shuffle, &same_shuffle));
+        assert_eq!(
+            CacheStats {
+                hits: 1,
+                misses: 0,
+                inserts: 1,
+                evictions: 0
+            },
+            cache.stats()
+        );
+    }
+
+    #[test]
+    fn test_shuffle_cache_lru() {
+        const NUM_BITS: u32 = 10;
+        let cache = ShuffleCache::new(2);
+        let get = |seed: u64| {
+            cache
+                .get_or_create_from_seed(2, NUM_BITS, seed, ShuffleBackend::Table)
+                .unwrap()
+        };
+        let first = get(1);
+        let _second = get(2);
+        // use the first shuffle, so that the second one is the least recently used
+        let _ = get(1);
+        let _third = get(3);
+        assert_eq!(2, cache.len());
+        assert_eq!(1, cache.stats().evictions);
+
+        // the first shuffle is still cached, the second one was evicted
+        let again = get(1);
+        assert!(Arc::ptr_eq(&first, &again));
+        let _ = get(2);
+        assert_eq!(
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                inserts: 0,
+                evictions: 2
+            },
+            cache.stats()
+        );
+
+        // capacity 0 disables the cache
+        cache.set_capacity(0);
+        assert!(cache.is_empty());
+        let _ = get(1);
+        assert!(cache.is_empty());
+
+        cache.clear();
+        assert_eq!(CacheStats::default(), cache.stats());
+    }
+
+    #[test]
+    fn test_shuffle_cache_shared_between_threads() {
+        const NUM_BITS: u32 = 10000;
+        const SEED: u64 = 3333;
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Shuffle>();
+        assert_send_sync::<ShuffleCache>();
+
+        let cache = ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY);
+        let shuffles: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        cache
+                            .get_or_create_from_seed(3, NUM_BITS, SEED, ShuffleBackend::Table)
+                            .unwrap()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
         });
+        // built once, shared by all threads
+        for shuffle in &shuffles[1..] {
+            assert!(Arc::ptr_eq(&shuffles[0], shuffle));
+        }
+        assert_eq!(1, cache.stats().misses);
+        assert_eq!(3, cache.stats().hits);
+    }
+
+    #[test]
+    fn test_feistel_shuffle() {
+        const SEED: u64 = 123456789;
+        const NUM_BITS: u32 = 1000;
+        // no shuffle at iteration 1
+        let shuffle = Shuffle::new_shuffle_from_seed_with_backend(
+            1,
+            10,
+            SEED,
+            ShuffleBackend::Feistel,
+            false,
+        )
+        .unwrap();
+        assert_eq!(ShuffleBackend::Feistel, shuffle.get_backend());
+        assert_eq!(
+            (0..10).collect::<Vec<u32>>(),
+            orig_to_shuffled_map(&shuffle)
+        );
+
+        let shuffle = Shuffle::new_shuffle_from_seed_with_backend(
+            2,
+            NUM_BITS,
+            SEED,
+            ShuffleBackend::Feistel,
+            false,
+        )
+        .unwrap();
+        assert_eq!(SEED, shuffle.get_seed());
+        assert_eq!(0, shuffle.heap_size());
+        assert_ne!(
+            orig_to_shuffled_map(&shuffle),
+            shuffled_to_orig_map(&shuffle)
+        );
+        for shuffled_bit_nr in 0..NUM_BITS {
+            let ori_bit_nr = shuffle.shuffle_to_orig(shuffled_bit_nr);
+            assert_eq!(shuffled_bit_nr, shuffle.orig_to_shuffle(ori_bit_nr));
+        }
+
+        let table_shuffle = Shuffle::new_shuffle_from_seed(2, NUM_BITS, SEED, false).unwrap();
+        assert_eq!(ShuffleBackend::Table, table_shuffle.get_backend());
+        assert_eq!(8 * NUM_BITS as usize, table_shuffle.heap_size());
+    }
+
+    #[test]
+    fn test_feistel_shuffle_cache() {
+        const SEED: u64 = 1234;
+        let cache = ShuffleCache::new(ShuffleCache::DEFAULT_CAPACITY);
+        let get = |backend| {
+            cache
+                .get_or_create_from_seed(2, 100, SEED, backend)
+                .unwrap()
+        };
+        let shuffle = get(ShuffleBackend::Feistel);
+        assert_eq!(ShuffleBackend::Feistel, shuffle.get_backend());
+        assert!(Arc::ptr_eq(&shuffle, &get(ShuffleBackend::Feistel)));
+        // the table shuffle of the same seed is a different cache entry
+        let table_shuffle = get(ShuffleBackend::Table);
+        assert_eq!(ShuffleBackend::Table, table_shuffle.get_backend());
+        let index = ShuffleIndex::new(2, 100, SEED);
+        assert!(cache.contains(&index));
+        assert!(cache.contains(&index.with_backend(ShuffleBackend::Feistel)));
     }
 }
@@ -0,0 +1,120 @@
+//! Portable random stream and Fisher-Yates shuffle for shuffles created from a seed.
+//!
+//! `rand::StdRng` and `SliceRandom::shuffle` may change between rand versions, so they cannot be
+//! used to agree on a permutation with a peer. Shuffles created from a seed use this
+//! specification instead, which any implementation can follow bit for bit:
+//!
+//! ```text
+//! key    = seed as u64 little endian || 24 zero bytes
+//! stream = ChaCha20 keystream (20 rounds), all zero nonce, block counter starting at 0
+//! word   = next 4 bytes of the stream, little endian u32
+//! u64    = word || word, the first word is the low half
+//!
+//! below(bound):                 uniform value in 0..bound, bound > 0
+//!     zone = 2^32 - (2^32 mod bound)
+//!     repeat x = word until x < zone
+//!     return x mod bound
+//!
+//! shuffle of nr_bits:           shuffled_to_orig map
+//!     map = [0, 1, .., nr_bits - 1]
+//!     for i from nr_bits - 1 down to 1:
+//!         swap(map[i], map[below(i + 1)])
+//! ```
+//!
+//! The Feistel backend takes its round keys as the first u64 values of the stream.
+//! The ChaCha20 keystream is the one of RFC 8439 with a 64 bit block counter, which only
+//! differs after 2^32 blocks. Test vectors are in the tests of this module.
+
+use rand::RngCore;
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+
+pub struct ShuffleRng(ChaCha20Rng);
+
+impl ShuffleRng {
+    pub fn from_seed(seed: u64) -> Self {
+        let mut key = [0u8; 32];
+        key[..8].copy_from_slice(&seed.to_le_bytes());
+        Self(ChaCha20Rng::from_seed(key))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let low = self.next_u32() as u64;
+        let high = self.next_u32() as u64;
+        (high << 32) | low
+    }
+
+    /// Uniform value in `0..bound`, by rejection of the words of the incomplete last interval.
+    pub fn below(&mut self, bound: u32) -> u32 {
+        assert!(bound > 0);
+        let zone = (1u64 << 32) - (1u64 << 32) % bound as u64;
+        loop {
+            let x = self.next_u32();
+            if (x as u64) < zone {
+                return x % bound;
+            }
+        }
+    }
+
+    /// Fisher-Yates shuffle from the last element down, as specified in the module documentation.
+    pub fn shuffle(&mut self, values: &mut [u32]) {
+        for i in (1..values.len()).rev() {
+            let j = self.below(i as u32 + 1);
+            values.swap(i, j as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShuffleRng;
+
+    #[test]
+    fn test_chacha20_keystream() {
+        // RFC 8439 appendix A.1, test vector #1: all zero key and nonce, block counter 0
+        const KEYSTREAM: [u32; 4] = [0xade0b876, 0x903df1a0, 0xe56a5d40, 0x28bd8653];
+        let mut rng = ShuffleRng::from_seed(0);
+        let words: Vec<u32> = (0..4).map(|_| rng.next_u32()).collect();
+        assert_eq!(KEYSTREAM.to_vec(), words);
+
+        let mut rng = ShuffleRng::from_seed(0x0123456789abcdef);
+        assert_eq!(0x4fb0e90c4f17ff81, rng.next_u64());
+        assert_eq!(0xfcb649772ba310fb, rng.next_u64());
+    }
+
+    #[test]
+    fn test_below() {
+        let mut rng = ShuffleRng::from_seed(7);
+        let values: Vec<u32> = [1, 2, 3, 10, 1000, 0xffffffff]
+            .into_iter()
+            .map(|bound| rng.below(bound))
+            .collect();
+        assert_eq!(vec![0, 1, 1, 4, 535, 740680722], values);
+    }
+
+    #[test]
+    fn test_shuffle_vectors() {
+        let vectors: [(u64, Vec<u32>); 3] = [
+            (0, vec![6, 8, 2, 7, 9, 5, 1, 0, 3, 4]),
+            (
+                0x0123456789abcdef,
+                vec![13, 3, 2, 8, 12, 7, 4, 9, 6, 10, 0, 11, 15, 14, 5, 1],
+            ),
+            (
+                42,
+                vec![
+                    27, 11, 9, 13, 7, 18, 8, 12, 4, 3, 0, 25, 14, 29, 15, 23, 30, 20, 28, 21, 1,
+                    24, 5, 19, 2, 22, 6, 10, 26, 16, 17, 31,
+                ],
+            ),
+        ];
+        for (seed, shuffled_to_orig_map) in vectors {
+            let mut map: Vec<u32> = (0..shuffled_to_orig_map.len() as u32).collect();
+            ShuffleRng::from_seed(seed).shuffle(&mut map);
+            assert_eq!(shuffled_to_orig_map, map);
+        }
+    }
+}
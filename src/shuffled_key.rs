@@ -1,45 +1,84 @@
-use std::{
-    cell::{Ref, RefCell},
-    rc::Rc,
-};
+use std::{fmt, sync::Arc};
 
-use crate::{key::Key, shuffle::SharedShuffle};
+use zeroize::Zeroize;
 
-pub type SharedKey = Rc<RefCell<Key>>;
-/// ShuffledKey is a key with a shuffle applied to it.
-/// ShuffledKey clones shares the same shuffle and key.
-/// Not thread safe.
+use crate::{
+    key::Key,
+    protocol::{ParityRequest, ParityRequestEncoding, WireStats},
+    shuffle::SharedShuffle,
+};
 
-#[derive(Clone, Debug)]
+/// ShuffledKey is the shuffled copy of the noise key of one iteration.
+///
+/// The bits are kept packed in shuffled order, with a parity index, so that the parity of
+/// a range of shuffled bits is computed in O(log n) with [`Key::compute_range_parity`].
+/// The noise key is owned by the caller, e.g. the [`Reconciliation`](crate::reconciliation::Reconciliation),
+/// and stays the source of truth: bits are always changed in the noise key, and the shuffled copy
+/// replays the changes of the noise key in [`ShuffledKey::sync`].
+/// This keeps the shuffled copies of all iterations coherent, whichever iteration changed the bit.
+/// The reads see the noise key as of the last sync.
+///
+/// Bob asks Alice the correct parities of ranges of shuffled bits with the requests of
+/// [`ShuffledKey::parity_requests`], which also counts their bytes on the wire.
+///
+/// Like [`Key`], `Debug` does not print key bits, use [`ShuffledKey::reveal`] to opt in.
 pub struct ShuffledKey {
-    pub correct_key: Rc<Key>, //TODO: test only, remove this
-    key: SharedKey,
     shuffle: SharedShuffle,
+    // the noise key in shuffled order
+    key: Key,
+    // how many changes of the noise key are applied to `key`
+    nr_synced_changes: usize,
+    encoding: ParityRequestEncoding,
+    wire_stats: WireStats,
 }
 
 impl ShuffledKey {
-    pub fn new(correct_key: Rc<Key>, noise_key: SharedKey, shuffle: SharedShuffle) -> Self {
+    pub fn new(noise_key: &Key, shuffle: SharedShuffle) -> Self {
+        Self::with_encoding(noise_key, shuffle, ParityRequestEncoding::default())
+    }
+
+    /// Shuffled key asking the correct parities with the given request encoding.
+    pub fn with_encoding(
+        noise_key: &Key,
+        shuffle: SharedShuffle,
+        encoding: ParityRequestEncoding,
+    ) -> Self {
+        let mut key = noise_key.shuffled(&shuffle);
+        key.build_parity_index();
         Self {
-            correct_key,
-            key: noise_key,
             shuffle,
+            key,
+            nr_synced_changes: noise_key.get_nr_changes(),
+            encoding,
+            wire_stats: WireStats::default(),
         }
     }
+
+    /// Apply the changes of the noise key made since the last sync.
+    pub fn sync(&mut self, noise_key: &Key) {
+        if self.nr_synced_changes != noise_key.get_nr_changes() {
+            for &orig_bit_nr in noise_key.changes_since(self.nr_synced_changes) {
+                let shuffle_bit_nr = self.shuffle.orig_to_shuffle(orig_bit_nr);
+                self.key.flip_bit(shuffle_bit_nr);
+            }
+            self.nr_synced_changes = noise_key.get_nr_changes();
+        }
+    }
+
     pub fn get_estimated_ber(&self) -> f32 {
-        self.key.borrow().get_estimated_ber()
+        self.key.get_estimated_ber()
     }
     pub fn get_shuffle(&self) -> SharedShuffle {
-        Rc::clone(&self.shuffle)
+        Arc::clone(&self.shuffle)
     }
 
     pub fn get_nr_bits(&self) -> u32 {
-        self.key.borrow().get_nr_bits()
+        self.key.get_nr_bits()
     }
 
-    /// get bit in the original key
+    /// get bit in the shuffled key
     pub fn get_bit(&self, bit_nr: u32) -> u8 {
-        let orig_bit_nr = self.shuffle.shuffle_to_orig(bit_nr);
-        self.key.borrow().get_bit(orig_bit_nr)
+        self.key.get_bit(bit_nr)
     }
 
     pub fn shuffle_to_orig_bit_nr(&self, shuffle_bit_nr: u32) -> u32 {
@@ -49,74 +88,85 @@ impl ShuffledKey {
         self.shuffle.orig_to_shuffle(orig_bit_nr)
     }
 
-    /// set bit in the original key
-    pub fn set_bit(&self, bit_nr: u32, value: u8) {
+    /// set bit in the noise key, and sync
+    pub fn set_bit(&mut self, noise_key: &mut Key, bit_nr: u32, value: u8) {
         let orig_bit_nr = self.shuffle.shuffle_to_orig(bit_nr);
-        self.key.borrow_mut().set_bit(orig_bit_nr, value);
+        noise_key.set_bit(orig_bit_nr, value);
+        self.sync(noise_key);
     }
 
-    /// flip bit in the original key
-    pub fn flip_bit(&self, bit_nr: u32) {
+    /// flip bit in the noise key, and sync
+    pub fn flip_bit(&mut self, noise_key: &mut Key, bit_nr: u32) {
         let orig_bit_nr = self.shuffle.shuffle_to_orig(bit_nr);
-        self.key.borrow_mut().flip_bit(orig_bit_nr);
+        noise_key.flip_bit(orig_bit_nr);
+        self.sync(noise_key);
     }
 
     pub fn compute_range_parity(&self, start_bit_nr: u32, end_bit_nr: u32) -> u8 {
-        let mut parity = 0;
-        // have to get the index of the bit in the original key first
-        // so we can not use original key's compute_range_parity method
-        for bit_nr in start_bit_nr..=end_bit_nr {
-            let orig_bit_nr = self.shuffle.shuffle_to_orig(bit_nr);
-            if self.key.borrow().get_bit(orig_bit_nr) == 1 {
-                parity = 1 - parity;
-            }
-        }
-        parity
-    }
-
-    // TODO: this is for testing only,
-    pub(crate) fn ask_correct_range_parity(&self, start_bit_nr: u32, end_bit_nr: u32) -> u8 {
-        let mut parity = 0;
-        // have to get the index of the bit in the original key first
-        // so we can not use original key's compute_range_parity method
-        for bit_nr in start_bit_nr..=end_bit_nr {
-            let orig_bit_nr = self.shuffle.shuffle_to_orig(bit_nr);
-            if self.correct_key.get_bit(orig_bit_nr) == 1 {
-                parity = 1 - parity;
-            }
-        }
-        parity
+        self.key.compute_range_parity(start_bit_nr, end_bit_nr)
+    }
+
+    /// The requests asking Alice the correct parities of the inclusive ranges of shuffled bits,
+    /// sent together in one round trip.
+    pub fn parity_requests(&mut self, ranges: &[(u32, u32)]) -> Vec<ParityRequest> {
+        let requests: Vec<ParityRequest> = ranges
+            .iter()
+            .map(|&(start_bit_nr, end_bit_nr)| {
+                ParityRequest::new(self.encoding, &self.shuffle, start_bit_nr, end_bit_nr)
+            })
+            .collect();
+        self.wire_stats += WireStats::of_round_trip(&requests);
+        requests
     }
-    //TODO: testing only
-    pub(crate) fn get_noise_key(&self) -> Ref<'_, Key> {
-        self.key.borrow()
+
+    /// Bytes on the wire of the parity requests of this shuffled key.
+    pub fn get_wire_stats(&self) -> WireStats {
+        self.wire_stats
     }
-    pub(crate) fn get_correct_key(&self) -> &Rc<Key> {
-        &self.correct_key
+    /// Opt in to printing the key bits in shuffled order.
+    pub fn reveal(&self) -> RevealedShuffledKey<'_> {
+        RevealedShuffledKey(self)
     }
 }
 
-impl std::fmt::Display for ShuffledKey {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut s = String::new();
+impl fmt::Debug for ShuffledKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShuffledKey")
+            .field("nr_bits", &self.get_nr_bits())
+            .field("key", &"<redacted>")
+            .field("shuffle_seed", &self.shuffle.get_seed())
+            .finish()
+    }
+}
+
+/// Printable view of the shuffled key bits, created by [`ShuffledKey::reveal`].
+pub struct RevealedShuffledKey<'a>(&'a ShuffledKey);
+
+impl fmt::Display for RevealedShuffledKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shuffled_key = self.0;
+        let mut s = String::with_capacity(shuffled_key.get_nr_bits() as usize);
         // Print key bits in natural order from LSB to MSB
-        for bit_nr in 0..self.get_nr_bits() {
-            // '0' ascii code is 48
-            let zero = '0' as u8;
-            s.push(char::from(zero + self.get_bit(bit_nr)));
+        for bit_nr in 0..shuffled_key.get_nr_bits() {
+            s.push(char::from(b'0' + shuffled_key.get_bit(bit_nr)));
         }
-        write!(f, "{}", s)
+        let result = write!(f, "{}", s);
+        s.zeroize();
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        cell::{Ref, RefCell},
-        rc::Rc,
-    };
+    use std::sync::Arc;
 
-    use crate::{key::Key, random, shuffle::Shuffle, shuffled_key::ShuffledKey};
+    use crate::{
+        key::Key,
+        protocol::{ParityRequest, ParityRequestEncoding},
+        random,
+        shuffle::Shuffle,
+        shuffled_key::ShuffledKey,
+    };
     #[test]
     fn test_compute_parity() {
         const SEED: u64 = 12345678;
@@ -125,23 +175,105 @@ mod tests {
         const KEY_SIZE: u32 = ORIGINAL_KEY.len() as u32;
 
         // random key
-        random::set_random_uint32_seed(SEED as u32);
-        let correct_key = Key::from(ORIGINAL_KEY);
-        let key: Rc<RefCell<_>> = Rc::new(RefCell::new(correct_key.clone()));
+        random::set_random_seed(SEED);
+        let mut key = Key::from(ORIGINAL_KEY);
 
         // random shuffle
-        let shuffle = Shuffle::new_shuffle_from_seed(2, KEY_SIZE, SEED, true);
-        let shuffled_key =
-            ShuffledKey::new(Rc::new(correct_key), Rc::clone(&key), Rc::clone(&shuffle));
+        let shuffle = Shuffle::new_shuffle_from_seed(2, KEY_SIZE, SEED, true).unwrap();
+        let mut shuffled_key = ShuffledKey::new(&key, Arc::clone(&shuffle));
 
-        let ori_parity: u8 = key.borrow().compute_range_parity(0, KEY_SIZE - 1);
+        let ori_parity: u8 = key.compute_range_parity(0, KEY_SIZE - 1);
         let shuffled_parity = shuffled_key.compute_range_parity(0, KEY_SIZE - 1);
         assert_eq!(ori_parity, shuffled_parity);
 
         const BIT_NR: u32 = 2;
-        shuffled_key.flip_bit(BIT_NR);
-        let ori_parity = key.borrow().compute_range_parity(0, KEY_SIZE - 1);
+        shuffled_key.flip_bit(&mut key, BIT_NR);
+        let ori_parity = key.compute_range_parity(0, KEY_SIZE - 1);
         let shuffled_parity = shuffled_key.compute_range_parity(0, KEY_SIZE - 1);
         assert_eq!(ori_parity, shuffled_parity);
     }
+
+    #[test]
+    fn test_changes_are_coherent() {
+        const ORIGINAL_KEY: &str =
+            "1011000010101111010010001001000011001100110001011010100001010111";
+        const KEY_SIZE: u32 = ORIGINAL_KEY.len() as u32;
+        let mut key = Key::from(ORIGINAL_KEY);
+
+        // two iterations with different shuffles over the same key
+        let mut shuffled_keys: Vec<ShuffledKey> = [(2, 1111), (3, 2222)]
+            .into_iter()
+            .map(|(iteration_nr, seed)| {
+                let shuffle =
+                    Shuffle::new_shuffle_from_seed(iteration_nr, KEY_SIZE, seed, false).unwrap();
+                ShuffledKey::new(&key, shuffle)
+            })
+            .collect();
+
+        let assert_coherent = |shuffled_keys: &mut Vec<ShuffledKey>, key: &Key| {
+            for shuffled_key in shuffled_keys.iter_mut() {
+                shuffled_key.sync(key);
+                for bit_nr in 0..KEY_SIZE {
+                    let orig_bit_nr = shuffled_key.shuffle_to_orig_bit_nr(bit_nr);
+                    assert_eq!(key.get_bit(orig_bit_nr), shuffled_key.get_bit(bit_nr));
+                }
+                for end_bit_nr in 0..KEY_SIZE {
+                    let mut parity = 0;
+                    for bit_nr in 0..=end_bit_nr {
+                        parity ^= shuffled_key.get_bit(bit_nr);
+                    }
+                    assert_eq!(parity, shuffled_key.compute_range_parity(0, end_bit_nr));
+                }
+            }
+        };
+
+        // flip through one iteration
+        shuffled_keys[0].flip_bit(&mut key, 5);
+        let bit = shuffled_keys[0].get_bit(6);
+        shuffled_keys[0].set_bit(&mut key, 6, 1 - bit);
+        assert_coherent(&mut shuffled_keys, &key);
+        // flip through the other iteration
+        shuffled_keys[1].flip_bit(&mut key, 5);
+        shuffled_keys[1].flip_bit(&mut key, 63);
+        assert_coherent(&mut shuffled_keys, &key);
+        // flip the original key directly
+        key.flip_bit(0);
+        assert_coherent(&mut shuffled_keys, &key);
+    }
+
+    #[test]
+    fn test_parity_requests() {
+        let key = Key::from("1011000010101111010010001001000011001100110001011010100001010111");
+        let shuffle = Shuffle::new_shuffle_from_seed(2, 64, 1234, false).unwrap();
+        let mut shuffled_key =
+            ShuffledKey::with_encoding(&key, shuffle.clone(), ParityRequestEncoding::ShuffleSeed);
+        let requests = shuffled_key.parity_requests(&[(0, 31), (32, 63)]);
+        assert_eq!(
+            vec![
+                ParityRequest::new(ParityRequestEncoding::ShuffleSeed, &shuffle, 0, 31),
+                ParityRequest::new(ParityRequestEncoding::ShuffleSeed, &shuffle, 32, 63),
+            ],
+            requests
+        );
+        assert!(shuffled_key.parity_requests(&[]).is_empty());
+        // one round trip of two requests
+        let wire_stats = shuffled_key.get_wire_stats();
+        assert_eq!(2, wire_stats.nr_requests);
+        assert_eq!(1, wire_stats.nr_round_trips);
+        assert_eq!(2 * 21, wire_stats.request_bytes);
+    }
+
+    #[test]
+    fn test_debug_is_redacted() {
+        const ORIGINAL_KEY: &str =
+            "1011000010101111010010001001000011001100110001011010100001010111";
+        let key = Key::from(ORIGINAL_KEY);
+        let shuffle = Shuffle::new_shuffle_from_seed(1, 64, 0, false).unwrap();
+        let shuffled_key = ShuffledKey::new(&key, shuffle);
+
+        let debug = format!("{:?}", shuffled_key);
+        assert!(debug.contains("<redacted>"));
+        assert!(!debug.contains(ORIGINAL_KEY));
+        assert_eq!(ORIGINAL_KEY, shuffled_key.reveal().to_string());
+    }
 }
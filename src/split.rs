@@ -0,0 +1,133 @@
+//! How the binary search splits a block with an odd number of errors, see
+//! [`Algorithm::split_strategy`](crate::algorithm::Algorithm::split_strategy).
+//!
+//! The correct parities of all sub blocks but one are asked, in one round trip. The parity of the
+//! remaining sub block is inferred from the parity of the block and the parities of its siblings.
+//! The search continues in a sub block with an odd number of errors.
+
+/// How to split a block, and which sub block has its correct parity inferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SplitStrategy {
+    /// Split in halves, the left half takes the larger half of an odd sized block.
+    /// Ask the left half, infer the right half.
+    #[default]
+    Bisect,
+    /// Split in halves as [`SplitStrategy::Bisect`], ask the smaller half and infer the larger one.
+    /// The request of a smaller block is shorter as a bit list.
+    AskSmaller,
+    /// The left sub block is the largest power of two smaller than the block, ask it and infer
+    /// the right sub block. The split points are relative to the start of the block; they do not
+    /// line up with the blocks of other iterations, which use other shuffles.
+    PowerOfTwo,
+    /// Split in `k` parts of about equal size, the first parts take the remainder.
+    /// Ask the first `k - 1` parities in one round trip and infer the last one: fewer round trips
+    /// than bisecting, more parities asked.
+    KAry(u8),
+}
+
+impl SplitStrategy {
+    /// The sub blocks of the block `start_bit_nr..=end_bit_nr`, as inclusive ranges in order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block is a single bit, or for a k-ary split with `k < 2`
+    pub fn split(&self, start_bit_nr: u32, end_bit_nr: u32) -> Vec<(u32, u32)> {
+        assert!(start_bit_nr < end_bit_nr, "cannot split a single bit");
+        let nr_bits = end_bit_nr - start_bit_nr + 1;
+        let left_nr_bits = match self {
+            Self::Bisect | Self::AskSmaller => nr_bits.div_ceil(2),
+            // the largest power of two below nr_bits
+            Self::PowerOfTwo => 1 << (u32::BITS - 1 - (nr_bits - 1).leading_zeros()),
+            Self::KAry(k) => {
+                assert!(*k >= 2, "a k-ary split needs k >= 2");
+                let nr_parts = nr_bits.min(u32::from(*k));
+                let (part_nr_bits, remainder) = (nr_bits / nr_parts, nr_bits % nr_parts);
+                let mut sub_blocks = Vec::with_capacity(nr_parts as usize);
+                let mut part_start_bit_nr = start_bit_nr;
+                for part_nr in 0..nr_parts {
+                    let nr_bits = part_nr_bits + u32::from(part_nr < remainder);
+                    sub_blocks.push((part_start_bit_nr, part_start_bit_nr + nr_bits - 1));
+                    part_start_bit_nr += nr_bits;
+                }
+                return sub_blocks;
+            }
+        };
+        let mid_bit_nr = start_bit_nr + left_nr_bits - 1;
+        vec![(start_bit_nr, mid_bit_nr), (mid_bit_nr + 1, end_bit_nr)]
+    }
+
+    /// The index of the sub block whose correct parity is inferred, the others are asked.
+    ///
+    /// # Arguments
+    /// sub_blocks: the sub blocks as returned by [`SplitStrategy::split`]
+    pub fn inferred_sub_block(&self, sub_blocks: &[(u32, u32)]) -> usize {
+        match self {
+            Self::AskSmaller => {
+                let nr_bits = |&(start_bit_nr, end_bit_nr): &(u32, u32)| end_bit_nr - start_bit_nr;
+                if nr_bits(&sub_blocks[0]) > nr_bits(&sub_blocks[1]) {
+                    0
+                } else {
+                    1
+                }
+            }
+            Self::Bisect | Self::PowerOfTwo | Self::KAry(_) => sub_blocks.len() - 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SplitStrategy;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(vec![(0, 3), (4, 6)], SplitStrategy::Bisect.split(0, 6));
+        assert_eq!(vec![(4, 4), (5, 5)], SplitStrategy::Bisect.split(4, 5));
+        assert_eq!(vec![(0, 3), (4, 6)], SplitStrategy::AskSmaller.split(0, 6));
+        assert_eq!(
+            vec![(8, 15), (16, 20)],
+            SplitStrategy::PowerOfTwo.split(8, 20)
+        );
+        assert_eq!(
+            vec![(0, 7), (8, 15)],
+            SplitStrategy::PowerOfTwo.split(0, 15)
+        );
+        assert_eq!(vec![(0, 0), (1, 1)], SplitStrategy::PowerOfTwo.split(0, 1));
+        assert_eq!(
+            vec![(0, 3), (4, 7), (8, 10), (11, 13)],
+            SplitStrategy::KAry(4).split(0, 13)
+        );
+        // fewer bits than parts
+        assert_eq!(
+            vec![(5, 5), (6, 6), (7, 7)],
+            SplitStrategy::KAry(4).split(5, 7)
+        );
+    }
+
+    #[test]
+    fn test_inferred_sub_block() {
+        assert_eq!(
+            1,
+            SplitStrategy::Bisect.inferred_sub_block(&[(0, 3), (4, 6)])
+        );
+        // the larger half is inferred
+        assert_eq!(
+            0,
+            SplitStrategy::AskSmaller.inferred_sub_block(&[(0, 3), (4, 6)])
+        );
+        assert_eq!(
+            1,
+            SplitStrategy::AskSmaller.inferred_sub_block(&[(0, 3), (4, 7)])
+        );
+        assert_eq!(
+            3,
+            SplitStrategy::KAry(4).inferred_sub_block(&SplitStrategy::KAry(4).split(0, 13))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "a k-ary split needs k >= 2")]
+    fn test_unary_split() {
+        SplitStrategy::KAry(1).split(0, 7);
+    }
+}
@@ -0,0 +1,173 @@
+//! Result of a reconciliation: what was asked, leaked and corrected, and how efficiently.
+//!
+//! Every asked parity leaks one bit of the key. The Shannon limit `n * h(qber)` is the least
+//! number of bits any reconciliation must leak on average; the efficiency is the number of leaked
+//! bits relative to it, 1 is perfect. The QBER is the real one if the correct key is known,
+//! else the estimated one.
+
+use std::{fmt, time::Duration};
+
+use crate::protocol::WireStats;
+
+/// Statistics of a reconciliation, returned by
+/// [`Reconciliation::start_iterations`](crate::reconciliation::Reconciliation::start_iterations).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconciliationStats {
+    pub nr_key_bits: u32,
+    /// The QBER the reconciliation was configured for, see [`Key::get_estimated_ber`](crate::key::Key::get_estimated_ber).
+    pub estimated_ber: f32,
+    pub nr_iterations: u32,
+    pub elapsed: Duration,
+    /// Parities asked to the peer, in all iterations.
+    pub nr_asked_parities: u64,
+    /// Parities inferred from the parent and sibling blocks, without asking.
+    pub nr_inferred_parities: u64,
+    /// Requests and replies sent on the wire.
+    pub nr_messages: u64,
+    pub nr_round_trips: u64,
+    pub wire_stats: WireStats,
+    /// Bits corrected by the binary search in the block tree of every iteration.
+    pub corrected_bits_per_iteration: Vec<u32>,
+    /// Bits corrected per cascade: a cascade starts at a top block with an odd number of errors
+    /// when its iteration starts, and goes on with the blocks its corrected bits scheduled.
+    pub corrected_bits_per_cascade: Vec<u32>,
+    /// Bits different from the correct key before the reconciliation, if the correct key is known.
+    pub initial_bit_errors: Option<u32>,
+    /// Bits still different from the correct key, if the correct key is known, e.g. simulated.
+    pub residual_bit_errors: Option<u32>,
+}
+
+impl ReconciliationStats {
+    /// Every asked parity leaks a bit.
+    pub fn nr_leaked_bits(&self) -> u64 {
+        self.nr_asked_parities
+    }
+
+    pub fn nr_corrected_bits(&self) -> u32 {
+        self.corrected_bits_per_iteration.iter().sum()
+    }
+
+    /// The QBER of the noise key: the real one if the correct key is known, else the estimated one.
+    pub fn qber(&self) -> f64 {
+        match self.initial_bit_errors {
+            Some(initial_bit_errors) if self.nr_key_bits > 0 => {
+                f64::from(initial_bit_errors) / f64::from(self.nr_key_bits)
+            }
+            _ => f64::from(self.estimated_ber),
+        }
+    }
+
+    /// The least number of bits to leak on average, `n * h(qber)`.
+    pub fn shannon_limit(&self) -> f64 {
+        f64::from(self.nr_key_bits) * binary_entropy(self.qber())
+    }
+
+    /// Leaked bits relative to the Shannon limit. If the limit is 0, it is 1 if no bit leaked,
+    /// else infinite.
+    pub fn efficiency(&self) -> f64 {
+        let shannon_limit = self.shannon_limit();
+        if shannon_limit == 0.0 {
+            return if self.nr_leaked_bits() == 0 {
+                1.0
+            } else {
+                f64::INFINITY
+            };
+        }
+        self.nr_leaked_bits() as f64 / shannon_limit
+    }
+}
+
+impl fmt::Display for ReconciliationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "key bits: {}, estimated QBER: {:.4}, iterations: {}, elapsed: {:?}",
+            self.nr_key_bits, self.estimated_ber, self.nr_iterations, self.elapsed
+        )?;
+        writeln!(
+            f,
+            "parities asked: {}, inferred: {}, messages: {}, round trips: {}, bytes on the wire: {}",
+            self.nr_asked_parities,
+            self.nr_inferred_parities,
+            self.nr_messages,
+            self.nr_round_trips,
+            self.wire_stats.total_bytes()
+        )?;
+        writeln!(
+            f,
+            "bits corrected: {}, per iteration: {:?}, cascades: {}",
+            self.nr_corrected_bits(),
+            self.corrected_bits_per_iteration,
+            self.corrected_bits_per_cascade.len()
+        )?;
+        write!(
+            f,
+            "bits leaked: {}, Shannon limit: {:.1} at QBER {:.4}, efficiency: {:.3}",
+            self.nr_leaked_bits(),
+            self.shannon_limit(),
+            self.qber(),
+            self.efficiency()
+        )?;
+        if let Some(residual_bit_errors) = self.residual_bit_errors {
+            write!(f, "\nresidual bit errors: {}", residual_bit_errors)?;
+        }
+        Ok(())
+    }
+}
+
+/// `h(p) = -p log2(p) - (1 - p) log2(1 - p)`, 0 at `p = 0` and `p = 1`.
+pub fn binary_entropy(p: f64) -> f64 {
+    if p <= 0.0 || p >= 1.0 {
+        return 0.0;
+    }
+    -p * p.log2() - (1.0 - p) * (1.0 - p).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{binary_entropy, ReconciliationStats};
+
+    #[test]
+    fn test_binary_entropy() {
+        assert_eq!(0.0, binary_entropy(0.0));
+        assert_eq!(0.0, binary_entropy(1.0));
+        assert_eq!(1.0, binary_entropy(0.5));
+        assert!((binary_entropy(0.1) - 0.469).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_efficiency() {
+        let stats = ReconciliationStats {
+            nr_key_bits: 1000,
+            estimated_ber: 0.5,
+            nr_asked_parities: 1500,
+            corrected_bits_per_iteration: vec![3, 1],
+            ..Default::default()
+        };
+        assert_eq!(1000.0, stats.shannon_limit());
+        assert_eq!(1.5, stats.efficiency());
+        assert_eq!(4, stats.nr_corrected_bits());
+        assert!(!stats.to_string().contains("residual"));
+
+        // the real QBER, when the correct key is known
+        let stats = ReconciliationStats {
+            initial_bit_errors: Some(100),
+            ..stats
+        };
+        assert_eq!(0.1, stats.qber());
+        assert_eq!(1000.0 * binary_entropy(0.1), stats.shannon_limit());
+
+        // a limit of 0
+        let stats = ReconciliationStats {
+            initial_bit_errors: Some(0),
+            ..stats
+        };
+        assert_eq!(0.0, stats.shannon_limit());
+        assert_eq!(f64::INFINITY, stats.efficiency());
+        let stats = ReconciliationStats {
+            nr_asked_parities: 0,
+            ..stats
+        };
+        assert_eq!(1.0, stats.efficiency());
+    }
+}